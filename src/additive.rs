@@ -0,0 +1,99 @@
+//! An additive oscillator bank, which sums sine wave partials that each have their own envelope.
+
+use std::sync::Arc;
+
+use crate::clock::Clock;
+use crate::envelope::{Adsr, AdsrParams};
+use crate::functions::{additive_wave, Partial};
+use crate::signal::{Continuous, snapshot4};
+use crate::types::{Frequency, Sample, Time, Velocity};
+
+/// A bank of sine wave partials, each of which is scaled by its own envelope.
+#[derive(Clone, Debug)]
+pub struct AdditiveBank {
+    envelopes: Vec<Adsr>,
+    shaped: Vec<Partial>,
+}
+
+impl AdditiveBank {
+    /// Create a new bank with the given envelope for each partial. Partials without an envelope
+    /// (beyond the end of `envelopes`) are played at a constant level.
+    pub fn new(envelopes: Vec<AdsrParams>) -> Self {
+        Self {
+            envelopes: envelopes.into_iter().map(Adsr::new).collect(),
+            shaped: Vec::new(),
+        }
+    }
+
+    /// Step the bank's envelopes forward by `time_step` seconds and return the sum of the partials
+    /// at the given time, skipping any above the Nyquist frequency.
+    pub fn step(&mut self,
+                time: Time,
+                frequency: Frequency,
+                partials: &[Partial],
+                gate: bool,
+                velocity: Velocity,
+                time_step: Time)
+        -> Sample
+    {
+        self.shaped.clear();
+        for (index, partial) in partials.iter().enumerate() {
+            let level = self.envelopes.get_mut(index)
+                .map(|envelope| envelope.step(gate, velocity, time_step))
+                .unwrap_or(1.0);
+            self.shaped.push(Partial::new(partial.ratio, partial.amplitude * level));
+        }
+
+        // Without a time step we can't know the Nyquist frequency, so be silent.
+        if time_step <= 0.0 {
+            return 0.0;
+        }
+        additive_wave(time, frequency, &self.shaped, 0.5 / time_step)
+    }
+}
+
+/// Create an additive oscillator node, which sums the partials given by the `partials` signal at
+/// the given frequency. Each partial's amplitude is shaped by the corresponding envelope in
+/// `envelopes`, which are driven by the gate and velocity signals. The partials are shared rather
+/// than copied, so that sampling them every step doesn't allocate.
+pub fn additive(time: &mut Continuous<Time>,
+                frequency: &Continuous<Frequency>,
+                gate: &Continuous<bool>,
+                velocity: &Continuous<Velocity>,
+                partials: &Continuous<Arc<[Partial]>>,
+                envelopes: Vec<AdsrParams>)
+    -> Continuous<Sample>
+{
+    let mut clock = Clock::new();
+    let mut bank = AdditiveBank::new(envelopes);
+    snapshot4(time, frequency, gate, velocity, partials, move |time, frequency, gate, velocity, partials| {
+        let time_step = clock.tick(time);
+        bank.step(time, frequency, &partials, gate, velocity, time_step)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::functions::sine_wave;
+
+    #[test]
+    fn test_partial_envelopes() {
+        const TIME_STEP: f64 = 1.0 / 48000.0;
+
+        // The second partial decays away quickly while the first sustains.
+        let sustained = AdsrParams { attack: 0.0, decay: 0.0, sustain: 1.0, ..AdsrParams::default() };
+        let decaying = AdsrParams { sustain: 0.0, decay: 0.01, ..sustained };
+        let mut bank = AdditiveBank::new(vec![sustained, decaying]);
+        let partials = Partial::harmonics(2, |_| 1.0);
+
+        for i in 1..2000 {
+            let time = i as f64 * TIME_STEP;
+            let output = bank.step(time, 100.0, &partials, true, 1.0, TIME_STEP);
+            if i > 1000 {
+                assert_relative_eq!(output, sine_wave(time, 100.0), epsilon = 1e-9);
+            }
+        }
+    }
+}
@@ -1,8 +1,8 @@
 //! Types for interacting with audio devices.
 
-use std::{sync::Arc, error::Error, mem::MaybeUninit};
+use std::error::Error;
 use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
-use ringbuf::{Consumer, SharedRb};
+use ringbuf::{HeapConsumer, HeapProducer};
 
 /// An abstraction which allows you to open an audio device and send samples to it.
 pub struct AudioOutput {
@@ -15,7 +15,7 @@ impl AudioOutput {
     /// instance allowing it to be written to.
     ///
     /// TODO: allow enumeration of devices instead of using default device.
-    pub fn connect_default(mut cons: Consumer<f32, Arc<SharedRb<f32, Vec<MaybeUninit<f32>>>>>)
+    pub fn connect_default(mut cons: HeapConsumer<f32>)
         -> Result<Self, Box<dyn Error>>
     {
         log::info!("Connecting to default audio device");
@@ -73,6 +73,80 @@ impl AudioOutput {
 impl Drop for AudioOutput {
     fn drop(&mut self) {
         log::info!("Closing audio device...");
-        drop(&mut self.stream);
+        if let Err(err) = self.stream.pause() {
+            log::info!("Failed to stop output stream: {:?}", err);
+        }
+    }
+}
+
+/// An abstraction which allows you to open an audio input device and receive samples from it.
+pub struct AudioInput {
+    config: cpal::StreamConfig,
+    stream: cpal::Stream,
+}
+
+impl AudioInput {
+    /// Connect to the default audio input device at the given sample rate, and push its samples
+    /// into the ring buffer, mixed down to mono. Samples are dropped if the ring buffer is full.
+    pub fn connect_default(mut prod: HeapProducer<f32>, sample_rate: u32)
+        -> Result<Self, Box<dyn Error>>
+    {
+        log::info!("Connecting to default audio input device");
+
+        // Get default host and input device.
+        let host = cpal::default_host();
+        let device = host
+            .default_input_device()
+            .ok_or("Failed to get default input device")?;
+
+        // Get a supported input config at the sample rate, so that the input matches the output.
+        let config = device
+            .supported_input_configs()?
+            .find(|config| (config.min_sample_rate().0..=config.max_sample_rate().0).contains(&sample_rate))
+            .ok_or(format!("No supported input configs at {sample_rate}Hz"))?
+            .with_sample_rate(cpal::SampleRate(sample_rate))
+            .config();
+        let channel_count = config.channels.max(1) as usize;
+
+        // Build input stream.
+        log::info!("Building input stream");
+        let stream = device.build_input_stream(
+            &config,
+            move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                // Feed mono samples to ring buffer.
+                for frame in data.chunks_exact(channel_count) {
+                    let _ = prod.push(frame.iter().sum::<f32>() / channel_count as f32);
+                }
+            },
+            move |err| {
+                log::info!("Input stream error: {:?}", err);
+            })?;
+
+        log::info!("Starting input stream...");
+        stream.play()?;
+
+        Ok(Self {
+            config,
+            stream,
+        })
+    }
+
+    /// Get the sample rate of the device.
+    pub fn sample_rate(&self) -> u32 {
+        self.config.sample_rate.0
+    }
+
+    /// Get the number of channels the device has.
+    pub fn channel_count(&self) -> u16 {
+        self.config.channels
+    }
+}
+
+impl Drop for AudioInput {
+    fn drop(&mut self) {
+        log::info!("Closing audio input device...");
+        if let Err(err) = self.stream.pause() {
+            log::info!("Failed to stop input stream: {:?}", err);
+        }
     }
 }
@@ -0,0 +1,47 @@
+//! Helpers for stateful nodes that need to know how much time passes between samples.
+
+use crate::types::Time;
+
+/// Tracks the time step between successive values of a network's time input, so that stateful
+/// nodes don't need to be told the sample rate explicitly.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Clock {
+    last_time: Option<Time>,
+    time_step: Time,
+}
+
+impl Clock {
+    /// Create a new clock which hasn't seen any time values yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advance the clock to the given time, returning the time step since the previous tick.
+    ///
+    /// The first tick has no previous time to compare to, so it returns a time step of 0. If time
+    /// goes backwards (e.g. the network is restarted), the previous time step is reused.
+    pub fn tick(&mut self, time: Time) -> Time {
+        if let Some(last_time) = self.last_time.replace(time) {
+            let time_step = time - last_time;
+            if time_step > 0.0 {
+                self.time_step = time_step;
+            }
+        }
+        self.time_step
+    }
+
+    /// The most recent time step, or 0 if the clock hasn't ticked twice yet.
+    pub fn time_step(&self) -> Time {
+        self.time_step
+    }
+
+    /// The sample rate implied by the most recent time step, or None if it isn't known yet.
+    pub fn sample_rate(&self) -> Option<f64> {
+        if self.time_step > 0.0 {
+            Some(1.0 / self.time_step)
+        }
+        else {
+            None
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! A convolution reverb, which convolves the output with a recorded impulse response using
+//! uniformly partitioned FFT convolution. The impulse response is split into blocks which are
+//! convolved with the input separately, so the latency is one block however long the impulse
+//! response is.
+
+use std::error::Error;
+use std::path::Path;
+
+use crate::effects::{mix_frames, Effect, Param};
+use crate::fft::{Complex, Fft};
+use crate::types::{Frame, Sample};
+use crate::wav::Wav;
+
+/// The size of each block of the convolution, which is also its latency in samples.
+const BLOCK_SIZE: usize = 256;
+
+/// Convolves a single channel with an impulse response, one sample at a time.
+#[derive(Clone, Debug)]
+pub struct Convolver {
+    block_size: usize,
+    fft: Fft,
+    /// The spectrum of each block of the impulse response.
+    partitions: Vec<Vec<Complex>>,
+    /// The spectra of the most recent input blocks, in the same order as the partitions they're
+    /// multiplied with, starting at `history_index`.
+    history: Vec<Vec<Complex>>,
+    history_index: usize,
+    /// The previous and current block of input.
+    input: Vec<Sample>,
+    output: Vec<Sample>,
+    position: usize,
+    scratch: Vec<Complex>,
+}
+
+impl Convolver {
+    /// Create a convolver for the given impulse response, with the given block size, which must be
+    /// a power of two.
+    pub fn new(impulse_response: &[Sample], block_size: usize) -> Self {
+        let fft = Fft::new(block_size * 2);
+        let partitions: Vec<Vec<Complex>> = impulse_response.chunks(block_size).map(|block| {
+            let mut spectrum = vec![Complex::default(); block_size * 2];
+            for (value, &sample) in spectrum.iter_mut().zip(block) {
+                value.re = sample;
+            }
+            fft.forward(&mut spectrum);
+            spectrum
+        }).collect();
+
+        Self {
+            block_size,
+            history: vec![vec![Complex::default(); block_size * 2]; partitions.len()],
+            history_index: 0,
+            partitions,
+            fft,
+            input: vec![0.0; block_size * 2],
+            output: vec![0.0; block_size],
+            position: 0,
+            scratch: vec![Complex::default(); block_size * 2],
+        }
+    }
+
+    /// The delay between a sample going in and its convolution coming out.
+    pub fn latency(&self) -> usize {
+        self.block_size
+    }
+
+    /// Process a single sample, returning the convolved sample from one block ago.
+    pub fn process(&mut self, input: Sample) -> Sample {
+        let output = self.output[self.position];
+        self.input[self.block_size + self.position] = input;
+        self.position += 1;
+        if self.position == self.block_size {
+            self.position = 0;
+            self.process_block();
+        }
+        output
+    }
+
+    /// Clear the input history.
+    pub fn reset(&mut self) {
+        self.history.iter_mut().flatten().for_each(|value| *value = Complex::default());
+        self.input.fill(0.0);
+        self.output.fill(0.0);
+        self.position = 0;
+    }
+
+    /// Convolve the last two blocks of input with the impulse response, keeping the second half of
+    /// the result, which is free of circular convolution artifacts (overlap-save).
+    fn process_block(&mut self) {
+        if self.partitions.is_empty() {
+            self.output.fill(0.0);
+            return;
+        }
+
+        // Add the spectrum of the latest input to the history, replacing the oldest.
+        let count = self.partitions.len();
+        self.history_index = (self.history_index + count - 1) % count;
+        let spectrum = &mut self.history[self.history_index];
+        for (value, &sample) in spectrum.iter_mut().zip(&self.input) {
+            *value = Complex::new(sample, 0.0);
+        }
+        self.fft.forward(spectrum);
+
+        // Multiply each partition with the input from that many blocks ago and sum them.
+        self.scratch.fill(Complex::default());
+        for (partition, offset) in self.partitions.iter().zip(0..) {
+            let input = &self.history[(self.history_index + offset) % count];
+            for ((sum, &a), &b) in self.scratch.iter_mut().zip(partition).zip(input) {
+                *sum = *sum + a * b;
+            }
+        }
+        self.fft.inverse(&mut self.scratch);
+
+        for (output, value) in self.output.iter_mut().zip(&self.scratch[self.block_size..]) {
+            *output = value.re;
+        }
+        self.input.copy_within(self.block_size.., 0);
+    }
+}
+
+/// A stereo convolution reverb. Mono impulse responses are used for both channels, otherwise the
+/// first two channels are used for the left and right.
+///
+/// The convolvers are built by `prepare`, as resampling the impulse response and transforming its
+/// partitions is far too slow for the audio thread. Until it's prepared for the sample rate being
+/// processed at, the reverb is silent.
+pub struct ConvolutionReverb {
+    pub mix: Param,
+    impulse_response: Wav,
+    convolvers: Option<[Convolver; 2]>,
+    sample_rate: f64,
+}
+
+impl ConvolutionReverb {
+    /// Create a convolution reverb from an impulse response, which is resampled to the output
+    /// sample rate if it differs.
+    pub fn new(impulse_response: Wav, mix: Param) -> Result<Self, Box<dyn Error>> {
+        if impulse_response.channel_count() == 0 || impulse_response.frame_count() == 0 {
+            return Err("Impulse response is empty".into());
+        }
+        Ok(Self {
+            mix,
+            impulse_response,
+            convolvers: None,
+            sample_rate: 0.0,
+        })
+    }
+
+    /// Load the impulse response from a WAV file.
+    pub fn load<P: AsRef<Path>>(path: P, mix: Param) -> Result<Self, Box<dyn Error>> {
+        Self::new(Wav::load(path)?, mix)
+    }
+}
+
+impl Effect for ConvolutionReverb {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        let wet = match &mut self.convolvers {
+            Some(convolvers) if sample_rate == self.sample_rate => {
+                [convolvers[0].process(input[0]), convolvers[1].process(input[1])]
+            },
+            _ => [0.0; 2],
+        };
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        self.convolvers.iter_mut().flatten().for_each(Convolver::reset);
+    }
+
+    fn prepare(&mut self, sample_rate: f64) {
+        if self.convolvers.is_some() && sample_rate == self.sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        let impulse_response = &self.impulse_response;
+        let ratio = sample_rate / impulse_response.sample_rate as f64;
+        let channel = |index: usize| {
+            let samples = &impulse_response.channels[index.min(impulse_response.channel_count() - 1)];
+            Convolver::new(&resample(samples, ratio), BLOCK_SIZE)
+        };
+        self.convolvers = Some([channel(0), channel(1)]);
+    }
+}
+
+/// Resample by the given ratio of new to old sample rate, using linear interpolation.
+fn resample(samples: &[Sample], ratio: f64) -> Vec<Sample> {
+    if ratio == 1.0 {
+        return samples.to_vec();
+    }
+    let length = (samples.len() as f64 * ratio).round() as usize;
+    (0..length).map(|i| {
+        let position = i as f64 / ratio;
+        let index = position.floor() as usize;
+        let fraction = position - index as f64;
+        let a = samples.get(index).copied().unwrap_or(0.0);
+        let b = samples.get(index + 1).copied().unwrap_or(0.0);
+        a + (b - a) * fraction
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::random::Rng;
+
+    #[test]
+    fn test_convolver() {
+        // Compare against direct convolution, with an impulse response that spans several blocks
+        // and doesn't fill the last one.
+        let mut rng = Rng::new(1);
+        let impulse_response: Vec<Sample> = (0..150).map(|_| rng.next_bipolar()).collect();
+        let input: Vec<Sample> = (0..500).map(|_| rng.next_bipolar()).collect();
+
+        let mut convolver = Convolver::new(&impulse_response, 32);
+        let output: Vec<Sample> = input.iter().map(|&sample| convolver.process(sample)).collect();
+
+        assert!(output[..convolver.latency()].iter().all(|&sample| sample == 0.0));
+        for (n, &sample) in output.iter().enumerate().skip(convolver.latency()) {
+            let n = n - convolver.latency();
+            let expected: Sample = (0..=n)
+                .map(|k| input[k] * impulse_response.get(n - k).copied().unwrap_or(0.0))
+                .sum();
+            assert_relative_eq!(sample, expected, epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_convolution_reverb() {
+        // A stereo impulse response that delays the left channel and inverts the right.
+        let mut left = vec![0.0; 10];
+        left[9] = 1.0;
+        let impulse_response = Wav { sample_rate: 1000, channels: vec![left, vec![-1.0]], loop_points: None };
+        let mut reverb = ConvolutionReverb::new(impulse_response, 1.0.into()).unwrap();
+
+        // Nothing comes out until the reverb is prepared.
+        assert_eq!(reverb.process([1.0, 1.0], 1000.0), [0.0; 2]);
+        reverb.prepare(1000.0);
+
+        let output: Vec<Frame> = (0..BLOCK_SIZE + 20).map(|i| {
+            let input = if i == 0 { 1.0 } else { 0.0 };
+            reverb.process([input, input], 1000.0)
+        }).collect();
+        assert_relative_eq!(output[BLOCK_SIZE + 9][0], 1.0, epsilon = 1e-9);
+        assert_relative_eq!(output[BLOCK_SIZE][1], -1.0, epsilon = 1e-9);
+        assert_relative_eq!(output[BLOCK_SIZE][0], 0.0, epsilon = 1e-9);
+
+        assert!(ConvolutionReverb::new(Wav::default(), 1.0.into()).is_err());
+    }
+
+    #[test]
+    fn test_resample() {
+        assert_eq!(resample(&[0.0, 1.0, 0.0], 2.0), vec![0.0, 0.5, 1.0, 0.5, 0.0, 0.0]);
+        assert_eq!(resample(&[0.0, 1.0, 0.0, 1.0], 0.5), vec![0.0, 0.0]);
+    }
+}
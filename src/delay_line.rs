@@ -0,0 +1,168 @@
+//! A delay line primitive with fractional delay reads, used by time based effects and physical
+//! models.
+
+use crate::types::Sample;
+
+/// A circular buffer of past samples which can be read at any (fractional) delay up to its length.
+#[derive(Clone, Debug)]
+pub struct DelayLine {
+    buffer: Vec<Sample>,
+    write_index: usize,
+}
+
+impl DelayLine {
+    /// Create a new delay line which can delay by up to `length` samples.
+    pub fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; length.max(1) + 1],
+            write_index: 0,
+        }
+    }
+
+    /// The maximum delay in samples.
+    pub fn len(&self) -> usize {
+        self.buffer.len() - 1
+    }
+
+    /// Whether the delay line can't delay at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Change the maximum delay, clearing the delay line if it changes.
+    pub fn resize(&mut self, length: usize) {
+        if length != self.len() {
+            *self = Self::new(length);
+        }
+    }
+
+    /// Clear the contents of the delay line.
+    pub fn clear(&mut self) {
+        self.fill(0.0);
+    }
+
+    /// Set every sample in the delay line to the given value.
+    pub fn fill(&mut self, sample: Sample) {
+        self.buffer.fill(sample);
+    }
+
+    /// Write the next sample into the delay line.
+    pub fn write(&mut self, sample: Sample) {
+        self.write_index = (self.write_index + 1) % self.buffer.len();
+        self.buffer[self.write_index] = sample;
+    }
+
+    /// Read the sample written `delay` samples ago, where 0 is the most recent sample. The delay is
+    /// clamped to the length of the delay line.
+    pub fn tap(&self, delay: usize) -> Sample {
+        let delay = delay.min(self.len());
+        let index = (self.write_index + self.buffer.len() - delay) % self.buffer.len();
+        self.buffer[index]
+    }
+
+    /// Add to the sample written `delay` samples ago, for injecting a signal part way along a
+    /// waveguide. The delay is clamped to the length of the delay line.
+    pub fn add(&mut self, delay: usize, sample: Sample) {
+        let delay = delay.min(self.len());
+        let index = (self.write_index + self.buffer.len() - delay) % self.buffer.len();
+        self.buffer[index] += sample;
+    }
+
+    /// Read the delay line at a fractional delay in samples, linearly interpolating between the
+    /// neighbouring samples.
+    pub fn read(&self, delay: f64) -> Sample {
+        let delay = delay.clamp(0.0, self.len() as f64);
+        let whole = delay.floor();
+        let fraction = delay - whole;
+        let a = self.tap(whole as usize);
+        let b = self.tap(whole as usize + 1);
+        a + (b - a) * fraction
+    }
+}
+
+impl Default for DelayLine {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+/// Reads a delay line at a fractional delay with a first order allpass filter. Unlike linear
+/// interpolation this doesn't dull high frequencies, so it's used to tune feedback loops such as
+/// waveguides, where any loss would be applied every time round the loop. It has state, so each
+/// read position needs its own interpolator, and the delay should change slowly.
+#[derive(Clone, Debug, Default)]
+pub struct AllpassInterpolator {
+    previous: Sample,
+}
+
+impl AllpassInterpolator {
+    /// Create an interpolator with no previous output.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the delay line at a fractional delay of at least 0.1 samples. This should be called
+    /// once per sample.
+    pub fn read(&mut self, delay_line: &DelayLine, delay: f64) -> Sample {
+        // Keep the fractional part between 0.1 and 1.1, where the filter's delay is accurate and
+        // its pole is well away from the unit circle.
+        let delay = delay.clamp(0.1, delay_line.len() as f64 - 0.9);
+        let whole = (delay - 0.1).floor();
+        let fraction = delay - whole;
+        let coefficient = (1.0 - fraction) / (1.0 + fraction);
+
+        let current = delay_line.tap(whole as usize);
+        let previous_input = delay_line.tap(whole as usize + 1);
+        self.previous = coefficient * (current - self.previous) + previous_input;
+        self.previous
+    }
+
+    /// Clear the interpolator's state.
+    pub fn reset(&mut self) {
+        self.previous = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_delay_line() {
+        let mut delay_line = DelayLine::new(4);
+        for sample in [1.0, 2.0, 3.0, 4.0, 5.0, 6.0] {
+            delay_line.write(sample);
+        }
+
+        assert_eq!(delay_line.tap(0), 6.0);
+        assert_eq!(delay_line.tap(3), 3.0);
+        assert_eq!(delay_line.tap(4), 2.0);
+        // Delays past the end are clamped.
+        assert_eq!(delay_line.tap(10), 2.0);
+
+        // Fractional reads interpolate.
+        assert_relative_eq!(delay_line.read(1.5), 4.5);
+        assert_relative_eq!(delay_line.read(0.25), 5.75);
+
+        // Adding injects into the past.
+        delay_line.add(1, 10.0);
+        assert_eq!(delay_line.tap(1), 15.0);
+    }
+
+    #[test]
+    fn test_allpass_interpolator() {
+        // A low frequency sine should come out delayed by the fractional delay, at the same level.
+        let mut delay_line = DelayLine::new(16);
+        let mut interpolator = AllpassInterpolator::new();
+        let input = |n: usize| f64::sin(2.0 * std::f64::consts::PI * n as f64 / 200.0);
+        for n in 0..1000 {
+            delay_line.write(input(n));
+            let output = interpolator.read(&delay_line, 3.4);
+            if n > 500 {
+                let expected = f64::sin(2.0 * std::f64::consts::PI * (n as f64 - 3.4) / 200.0);
+                assert_relative_eq!(output, expected, epsilon = 1e-3);
+            }
+        }
+    }
+}
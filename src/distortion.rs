@@ -0,0 +1,299 @@
+//! Nonlinear processors: waveshapers, a wavefolder and a bitcrusher, usable as network nodes per
+//! voice or as effects on the master bus.
+//!
+//! Waveshaping adds harmonics which can go above the Nyquist frequency and alias, so waveshapers can
+//! optionally be run at a multiple of the sample rate.
+
+use crate::clock::Clock;
+use crate::effects::{mix_frames, Effect, Param};
+use crate::oversampling::{Oversampler, OversamplingFactor};
+use crate::signal::{Continuous, snapshot2, snapshot3};
+use crate::types::{Frame, Frequency, Sample, Time};
+
+/// A waveshaping function.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shaper {
+    /// Clip to the range -1 to 1.
+    HardClip,
+    /// A cubic curve which smoothly reaches 1 at an input of 1, then clips.
+    SoftClip,
+    /// A hyperbolic tangent, which gets gradually harder as the input gets louder.
+    Tanh,
+    /// A polynomial with the given coefficients, starting from the constant term. The input is
+    /// clipped to -1 to 1 first so the output stays bounded.
+    Polynomial(Vec<f64>),
+    /// Reflect the input back from -1 and 1 until it's inside that range, so louder inputs fold
+    /// over more times.
+    Fold,
+}
+
+impl Shaper {
+    /// Shape a single sample.
+    pub fn apply(&self, input: Sample) -> Sample {
+        match self {
+            Shaper::HardClip => input.clamp(-1.0, 1.0),
+            Shaper::SoftClip => {
+                let x = input.clamp(-1.0, 1.0);
+                1.5 * (x - x * x * x / 3.0)
+            },
+            Shaper::Tanh => input.tanh(),
+            Shaper::Polynomial(coefficients) => {
+                let x = input.clamp(-1.0, 1.0);
+                coefficients.iter().rev().fold(0.0, |sum, coefficient| sum * x + coefficient)
+            },
+            Shaper::Fold => 1.0 - 4.0 * (((input + 1.0) / 4.0).rem_euclid(1.0) - 0.5).abs(),
+        }
+    }
+}
+
+/// A waveshaper with a drive control, optionally oversampled.
+#[derive(Clone, Debug)]
+pub struct Waveshaper {
+    pub shaper: Shaper,
+    oversampler: Option<Oversampler>,
+}
+
+impl Waveshaper {
+    /// Create a new waveshaper, oversampled by the given factor, or running at the sample rate if
+    /// there isn't one.
+    pub fn new(shaper: Shaper, oversampling: Option<OversamplingFactor>) -> Self {
+        Self {
+            shaper,
+            oversampler: oversampling.map(Oversampler::new),
+        }
+    }
+
+    /// Shape a single sample after multiplying it by the drive.
+    pub fn process(&mut self, input: Sample, drive: f64) -> Sample {
+        let shaper = &self.shaper;
+        match &mut self.oversampler {
+            Some(oversampler) => oversampler.process(input, |_, sample| shaper.apply(sample * drive)),
+            None => shaper.apply(input * drive),
+        }
+    }
+
+    /// Clear the oversampling filters.
+    pub fn reset(&mut self) {
+        if let Some(oversampler) = &mut self.oversampler {
+            oversampler.reset();
+        }
+    }
+}
+
+/// Reduces the bit depth and sample rate of a signal for a lo-fi sound.
+#[derive(Clone, Copy, Debug)]
+pub struct Bitcrusher {
+    held: Sample,
+    phase: f64,
+}
+
+impl Bitcrusher {
+    /// Create a new bitcrusher, which holds the first sample it's given.
+    pub fn new() -> Self {
+        Self {
+            held: 0.0,
+            phase: 1.0,
+        }
+    }
+
+    /// Crush a single sample to the given number of bits (which can be fractional), holding it
+    /// until the next sample at the given reduced sample rate.
+    pub fn process(&mut self, input: Sample, bits: f64, rate: Frequency, sample_rate: f64) -> Sample {
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            let levels = f64::powf(2.0, bits.max(1.0) - 1.0);
+            self.held = (input * levels).round() / levels;
+        }
+        self.phase += rate / sample_rate;
+        self.held
+    }
+}
+
+/// Create a waveshaper node, which multiplies the input by the drive and shapes it.
+pub fn waveshaper(time: &mut Continuous<Time>,
+                  input: &Continuous<Sample>,
+                  drive: &Continuous<f64>,
+                  mut shaper: Waveshaper)
+    -> Continuous<Sample>
+{
+    snapshot2(time, input, drive, move |_, input, drive| shaper.process(input, drive))
+}
+
+/// Create a bitcrusher node, which reduces the input to the given bit depth and sample rate.
+pub fn bitcrusher(time: &mut Continuous<Time>,
+                  input: &Continuous<Sample>,
+                  bits: &Continuous<f64>,
+                  rate: &Continuous<Frequency>)
+    -> Continuous<Sample>
+{
+    let mut clock = Clock::new();
+    let mut crusher = Bitcrusher::new();
+    snapshot3(time, input, bits, rate, move |time, input, bits, rate| {
+        clock.tick(time);
+        match clock.sample_rate() {
+            Some(sample_rate) => crusher.process(input, bits, rate, sample_rate),
+            None => 0.0,
+        }
+    })
+}
+
+/// A stereo waveshaping distortion for the master bus.
+pub struct Distortion {
+    /// The gain before the waveshaper.
+    pub drive: Param,
+    /// The gain after the waveshaper.
+    pub level: Param,
+    pub mix: Param,
+    shapers: [Waveshaper; 2],
+}
+
+impl Distortion {
+    /// Create a new distortion with the given shaper, oversampled by the given factor if there is
+    /// one.
+    pub fn new(shaper: Shaper, oversampling: Option<OversamplingFactor>, drive: Param, mix: Param) -> Self {
+        let shaper = Waveshaper::new(shaper, oversampling);
+        Self {
+            drive,
+            level: Param::Constant(1.0),
+            mix,
+            shapers: [shaper.clone(), shaper],
+        }
+    }
+}
+
+impl Effect for Distortion {
+    fn process(&mut self, input: Frame, _sample_rate: f64) -> Frame {
+        let (drive, level) = (self.drive.value(), self.level.value());
+        let wet = [
+            self.shapers[0].process(input[0], drive) * level,
+            self.shapers[1].process(input[1], drive) * level,
+        ];
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        self.shapers.iter_mut().for_each(Waveshaper::reset);
+    }
+}
+
+/// A stereo bitcrusher for the master bus.
+pub struct Crusher {
+    /// The bit depth, which can be fractional.
+    pub bits: Param,
+    /// The reduced sample rate in Hz.
+    pub rate: Param,
+    pub mix: Param,
+    crushers: [Bitcrusher; 2],
+}
+
+impl Crusher {
+    /// Create a new bitcrusher with the given bit depth and sample rate.
+    pub fn new(bits: Param, rate: Param, mix: Param) -> Self {
+        Self {
+            bits,
+            rate,
+            mix,
+            crushers: [Bitcrusher::new(); 2],
+        }
+    }
+}
+
+impl Default for Bitcrusher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Effect for Crusher {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        let (bits, rate) = (self.bits.value(), self.rate.value());
+        let wet = [
+            self.crushers[0].process(input[0], bits, rate, sample_rate),
+            self.crushers[1].process(input[1], bits, rate, sample_rate),
+        ];
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        self.crushers = [Bitcrusher::new(); 2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn test_shapers() {
+        assert_eq!(Shaper::HardClip.apply(1.5), 1.0);
+        assert_eq!(Shaper::HardClip.apply(-0.5), -0.5);
+        assert_eq!(Shaper::SoftClip.apply(1.0), 1.0);
+        assert_eq!(Shaper::SoftClip.apply(-3.0), -1.0);
+        assert_relative_eq!(Shaper::SoftClip.apply(0.5), 0.6875);
+        assert_relative_eq!(Shaper::Tanh.apply(0.5), 0.5f64.tanh());
+
+        // The third Chebyshev polynomial turns a sine into its third harmonic.
+        let chebyshev = Shaper::Polynomial(vec![0.0, -3.0, 0.0, 4.0]);
+        assert_relative_eq!(chebyshev.apply(f64::cos(0.3)), f64::cos(0.9), epsilon = 1e-12);
+        assert_eq!(chebyshev.apply(2.0), 1.0);
+    }
+
+    #[test]
+    fn test_wavefolder() {
+        assert_relative_eq!(Shaper::Fold.apply(0.5), 0.5);
+        assert_relative_eq!(Shaper::Fold.apply(1.0), 1.0);
+        assert_relative_eq!(Shaper::Fold.apply(1.25), 0.75);
+        assert_relative_eq!(Shaper::Fold.apply(2.5), -0.5);
+        assert_relative_eq!(Shaper::Fold.apply(-1.5), -0.5);
+    }
+
+    #[test]
+    fn test_bitcrusher() {
+        let mut crusher = Bitcrusher::new();
+        // 2 bits has levels at multiples of 0.5, and a quarter of the sample rate holds each
+        // sample for 4 samples.
+        let output: Vec<Sample> = [0.3, 0.9, 0.9, 0.9, -0.2, 0.0, 0.0, 0.0, -0.8]
+            .iter()
+            .map(|&input| crusher.process(input, 2.0, 250.0, 1000.0))
+            .collect();
+        assert_eq!(output, vec![0.5, 0.5, 0.5, 0.5, 0.0, 0.0, 0.0, 0.0, -1.0]);
+    }
+
+    /// The magnitude of a signal at the given frequency.
+    fn magnitude(signal: &[Sample], frequency: Frequency, sample_rate: f64) -> f64 {
+        let (re, im) = signal.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, sample)| {
+            let angle = 2.0 * PI * frequency * n as f64 / sample_rate;
+            (re + sample * angle.cos(), im - sample * angle.sin())
+        });
+        re.hypot(im) * 2.0 / signal.len() as f64
+    }
+
+    #[test]
+    fn test_oversampling() {
+        // Hard clipping a 5kHz sine at 48kHz produces a 45kHz harmonic which aliases down to 3kHz.
+        // Oversampling should reduce it, without changing the fundamental much.
+        let sample_rate = 48000.0;
+        let distort = |oversampling| {
+            let mut shaper = Waveshaper::new(Shaper::HardClip, oversampling);
+            let output: Vec<Sample> = (0..9600)
+                .map(|n| shaper.process(f64::sin(2.0 * PI * 5000.0 * n as f64 / sample_rate), 10.0))
+                .collect();
+            output[4800..].to_vec()
+        };
+        let (naive, oversampled) = (distort(None), distort(Some(OversamplingFactor::X8)));
+
+        assert!(magnitude(&oversampled, 3000.0, sample_rate) < magnitude(&naive, 3000.0, sample_rate) * 0.3);
+        assert_relative_eq!(magnitude(&oversampled, 5000.0, sample_rate), magnitude(&naive, 5000.0, sample_rate), max_relative = 0.1);
+    }
+
+    #[test]
+    fn test_distortion_effect() {
+        let mut distortion = Distortion::new(Shaper::Tanh, None, 4.0.into(), 1.0.into());
+        distortion.level = 0.5.into();
+        let output = distortion.process([0.5, -0.5], 48000.0);
+        assert_relative_eq!(output[0], 2.0f64.tanh() * 0.5);
+        assert_relative_eq!(output[1], -(2.0f64.tanh()) * 0.5);
+    }
+}
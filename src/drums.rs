@@ -0,0 +1,333 @@
+//! Drum synthesis, which builds kicks, snares, claps and hi-hats from oscillators, noise and
+//! envelopes, in the style of classic analog drum machines.
+//!
+//! A drum voice plays the drum mapped to its note in the General MIDI percussion map. Drums are
+//! one-shot, so they play out their envelopes whether or not the note is still held.
+
+use crate::clock::Clock;
+use crate::envelope::{Adsr, AdsrParams, Curve, Retrigger};
+use crate::filter::{Biquad, Filter, FilterMode};
+use crate::functions::{sine_wave, square_wave};
+use crate::random::Rng;
+use crate::signal::{Continuous, snapshot3};
+use crate::types::{Frame, Frequency, MidiNote, Sample, Time, Velocity};
+
+/// The frequency the kick settles at, and how far above that its pitch envelope starts.
+const KICK_FREQUENCY: Frequency = 45.0;
+const KICK_SWEEP: Frequency = 120.0;
+
+/// The frequencies of the two sine waves in the body of the snare.
+const SNARE_FREQUENCIES: [Frequency; 2] = [180.0, 330.0];
+
+/// The frequencies of the six square waves which make up the metallic sound of the hi-hats, which
+/// are far enough from harmonic to sound clangorous rather than pitched.
+const HAT_FREQUENCIES: [Frequency; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+
+/// The number of short bursts of noise at the start of a clap, and the time between them.
+const CLAP_BURSTS: u32 = 3;
+const CLAP_BURST_SPACING: Time = 0.01;
+
+/// A drum which a drum voice can play.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Drum {
+    Kick,
+    Snare,
+    Clap,
+    ClosedHat,
+    OpenHat,
+}
+
+impl Drum {
+    /// The drum for a note in the General MIDI percussion map, if it's one of the drums that can be
+    /// synthesised.
+    pub fn from_note(note: MidiNote) -> Option<Self> {
+        match note {
+            35 | 36 => Some(Drum::Kick),
+            38 | 40 => Some(Drum::Snare),
+            39 => Some(Drum::Clap),
+            42 | 44 => Some(Drum::ClosedHat),
+            46 => Some(Drum::OpenHat),
+            _ => None,
+        }
+    }
+
+    /// Where the drum sits in the stereo field, from -1 (left) to 1 (right).
+    fn pan(self) -> f64 {
+        match self {
+            Drum::Kick | Drum::Snare => 0.0,
+            Drum::Clap => -0.2,
+            Drum::ClosedHat | Drum::OpenHat => 0.3,
+        }
+    }
+}
+
+/// The parameters of an envelope for a hit, which rises almost instantly and then decays to
+/// silence over `decay` seconds, whether or not the note is held.
+fn hit(attack: Time, decay: Time) -> AdsrParams {
+    AdsrParams {
+        attack,
+        decay,
+        sustain: 0.0,
+        release: decay,
+        decay_curve: Curve::Exponential(5.0),
+        release_curve: Curve::Exponential(5.0),
+        retrigger: Retrigger::Reset,
+        ..AdsrParams::default()
+    }
+}
+
+/// A voice which plays a synthesised drum each time its gate opens.
+#[derive(Clone, Debug)]
+pub struct DrumVoice {
+    drum: Option<Drum>,
+    gate: bool,
+    velocity: Velocity,
+    /// The time since the drum was hit.
+    age: Time,
+    /// The phase of the kick, in cycles, which is accumulated so that its pitch can sweep.
+    phase: f64,
+    /// The envelope of the tone, or of the tail of a clap.
+    amplitude: Adsr,
+    /// The kick's pitch envelope.
+    pitch: Adsr,
+    /// The envelope of the noise, or of each burst of a clap.
+    noise: Adsr,
+    clap_bursts: u32,
+    rng: Rng,
+    noise_filter: Biquad,
+    metal_filter: Biquad,
+}
+
+impl DrumVoice {
+    /// Create a silent drum voice, with the noise seeded by `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            drum: None,
+            gate: false,
+            velocity: 0.0,
+            age: 0.0,
+            phase: 0.0,
+            amplitude: Adsr::new(hit(0.001, 0.1)),
+            pitch: Adsr::new(hit(0.001, 0.1)),
+            noise: Adsr::new(hit(0.001, 0.1)),
+            clap_bursts: 0,
+            rng: Rng::new(seed),
+            noise_filter: Biquad::new(FilterMode::HighPass),
+            metal_filter: Biquad::new(FilterMode::BandPass),
+        }
+    }
+
+    /// The drum that was last hit, if any.
+    pub fn drum(&self) -> Option<Drum> {
+        self.drum
+    }
+
+    /// Step the voice forward by `time_step` seconds, hitting the drum for the note when the gate
+    /// opens.
+    pub fn step(&mut self, note: MidiNote, gate: bool, velocity: Velocity, time_step: Time, sample_rate: f64) -> Frame {
+        if gate && !self.gate {
+            self.trigger(note, velocity, sample_rate);
+        }
+        self.gate = gate;
+
+        let Some(drum) = self.drum else {
+            return [0.0; 2];
+        };
+        self.age += time_step;
+        let velocity = self.velocity;
+        let sample = match drum {
+            Drum::Kick => self.kick(velocity, time_step),
+            Drum::Snare => self.snare(velocity, time_step),
+            Drum::Clap => self.clap(velocity, time_step),
+            Drum::ClosedHat | Drum::OpenHat => self.hat(velocity, time_step),
+        };
+
+        let pan = drum.pan();
+        [sample * (1.0 - pan).min(1.0), sample * (1.0 + pan).min(1.0)]
+    }
+
+    /// Hit the drum for the note, or stop playing if no drum is mapped to it.
+    fn trigger(&mut self, note: MidiNote, velocity: Velocity, sample_rate: f64) {
+        self.drum = Drum::from_note(note);
+        self.velocity = velocity.clamp(0.0, 1.0);
+        self.age = 0.0;
+        self.phase = 0.0;
+        self.clap_bursts = 0;
+        self.noise_filter.reset();
+        self.metal_filter.reset();
+
+        let Some(drum) = self.drum else {
+            return;
+        };
+        let (amplitude, noise) = match drum {
+            Drum::Kick => (hit(0.001, 0.5), hit(0.0005, 0.005)),
+            Drum::Snare => {
+                self.noise_filter = Biquad::new(FilterMode::HighPass);
+                self.noise_filter.set_params(1000.0, 0.2, sample_rate);
+                (hit(0.001, 0.12), hit(0.001, 0.2))
+            },
+            Drum::Clap => {
+                self.noise_filter = Biquad::new(FilterMode::BandPass);
+                self.noise_filter.set_params(1200.0, 0.5, sample_rate);
+                self.clap_bursts = CLAP_BURSTS - 1;
+                // The tail swells up underneath the bursts.
+                (hit(CLAP_BURST_SPACING * CLAP_BURSTS as f64, 0.2), hit(0.0005, CLAP_BURST_SPACING))
+            },
+            Drum::ClosedHat | Drum::OpenHat => {
+                self.metal_filter = Biquad::new(FilterMode::BandPass);
+                self.metal_filter.set_params(10000.0, 0.3, sample_rate);
+                self.noise_filter = Biquad::new(FilterMode::HighPass);
+                self.noise_filter.set_params(7000.0, 0.2, sample_rate);
+                let decay = if drum == Drum::ClosedHat { 0.05 } else { 0.4 };
+                (hit(0.001, decay), hit(0.001, decay))
+            },
+        };
+        self.amplitude = Adsr::new(amplitude);
+        self.noise = Adsr::new(noise);
+        self.pitch = Adsr::new(AdsrParams { velocity_sensitivity: 0.0, ..hit(0.0005, 0.05) });
+    }
+
+    /// A sine wave swept down by the pitch envelope, with a click of noise at the start.
+    fn kick(&mut self, velocity: Velocity, time_step: Time) -> Sample {
+        let pitch = self.pitch.step(true, velocity, time_step);
+        self.phase += (KICK_FREQUENCY + KICK_SWEEP * pitch) * time_step;
+        let body = sine_wave(self.phase, 1.0) * self.amplitude.step(true, velocity, time_step);
+        let click = self.rng.next_bipolar() * self.noise.step(true, velocity, time_step);
+        body + 0.2 * click
+    }
+
+    /// A pair of sine waves for the drum, with a longer burst of high-passed noise for the snares.
+    fn snare(&mut self, velocity: Velocity, time_step: Time) -> Sample {
+        let tone: Sample = SNARE_FREQUENCIES.iter().map(|frequency| sine_wave(self.age, *frequency)).sum();
+        let tone = 0.5 * tone * self.amplitude.step(true, velocity, time_step);
+        let noise = self.noise_filter.process(self.rng.next_bipolar()) * self.noise.step(true, velocity, time_step);
+        0.4 * tone + 0.8 * noise
+    }
+
+    /// Band-passed noise, played as a few short bursts followed by a longer tail.
+    fn clap(&mut self, velocity: Velocity, time_step: Time) -> Sample {
+        if self.clap_bursts > 0 && self.age >= CLAP_BURST_SPACING * (CLAP_BURSTS - self.clap_bursts) as f64 {
+            self.clap_bursts -= 1;
+            self.noise = Adsr::new(hit(0.0005, CLAP_BURST_SPACING));
+        }
+        let bursts = self.noise.step(true, velocity, time_step);
+        let tail = 0.6 * self.amplitude.step(true, velocity, time_step);
+        2.0 * self.noise_filter.process(self.rng.next_bipolar()) * bursts.max(tail)
+    }
+
+    /// Six square waves at inharmonic frequencies, filtered to leave only the high, metallic
+    /// partials.
+    fn hat(&mut self, velocity: Velocity, time_step: Time) -> Sample {
+        let metal: Sample = HAT_FREQUENCIES.iter().map(|frequency| square_wave(self.age, *frequency)).sum();
+        let metal = self.noise_filter.process(self.metal_filter.process(metal / HAT_FREQUENCIES.len() as f64));
+        2.0 * metal * self.amplitude.step(true, velocity, time_step)
+    }
+}
+
+/// Create a drum node, which plays the drum mapped to the note every time the gate opens.
+pub fn drums(time: &mut Continuous<Time>,
+             note: &Continuous<MidiNote>,
+             gate: &Continuous<bool>,
+             velocity: &Continuous<Velocity>,
+             seed: u64)
+    -> Continuous<Frame>
+{
+    let mut clock = Clock::new();
+    let mut voice = DrumVoice::new(seed);
+    snapshot3(time, note, gate, velocity, move |time, note, gate, velocity| {
+        let time_step = clock.tick(time);
+        match clock.sample_rate() {
+            Some(sample_rate) => voice.step(note, gate, velocity, time_step, sample_rate),
+            None => [0.0; 2],
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    /// Hit a drum with a short note, and return the left channel.
+    fn play(note: MidiNote, velocity: Velocity, length: Time) -> Vec<Sample> {
+        let mut voice = DrumVoice::new(1);
+        let note_length = (0.01 * SAMPLE_RATE) as usize;
+        (0..(length * SAMPLE_RATE) as usize)
+            .map(|n| voice.step(note, n < note_length, velocity, 1.0 / SAMPLE_RATE, SAMPLE_RATE)[0])
+            .collect()
+    }
+
+    /// The RMS level of part of a signal, given in seconds.
+    fn rms(signal: &[Sample], start: Time, end: Time) -> f64 {
+        let signal = &signal[(start * SAMPLE_RATE) as usize..(end * SAMPLE_RATE) as usize];
+        (signal.iter().map(|sample| sample * sample).sum::<f64>() / signal.len() as f64).sqrt()
+    }
+
+    /// Count the rising zero crossings in part of a signal, given in seconds.
+    fn crossings(signal: &[Sample], start: Time, end: Time) -> usize {
+        let signal = &signal[(start * SAMPLE_RATE) as usize..(end * SAMPLE_RATE) as usize];
+        signal.windows(2).filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0).count()
+    }
+
+    #[test]
+    fn test_note_map() {
+        assert_eq!(Drum::from_note(36), Some(Drum::Kick));
+        assert_eq!(Drum::from_note(38), Some(Drum::Snare));
+        assert_eq!(Drum::from_note(39), Some(Drum::Clap));
+        assert_eq!(Drum::from_note(42), Some(Drum::ClosedHat));
+        assert_eq!(Drum::from_note(46), Some(Drum::OpenHat));
+        assert_eq!(Drum::from_note(60), None);
+
+        // Notes without a drum should be silent.
+        assert!(play(60, 1.0, 0.1).iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn test_kick() {
+        // The pitch should sweep down and settle at the kick's frequency.
+        let kick = play(36, 1.0, 1.0);
+        assert!(crossings(&kick, 0.0, 0.02) >= 2);
+        let settled = crossings(&kick, 0.2, 0.4);
+        assert!((settled as f64 - KICK_FREQUENCY * 0.2).abs() <= 1.0, "{settled} crossings");
+
+        // The drum plays out after the note is released, and then decays to silence.
+        assert!(rms(&kick, 0.05, 0.1) > 0.1);
+        assert!(rms(&kick, 0.6, 1.0) < 1e-6);
+    }
+
+    #[test]
+    fn test_decays() {
+        // The open hat should ring on well after the closed hat has died away.
+        let closed = play(42, 1.0, 0.5);
+        let open = play(46, 1.0, 0.5);
+        assert!(rms(&closed, 0.0, 0.05) > 0.01);
+        assert!(rms(&closed, 0.1, 0.2) < 1e-6);
+        assert!(rms(&open, 0.1, 0.2) > 0.01);
+
+        for note in [38, 39] {
+            let drum = play(note, 1.0, 0.5);
+            assert!(rms(&drum, 0.0, 0.05) > 0.05);
+            assert!(rms(&drum, 0.3, 0.5) < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_velocity() {
+        // Softer hits should be quieter.
+        for note in [36, 38, 39, 42] {
+            let loud = rms(&play(note, 1.0, 0.1), 0.0, 0.1);
+            let soft = rms(&play(note, 0.5, 0.1), 0.0, 0.1);
+            assert!(soft < loud * 0.6, "note {note}: {soft} vs {loud}");
+        }
+    }
+
+    #[test]
+    fn test_clap_bursts() {
+        // The clap should dip between its opening bursts.
+        let clap = play(39, 1.0, 0.1);
+        let dip = rms(&clap, 0.008, 0.01);
+        assert!(rms(&clap, 0.0, 0.004) > dip * 2.0);
+        assert!(rms(&clap, 0.01, 0.014) > dip * 2.0);
+    }
+}
@@ -0,0 +1,342 @@
+//! Dynamics processors for the master bus: a compressor to even out the level of the mix, and a
+//! look-ahead limiter which keeps the output from clipping.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::delay_line::DelayLine;
+use crate::effects::{Effect, Param};
+use crate::types::{Frame, Sample, Time};
+
+/// The lowest level the detectors can see, to avoid taking the log of 0.
+const MIN_LEVEL: f64 = 1e-10;
+
+/// How many samples late the limiter's true peak detector is, as it needs a sample either side of
+/// the segment it's looking at.
+const TRUE_PEAK_DELAY: usize = 2;
+
+/// The points between samples where the limiter checks for inter-sample peaks.
+const TRUE_PEAK_POINTS: [f64; 3] = [0.25, 0.5, 0.75];
+
+/// Convert a level in decibels to a linear gain.
+pub fn db_to_gain(db: f64) -> f64 {
+    f64::powf(10.0, db / 20.0)
+}
+
+/// Convert a linear gain to a level in decibels.
+pub fn gain_to_db(gain: f64) -> f64 {
+    20.0 * gain.max(MIN_LEVEL).log10()
+}
+
+/// The smoothing coefficient of a one pole filter which gets most of the way to its target in the
+/// given time.
+pub fn time_coefficient(time: Time, sample_rate: f64) -> f64 {
+    if time > 0.0 { f64::exp(-1.0 / (time * sample_rate)) } else { 0.0 }
+}
+
+/// Estimate the highest peak of the segment between the middle two of four samples, using
+/// Catmull-Rom interpolation.
+fn true_peak(samples: [Sample; 4]) -> Sample {
+    let [p0, p1, p2, p3] = samples;
+    TRUE_PEAK_POINTS.iter().fold(p1.abs().max(p2.abs()), |peak, &t| {
+        let value = 0.5 * (2.0 * p1
+            + (p2 - p0) * t
+            + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t * t
+            + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t * t * t);
+        peak.max(value.abs())
+    })
+}
+
+/// A handle to the current gain reduction of a compressor or limiter in decibels, which can be
+/// read from another thread while the effect is running.
+#[derive(Clone, Debug, Default)]
+pub struct GainReductionMeter {
+    value: Arc<AtomicU64>,
+}
+
+impl GainReductionMeter {
+    /// The current gain reduction in decibels, where 0 is no reduction.
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.value.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, db: f64) {
+        self.value.store(db.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// A stereo linked feed-forward compressor with a soft knee.
+pub struct Compressor {
+    /// The level above which the signal is compressed, in dB.
+    pub threshold: Param,
+    /// How many dB the input has to go up above the threshold for the output to go up by 1dB.
+    pub ratio: Param,
+    /// How quickly gain reduction is applied and released, in seconds.
+    pub attack: Param,
+    pub release: Param,
+    /// The width of the soft knee around the threshold, in dB.
+    pub knee: Param,
+    /// Gain applied after compression, in dB.
+    pub makeup: Param,
+    gain_reduction: f64,
+    meter: GainReductionMeter,
+}
+
+impl Compressor {
+    /// Create a compressor with the given threshold and ratio, and typical time and knee settings.
+    pub fn new(threshold: Param, ratio: Param) -> Self {
+        Self {
+            threshold,
+            ratio,
+            attack: Param::Constant(0.01),
+            release: Param::Constant(0.1),
+            knee: Param::Constant(6.0),
+            makeup: Param::Constant(0.0),
+            gain_reduction: 0.0,
+            meter: GainReductionMeter::default(),
+        }
+    }
+
+    /// A meter showing the compressor's gain reduction.
+    pub fn meter(&self) -> GainReductionMeter {
+        self.meter.clone()
+    }
+
+    /// The static gain change in dB (which is never positive) for an input level in dB.
+    fn gain_computer(&self, level: f64) -> f64 {
+        let (threshold, knee) = (self.threshold.value(), self.knee.value().max(0.0));
+        let slope = 1.0 / self.ratio.value().max(1.0) - 1.0;
+        let over = level - threshold;
+        if 2.0 * over <= -knee {
+            0.0
+        }
+        else if 2.0 * over < knee {
+            slope * (over + knee / 2.0).powi(2) / (2.0 * knee)
+        }
+        else {
+            slope * over
+        }
+    }
+}
+
+impl Effect for Compressor {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        let level = gain_to_db(input[0].abs().max(input[1].abs()));
+        let target = self.gain_computer(level);
+
+        // Attack when the gain needs to go down, release when it can come back up.
+        let time = if target < self.gain_reduction { self.attack.value() } else { self.release.value() };
+        let coefficient = time_coefficient(time, sample_rate);
+        self.gain_reduction = target + (self.gain_reduction - target) * coefficient;
+        self.meter.set(-self.gain_reduction);
+
+        let gain = db_to_gain(self.gain_reduction + self.makeup.value());
+        [input[0] * gain, input[1] * gain]
+    }
+
+    fn reset(&mut self) {
+        self.gain_reduction = 0.0;
+        self.meter.set(0.0);
+    }
+}
+
+/// A brickwall limiter which delays the signal so that it can turn the gain down smoothly before
+/// peaks arrive. Peaks between samples are estimated by interpolation so that the reconstructed
+/// signal stays below the ceiling too.
+pub struct Limiter {
+    /// The maximum output level, in dB.
+    pub ceiling: Param,
+    /// How quickly the gain recovers after a peak, in seconds.
+    pub release: Param,
+    lookahead: Time,
+    lines: [DelayLine; 2],
+    /// The last few input peaks, for estimating inter-sample peaks.
+    recent: [Sample; 4],
+    /// The gain needed for each sample in the look-ahead window, newest first.
+    required: DelayLine,
+    envelope: f64,
+    /// The envelope over the look-ahead window and its sum, for smoothing.
+    smoothing: DelayLine,
+    smoothing_sum: f64,
+    window: usize,
+    sample_rate: f64,
+    meter: GainReductionMeter,
+}
+
+impl Limiter {
+    /// Create a limiter with the given ceiling in dB, and look-ahead time in seconds, which is also
+    /// its latency (plus a couple of samples for the true peak detector).
+    pub fn new(ceiling: Param, lookahead: Time) -> Self {
+        Self {
+            ceiling,
+            release: Param::Constant(0.1),
+            lookahead,
+            lines: Default::default(),
+            recent: [0.0; 4],
+            required: DelayLine::default(),
+            envelope: 1.0,
+            smoothing: DelayLine::default(),
+            smoothing_sum: 0.0,
+            window: 0,
+            sample_rate: 0.0,
+            meter: GainReductionMeter::default(),
+        }
+    }
+
+    /// A meter showing the limiter's gain reduction.
+    pub fn meter(&self) -> GainReductionMeter {
+        self.meter.clone()
+    }
+
+    /// The delay the limiter adds, in samples.
+    pub fn latency(&self) -> usize {
+        self.window + TRUE_PEAK_DELAY
+    }
+}
+
+impl Effect for Limiter {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        // Only sizes the window if the limiter wasn't prepared for this sample rate.
+        self.prepare(sample_rate);
+        let ceiling = db_to_gain(self.ceiling.value());
+
+        // Find the gain needed to keep the latest segment under the ceiling. Using the louder
+        // channel's sample keeps the channels linked.
+        let peak = if input[0].abs() > input[1].abs() { input[0] } else { input[1] };
+        self.recent.rotate_left(1);
+        self.recent[3] = peak;
+        let peak = true_peak(self.recent);
+        self.required.write(if peak > ceiling { ceiling / peak } else { 1.0 });
+
+        // Hold the lowest gain needed anywhere in the look-ahead window (plus a sample for the
+        // detector being late), then let it recover at the release rate.
+        let held = (0..self.window + 2).map(|delay| self.required.tap(delay)).fold(1.0, f64::min);
+        let release = time_coefficient(self.release.value(), sample_rate);
+        self.envelope = held.min(held + (self.envelope - held) * release);
+
+        // Average the envelope over the window so that the gain ramps down smoothly and reaches the
+        // held gain by the time the peak comes out of the delay.
+        self.smoothing_sum += self.envelope - self.smoothing.tap(self.window - 1);
+        self.smoothing.write(self.envelope);
+        let gain = (self.smoothing_sum / self.window as f64).min(1.0);
+        self.meter.set(-gain_to_db(gain));
+
+        // Delay the input and apply the gain, clipping anything the envelope missed.
+        let mut output = [0.0; 2];
+        for (channel, line) in self.lines.iter_mut().enumerate() {
+            line.write(input[channel]);
+            output[channel] = (line.tap(self.window + TRUE_PEAK_DELAY) * gain).clamp(-ceiling, ceiling);
+        }
+        output
+    }
+
+    fn reset(&mut self) {
+        self.lines.iter_mut().for_each(DelayLine::clear);
+        self.recent = [0.0; 4];
+        self.required.fill(1.0);
+        self.envelope = 1.0;
+        self.smoothing.fill(1.0);
+        self.smoothing_sum = self.window as f64;
+        self.meter.set(0.0);
+    }
+
+    fn prepare(&mut self, sample_rate: f64) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        self.window = ((self.lookahead * sample_rate).round() as usize).max(1);
+        self.lines.iter_mut().for_each(|line| line.resize(self.window + TRUE_PEAK_DELAY));
+        self.required.resize(self.window + 2);
+        self.smoothing.resize(self.window);
+        self.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::random::Rng;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    #[test]
+    fn test_compressor_curve() {
+        let mut compressor = Compressor::new((-20.0).into(), 4.0.into());
+        compressor.knee = 0.0.into();
+        assert_eq!(compressor.gain_computer(-30.0), 0.0);
+        assert_relative_eq!(compressor.gain_computer(-12.0), -6.0);
+
+        // The soft knee should be continuous with the rest of the curve.
+        compressor.knee = 6.0.into();
+        assert_eq!(compressor.gain_computer(-23.0), 0.0);
+        assert_relative_eq!(compressor.gain_computer(-17.0), -2.25);
+        assert!(compressor.gain_computer(-20.0) < 0.0);
+    }
+
+    #[test]
+    fn test_compressor() {
+        let mut compressor = Compressor::new((-20.0).into(), 4.0.into());
+        compressor.knee = 0.0.into();
+        compressor.makeup = 3.0.into();
+        let meter = compressor.meter();
+
+        // A steady level 8dB over the threshold should settle at 6dB of gain reduction.
+        let level = db_to_gain(-12.0);
+        let mut output = [0.0; 2];
+        for _ in 0..48000 {
+            output = compressor.process([level, -level], SAMPLE_RATE);
+        }
+        assert_relative_eq!(meter.get(), 6.0, epsilon = 1e-6);
+        assert_relative_eq!(gain_to_db(output[0]), -15.0, epsilon = 1e-6);
+        assert_relative_eq!(output[1], -output[0]);
+
+        // It should release once the input drops below the threshold.
+        for _ in 0..48000 {
+            compressor.process([0.01, 0.01], SAMPLE_RATE);
+        }
+        assert_relative_eq!(meter.get(), 0.0, epsilon = 1e-3);
+    }
+
+    /// The output of the limiter, and the input delayed by its latency.
+    fn limit(limiter: &mut Limiter, input: &[Sample]) -> (Vec<Frame>, Vec<Sample>) {
+        let output: Vec<Frame> = input.iter().map(|&sample| limiter.process([sample, sample * 0.5], SAMPLE_RATE)).collect();
+        let delayed = std::iter::repeat_n(0.0, limiter.latency()).chain(input.iter().copied()).collect();
+        (output, delayed)
+    }
+
+    #[test]
+    fn test_limiter_quiet() {
+        // Signals under the ceiling should just be delayed.
+        let mut limiter = Limiter::new(0.0.into(), 0.005);
+        let input: Vec<Sample> = (0..2000).map(|n| 0.8 * f64::sin(n as f64 * 0.01)).collect();
+        let (output, delayed) = limit(&mut limiter, &input);
+        for (frame, expected) in output.iter().zip(delayed) {
+            assert_relative_eq!(frame[0], expected, epsilon = 1e-12);
+        }
+        assert_relative_eq!(limiter.meter().get(), 0.0, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn test_limiter_ceiling() {
+        // A loud noisy signal with a sudden peak should never go over the ceiling, even between
+        // samples.
+        let mut limiter = Limiter::new((-1.0).into(), 0.005);
+        let ceiling = db_to_gain(-1.0);
+        let mut rng = Rng::new(7);
+        let mut input: Vec<Sample> = (0..20000).map(|n| 3.0 * f64::sin(n as f64 * 1.3) + rng.next_bipolar()).collect();
+        input[10000] = 10.0;
+        let (output, _) = limit(&mut limiter, &input);
+
+        for window in output.windows(4) {
+            assert!(true_peak([window[0][0], window[1][0], window[2][0], window[3][0]]) <= ceiling * 1.001);
+        }
+
+        // The gain should start coming down before the peak comes out of the delay.
+        let peak_output = 10000 + limiter.latency();
+        let before = output[peak_output - limiter.window / 2][0] / input[10000 - limiter.window / 2];
+        assert!(before < ceiling / 4.0);
+        assert!(limiter.meter().get() > 9.0);
+    }
+}
@@ -0,0 +1,567 @@
+//! Stereo effects which process the output of a synth network before it's sent to the audio device,
+//! and a rack which chains them together.
+
+use std::f64::consts::PI;
+
+use crate::delay_line::DelayLine;
+use crate::filter::{Filter, FilterMode, StateVariable};
+use crate::lfo::{Lfo, LfoParams};
+use crate::signal::Continuous;
+use crate::types::{Frame, Sample, Time};
+
+/// The longest delay time the stereo delay supports, in seconds.
+const MAX_DELAY_TIME: Time = 4.0;
+
+/// The delay times of the modulated delay effects, in seconds.
+const CHORUS_DELAY: Time = 0.015;
+const CHORUS_DEPTH: Time = 0.010;
+const FLANGER_DELAY: Time = 0.001;
+const FLANGER_DEPTH: Time = 0.005;
+
+/// A parameter of an effect, which is either constant or follows a signal so that it can be
+/// modulated. Signals without a value yet read as 0.
+#[derive(Clone)]
+pub enum Param {
+    Constant(f64),
+    Signal(Continuous<f64>),
+}
+
+impl Param {
+    /// The current value of the parameter.
+    pub fn value(&self) -> f64 {
+        match self {
+            Param::Constant(value) => *value,
+            Param::Signal(signal) => signal.sample().unwrap_or(0.0),
+        }
+    }
+}
+
+impl From<f64> for Param {
+    fn from(value: f64) -> Self {
+        Param::Constant(value)
+    }
+}
+
+impl From<Continuous<f64>> for Param {
+    fn from(signal: Continuous<f64>) -> Self {
+        Param::Signal(signal)
+    }
+}
+
+/// An effect which processes one stereo frame at a time.
+pub trait Effect: Send {
+    /// Process a single frame at the given sample rate.
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame;
+
+    /// Clear the effect's internal state, e.g. the contents of delay lines.
+    fn reset(&mut self) {}
+
+    /// Get ready to process at the given sample rate, before processing starts. Effects which need
+    /// expensive setup for a sample rate do it here rather than on the audio thread.
+    fn prepare(&mut self, _sample_rate: f64) {}
+}
+
+/// A chain of effects which are applied in order.
+#[derive(Default)]
+pub struct EffectsRack {
+    effects: Vec<Box<dyn Effect>>,
+}
+
+impl EffectsRack {
+    /// Create an empty rack, which passes its input through unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an effect to the end of the chain.
+    pub fn with<E: Effect + 'static>(mut self, effect: E) -> Self {
+        self.push(effect);
+        self
+    }
+
+    /// Add an effect to the end of the chain.
+    pub fn push<E: Effect + 'static>(&mut self, effect: E) {
+        self.effects.push(Box::new(effect));
+    }
+
+    /// The number of effects in the chain.
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+
+    /// Whether the chain has no effects.
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+}
+
+impl Effect for EffectsRack {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        self.effects.iter_mut().fold(input, |frame, effect| effect.process(frame, sample_rate))
+    }
+
+    fn reset(&mut self) {
+        self.effects.iter_mut().for_each(|effect| effect.reset());
+    }
+
+    fn prepare(&mut self, sample_rate: f64) {
+        self.effects.iter_mut().for_each(|effect| effect.prepare(sample_rate));
+    }
+}
+
+/// Mix a dry and wet frame together, where a mix of 0 is fully dry and 1 is fully wet.
+pub fn mix_frames(dry: Frame, wet: Frame, mix: f64) -> Frame {
+    let mix = mix.clamp(0.0, 1.0);
+    [dry[0] + (wet[0] - dry[0]) * mix, dry[1] + (wet[1] - dry[1]) * mix]
+}
+
+/// The time of a delay, either in seconds or synced to a tempo.
+#[derive(Clone)]
+pub enum DelayTime {
+    Seconds(Param),
+    /// A number of beats at the given tempo in beats per minute.
+    Beats { beats: f64, tempo: Param },
+}
+
+impl DelayTime {
+    /// The current delay time in seconds.
+    pub fn seconds(&self) -> Time {
+        match self {
+            DelayTime::Seconds(seconds) => seconds.value(),
+            DelayTime::Beats { beats, tempo } => {
+                let tempo = tempo.value();
+                if tempo > 0.0 { beats * 60.0 / tempo } else { 0.0 }
+            },
+        }
+    }
+}
+
+/// A stereo delay with filtering in the feedback path, so that repeats get darker and thinner.
+pub struct StereoDelay {
+    /// The delay time of the left channel.
+    pub time: DelayTime,
+    /// The right channel's delay time as a multiple of the left's.
+    pub spread: Param,
+    /// How much of the delayed signal is fed back, from 0 to just under 1.
+    pub feedback: Param,
+    /// The cutoff of the high-pass and low-pass filters in the feedback path in Hz, or None to
+    /// leave the repeats unfiltered.
+    pub low_cut: Option<Param>,
+    pub high_cut: Option<Param>,
+    /// Whether the repeats bounce between the left and right channels.
+    pub ping_pong: bool,
+    pub mix: Param,
+    lines: [DelayLine; 2],
+    filters: [[StateVariable; 2]; 2],
+    sample_rate: f64,
+}
+
+impl StereoDelay {
+    /// Create a new delay with the given time and feedback, and no filtering.
+    pub fn new(time: DelayTime, feedback: Param, mix: Param) -> Self {
+        let filters = || [StateVariable::new(FilterMode::HighPass), StateVariable::new(FilterMode::LowPass)];
+        Self {
+            time,
+            spread: Param::Constant(1.0),
+            feedback,
+            low_cut: None,
+            high_cut: None,
+            ping_pong: false,
+            mix,
+            lines: Default::default(),
+            filters: [filters(), filters()],
+            sample_rate: 0.0,
+        }
+    }
+}
+
+impl Effect for StereoDelay {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        // Only sizes the lines if the delay wasn't prepared for this sample rate.
+        self.prepare(sample_rate);
+
+        let time = self.time.seconds() * sample_rate;
+        let times = [time, time * self.spread.value()];
+        let feedback = self.feedback.value().clamp(0.0, 0.99);
+        let low_cut = self.low_cut.as_ref().map(Param::value);
+        let high_cut = self.high_cut.as_ref().map(Param::value);
+
+        // Read and filter the delayed signal. The most recent sample in the delay lines is already
+        // a sample old.
+        let mut wet = [0.0; 2];
+        for channel in 0..2 {
+            let [high_pass, low_pass] = &mut self.filters[channel];
+            let mut delayed = self.lines[channel].read(times[channel] - 1.0);
+            if let Some(cutoff) = low_cut {
+                high_pass.set_params(cutoff, 0.0, sample_rate);
+                delayed = high_pass.process(delayed);
+            }
+            if let Some(cutoff) = high_cut {
+                low_pass.set_params(cutoff, 0.0, sample_rate);
+                delayed = low_pass.process(delayed);
+            }
+            wet[channel] = delayed;
+        }
+
+        // Write the input and feedback back into the delay lines.
+        if self.ping_pong {
+            let mono = (input[0] + input[1]) * 0.5;
+            self.lines[0].write(mono + wet[1] * feedback);
+            self.lines[1].write(wet[0] * feedback);
+        }
+        else {
+            for channel in 0..2 {
+                self.lines[channel].write(input[channel] + wet[channel] * feedback);
+            }
+        }
+
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        self.lines.iter_mut().for_each(DelayLine::clear);
+        self.filters.iter_mut().flatten().for_each(|filter| filter.reset());
+    }
+
+    fn prepare(&mut self, sample_rate: f64) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            let length = (MAX_DELAY_TIME * sample_rate).ceil() as usize;
+            self.lines.iter_mut().for_each(|line| line.resize(length));
+        }
+    }
+}
+
+/// A delay line modulated by an LFO, which is the basis of the chorus and flanger. The LFOs for
+/// each channel are a quarter cycle apart to widen the stereo image.
+struct ModulatedDelay {
+    lines: [DelayLine; 2],
+    lfos: [Lfo; 2],
+    last_output: Frame,
+    /// The longest delay plus modulation depth the lines are sized for, in seconds.
+    max_delay: Time,
+    sample_rate: f64,
+}
+
+impl ModulatedDelay {
+    /// Create a modulated delay whose delay plus depth can be up to `max_delay` seconds.
+    fn new(max_delay: Time) -> Self {
+        Self {
+            lines: Default::default(),
+            lfos: [Lfo::new(LfoParams::default()), Lfo::new(LfoParams { start_phase: 0.25, ..LfoParams::default() })],
+            last_output: [0.0; 2],
+            max_delay,
+            sample_rate: 0.0,
+        }
+    }
+
+    /// Size the delay lines for the sample rate, if they aren't already.
+    fn prepare(&mut self, sample_rate: f64) {
+        if sample_rate != self.sample_rate {
+            self.sample_rate = sample_rate;
+            let length = (self.max_delay * sample_rate).ceil() as usize + 2;
+            self.lines.iter_mut().for_each(|line| line.resize(length));
+        }
+    }
+
+    /// Process a frame, delaying it by `delay` plus up to `depth` seconds of modulation at the
+    /// given rate, and mixing in `feedback` of the previous output.
+    fn process(&mut self, input: Frame, sample_rate: f64, delay: Time, depth: Time, rate: f64, feedback: f64) -> Frame {
+        self.prepare(sample_rate);
+
+        let mut output = [0.0; 2];
+        for channel in 0..2 {
+            let modulation = self.lfos[channel].step(true, rate, 1.0, 1.0 / sample_rate);
+            let delay_samples = (delay + depth * (modulation + 1.0) * 0.5) * sample_rate;
+            self.lines[channel].write(input[channel] + self.last_output[channel] * feedback);
+            output[channel] = self.lines[channel].read(delay_samples);
+        }
+        self.last_output = output;
+        output
+    }
+
+    fn reset(&mut self) {
+        self.lines.iter_mut().for_each(DelayLine::clear);
+        self.last_output = [0.0; 2];
+    }
+}
+
+/// A stereo chorus, which mixes the input with a slowly modulated delayed copy of itself.
+pub struct Chorus {
+    /// The rate of the modulation in Hz.
+    pub rate: Param,
+    /// The depth of the modulation, from 0 to 1.
+    pub depth: Param,
+    pub mix: Param,
+    delay: ModulatedDelay,
+}
+
+impl Chorus {
+    /// Create a new chorus with the given rate, depth and mix.
+    pub fn new(rate: Param, depth: Param, mix: Param) -> Self {
+        Self {
+            rate,
+            depth,
+            mix,
+            delay: ModulatedDelay::new(CHORUS_DELAY + CHORUS_DEPTH),
+        }
+    }
+}
+
+impl Effect for Chorus {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        let depth = CHORUS_DEPTH * self.depth.value().clamp(0.0, 1.0);
+        let wet = self.delay.process(input, sample_rate, CHORUS_DELAY, depth, self.rate.value(), 0.0);
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        self.delay.reset();
+    }
+
+    fn prepare(&mut self, sample_rate: f64) {
+        self.delay.prepare(sample_rate);
+    }
+}
+
+/// A stereo flanger, which mixes the input with a very short modulated delay with feedback to
+/// produce a sweeping comb filter.
+pub struct Flanger {
+    /// The rate of the modulation in Hz.
+    pub rate: Param,
+    /// The depth of the modulation, from 0 to 1.
+    pub depth: Param,
+    /// How much of the output is fed back, from -1 to 1. Negative values emphasise different
+    /// harmonics.
+    pub feedback: Param,
+    pub mix: Param,
+    delay: ModulatedDelay,
+}
+
+impl Flanger {
+    /// Create a new flanger with the given rate, depth, feedback and mix.
+    pub fn new(rate: Param, depth: Param, feedback: Param, mix: Param) -> Self {
+        Self {
+            rate,
+            depth,
+            feedback,
+            mix,
+            delay: ModulatedDelay::new(FLANGER_DELAY + FLANGER_DEPTH),
+        }
+    }
+}
+
+impl Effect for Flanger {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        let depth = FLANGER_DEPTH * self.depth.value().clamp(0.0, 1.0);
+        let feedback = self.feedback.value().clamp(-0.95, 0.95);
+        let wet = self.delay.process(input, sample_rate, FLANGER_DELAY, depth, self.rate.value(), feedback);
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        self.delay.reset();
+    }
+
+    fn prepare(&mut self, sample_rate: f64) {
+        self.delay.prepare(sample_rate);
+    }
+}
+
+/// A first order allpass filter, which shifts the phase of frequencies around its cutoff without
+/// changing their level.
+#[derive(Clone, Copy, Debug, Default)]
+struct Allpass {
+    x1: Sample,
+    y1: Sample,
+}
+
+impl Allpass {
+    fn process(&mut self, input: Sample, coefficient: f64) -> Sample {
+        let output = coefficient * input + self.x1 - coefficient * self.y1;
+        self.x1 = input;
+        self.y1 = output;
+        output
+    }
+}
+
+/// A stereo phaser, which mixes the input with a copy passed through a chain of swept allpass
+/// filters to produce moving notches.
+pub struct Phaser {
+    /// The rate of the sweep in Hz.
+    pub rate: Param,
+    /// How many octaves the sweep covers either side of the centre frequency.
+    pub depth: Param,
+    /// The centre frequency of the sweep in Hz.
+    pub frequency: Param,
+    /// How much of the output is fed back, from -1 to 1.
+    pub feedback: Param,
+    pub mix: Param,
+    stages: [Vec<Allpass>; 2],
+    lfos: [Lfo; 2],
+    last_output: Frame,
+}
+
+impl Phaser {
+    /// Create a new phaser with the given number of allpass stages (usually 4, 6 or 8).
+    pub fn new(stage_count: usize, rate: Param, depth: Param, frequency: Param, feedback: Param, mix: Param) -> Self {
+        Self {
+            rate,
+            depth,
+            frequency,
+            feedback,
+            mix,
+            stages: [vec![Allpass::default(); stage_count], vec![Allpass::default(); stage_count]],
+            lfos: [Lfo::new(LfoParams::default()), Lfo::new(LfoParams { start_phase: 0.25, ..LfoParams::default() })],
+            last_output: [0.0; 2],
+        }
+    }
+}
+
+impl Effect for Phaser {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        let (rate, depth, frequency) = (self.rate.value(), self.depth.value(), self.frequency.value());
+        let feedback = self.feedback.value().clamp(-0.95, 0.95);
+
+        let mut wet = [0.0; 2];
+        for channel in 0..2 {
+            let modulation = self.lfos[channel].step(true, rate, 1.0, 1.0 / sample_rate);
+            let cutoff = (frequency * f64::powf(2.0, depth * modulation)).clamp(1.0, sample_rate * 0.49);
+            let tan = f64::tan(PI * cutoff / sample_rate);
+            let coefficient = (tan - 1.0) / (tan + 1.0);
+
+            let mut sample = input[channel] + self.last_output[channel] * feedback;
+            for stage in self.stages[channel].iter_mut() {
+                sample = stage.process(sample, coefficient);
+            }
+            wet[channel] = sample;
+        }
+        self.last_output = wet;
+
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        self.stages.iter_mut().flatten().for_each(|stage| *stage = Allpass::default());
+        self.last_output = [0.0; 2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const SAMPLE_RATE: f64 = 1000.0;
+
+    /// Run an impulse through an effect and return the output of the left and right channels.
+    fn impulse_response<E: Effect>(effect: &mut E, length: usize) -> Vec<Frame> {
+        (0..length).map(|i| {
+            let input = if i == 0 { 1.0 } else { 0.0 };
+            effect.process([input, input], SAMPLE_RATE)
+        }).collect()
+    }
+
+    #[test]
+    fn test_stereo_delay() {
+        let mut delay = StereoDelay::new(DelayTime::Seconds(0.01.into()), 0.5.into(), 1.0.into());
+        let output = impulse_response(&mut delay, 40);
+
+        // Echoes every 10 samples, halving each time.
+        assert_eq!(output[0], [0.0, 0.0]);
+        assert_relative_eq!(output[10][0], 1.0);
+        assert_relative_eq!(output[20][0], 0.5);
+        assert_relative_eq!(output[30][1], 0.25);
+        assert_eq!(output[15], [0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_tempo_synced_delay() {
+        // A quarter note at 6000bpm is 10ms.
+        let time = DelayTime::Beats { beats: 1.0, tempo: 6000.0.into() };
+        assert_relative_eq!(time.seconds(), 0.01);
+    }
+
+    #[test]
+    fn test_ping_pong_delay() {
+        let mut delay = StereoDelay::new(DelayTime::Seconds(0.01.into()), 0.5.into(), 1.0.into());
+        delay.ping_pong = true;
+        let output = impulse_response(&mut delay, 40);
+
+        // Echoes should alternate between the left and right channels.
+        assert_relative_eq!(output[10][0], 1.0);
+        assert_eq!(output[10][1], 0.0);
+        assert_eq!(output[20][0], 0.0);
+        assert_relative_eq!(output[20][1], 0.5);
+        assert_relative_eq!(output[30][0], 0.25);
+    }
+
+    #[test]
+    fn test_feedback_filter() {
+        // With a low-pass filter in the feedback path, the echoes should be smeared out.
+        let mut delay = StereoDelay::new(DelayTime::Seconds(0.01.into()), 0.5.into(), 1.0.into());
+        delay.high_cut = Some(50.0.into());
+        let output = impulse_response(&mut delay, 40);
+        assert!(output[10][0] < 0.5);
+        assert!(output[11][0] > 0.0);
+    }
+
+    #[test]
+    fn test_dry_effects() {
+        // All of the effects should pass the input through unchanged when fully dry.
+        let mut rack = EffectsRack::new()
+            .with(Chorus::new(1.0.into(), 1.0.into(), 0.0.into()))
+            .with(Flanger::new(1.0.into(), 1.0.into(), 0.5.into(), 0.0.into()))
+            .with(Phaser::new(4, 1.0.into(), 2.0.into(), 500.0.into(), 0.5.into(), 0.0.into()));
+        assert_eq!(rack.len(), 3);
+        for i in 0..100 {
+            let input = [i as f64, -(i as f64)];
+            assert_eq!(rack.process(input, SAMPLE_RATE), input);
+        }
+    }
+
+    #[test]
+    fn test_modulated_effects() {
+        // The wet signal of each effect should be delayed or phase shifted, and stay bounded.
+        let mut effects: Vec<Box<dyn Effect>> = vec![
+            Box::new(Chorus::new(1.0.into(), 1.0.into(), 1.0.into())),
+            Box::new(Flanger::new(1.0.into(), 1.0.into(), 0.9.into(), 1.0.into())),
+            Box::new(Phaser::new(4, 1.0.into(), 2.0.into(), 100.0.into(), 0.9.into(), 1.0.into())),
+        ];
+        for effect in effects.iter_mut() {
+            let output: Vec<Frame> = (0..5000).map(|i| {
+                let input = (i as f64 * 0.1).sin();
+                effect.process([input, input], SAMPLE_RATE)
+            }).collect();
+            assert!(output.iter().flatten().all(|sample| sample.is_finite() && sample.abs() < 20.0));
+            assert_ne!(output[100][0], (100.0 * 0.1f64).sin());
+            // The channels should be modulated differently.
+            assert_ne!(output[1000][0], output[1000][1]);
+        }
+    }
+
+    #[test]
+    fn test_modulated_delay_length() {
+        // The delay lines are sized for the longest delay, so a delay that grows after the first
+        // sample isn't cut short.
+        let mut delay = ModulatedDelay::new(0.02);
+        delay.process([0.0; 2], SAMPLE_RATE, 0.001, 0.0, 0.0, 0.0);
+        let output: Vec<Frame> = (0..20)
+            .map(|i| {
+                let input = if i == 0 { 1.0 } else { 0.0 };
+                delay.process([input; 2], SAMPLE_RATE, 0.015, 0.0, 0.0, 0.0)
+            })
+            .collect();
+        assert_relative_eq!(output[15][0], 1.0);
+        assert_eq!(output[3], [0.0; 2]);
+    }
+
+    #[test]
+    fn test_signal_param() {
+        let mut input = crate::signal::Discrete::new();
+        let param = Param::from(input.hold());
+        assert_eq!(param.value(), 0.0);
+        input.push(0.5);
+        assert_eq!(param.value(), 0.5);
+    }
+}
@@ -0,0 +1,679 @@
+//! Envelope generators, which produce amplitude signals shaped by note gates.
+
+use crate::clock::Clock;
+use crate::signal::{Continuous, snapshot2};
+use crate::types::{Sample, Time, Velocity};
+
+/// The shape of a segment of an envelope as it moves from its start level to its end level.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    /// Move at a constant rate.
+    Linear,
+    /// Move along an exponential curve. Positive values move quickly at first and then slow down
+    /// (like an analog RC envelope), negative values start slowly and speed up. Larger magnitudes
+    /// produce steeper curves, and 0 is equivalent to linear.
+    Exponential(f64),
+}
+
+impl Curve {
+    /// Map the progress through a segment (0 to 1) to the proportion of the distance between the
+    /// start and end levels that has been covered (also 0 to 1).
+    pub fn shape(&self, progress: f64) -> f64 {
+        let progress = progress.clamp(0.0, 1.0);
+        match *self {
+            Curve::Exponential(k) if k.abs() > 1e-6 => {
+                (1.0 - f64::exp(-k * progress)) / (1.0 - f64::exp(-k))
+            },
+            _ => progress,
+        }
+    }
+}
+
+/// What an envelope should do when its gate opens while it's still sounding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retrigger {
+    /// Restart the attack stage from zero.
+    Reset,
+    /// Restart the attack stage from the current level, which avoids clicks.
+    FromCurrent,
+    /// Don't restart the attack, and instead move from the current level back towards the
+    /// sustain level.
+    Legato,
+}
+
+/// The parameters of an ADSR envelope. Times are in seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdsrParams {
+    pub attack: Time,
+    pub decay: Time,
+    pub sustain: f64,
+    pub release: Time,
+    pub attack_curve: Curve,
+    pub decay_curve: Curve,
+    pub release_curve: Curve,
+    /// How much the note velocity affects the output level, from 0 (not at all) to 1 (the output
+    /// is scaled by the velocity).
+    pub velocity_sensitivity: f64,
+    pub retrigger: Retrigger,
+}
+
+impl Default for AdsrParams {
+    fn default() -> Self {
+        Self {
+            attack: 0.01,
+            decay: 0.1,
+            sustain: 0.8,
+            release: 0.2,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Exponential(4.0),
+            release_curve: Curve::Exponential(4.0),
+            velocity_sensitivity: 1.0,
+            retrigger: Retrigger::FromCurrent,
+        }
+    }
+}
+
+/// The stage an ADSR envelope is currently in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdsrStage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// An ADSR envelope generator, which is stepped once per sample with the current gate.
+#[derive(Clone, Debug)]
+pub struct Adsr {
+    params: AdsrParams,
+    stage: AdsrStage,
+    level: f64,
+    stage_start_level: f64,
+    stage_time: Time,
+    gate: bool,
+    velocity: Velocity,
+}
+
+impl Adsr {
+    /// Create a new idle envelope with the given parameters.
+    pub fn new(params: AdsrParams) -> Self {
+        Self {
+            params,
+            stage: AdsrStage::Idle,
+            level: 0.0,
+            stage_start_level: 0.0,
+            stage_time: 0.0,
+            gate: false,
+            velocity: 1.0,
+        }
+    }
+
+    /// The current stage of the envelope.
+    pub fn stage(&self) -> AdsrStage {
+        self.stage
+    }
+
+    /// The current level of the envelope, before velocity scaling.
+    pub fn level(&self) -> f64 {
+        self.level
+    }
+
+    /// Replace the envelope's parameters. Takes effect from the next step.
+    pub fn set_params(&mut self, params: AdsrParams) {
+        self.params = params;
+    }
+
+    /// Step the envelope forward by `time_step` seconds with the given gate and velocity, and
+    /// return the new output level. The velocity is only latched when the gate opens.
+    pub fn step(&mut self, gate: bool, velocity: Velocity, time_step: Time) -> Sample {
+        if gate && !self.gate {
+            self.velocity = velocity.clamp(0.0, 1.0);
+            self.trigger();
+        }
+        else if !gate && self.gate && self.stage != AdsrStage::Idle {
+            self.enter(AdsrStage::Release);
+        }
+        self.gate = gate;
+
+        self.stage_time += time_step;
+        self.update_level();
+
+        let velocity_scale = 1.0 - self.params.velocity_sensitivity * (1.0 - self.velocity);
+        self.level * velocity_scale
+    }
+
+    /// Handle the gate opening, according to the retrigger mode.
+    fn trigger(&mut self) {
+        match self.params.retrigger {
+            Retrigger::Reset => {
+                self.level = 0.0;
+                self.enter(AdsrStage::Attack);
+            },
+            Retrigger::FromCurrent => self.enter(AdsrStage::Attack),
+            Retrigger::Legato if self.stage == AdsrStage::Idle => self.enter(AdsrStage::Attack),
+            Retrigger::Legato => self.enter(AdsrStage::Decay),
+        }
+    }
+
+    /// Move to the given stage, starting from the current level.
+    fn enter(&mut self, stage: AdsrStage) {
+        self.stage = stage;
+        self.stage_start_level = self.level;
+        self.stage_time = 0.0;
+    }
+
+    /// Update the level for the time spent in the current stage, moving on to the next stage if
+    /// this one has finished.
+    fn update_level(&mut self) {
+        let params = self.params;
+        let (duration, target, curve, next) = match self.stage {
+            AdsrStage::Idle => {
+                self.level = 0.0;
+                return;
+            },
+            AdsrStage::Sustain => {
+                self.level = params.sustain;
+                return;
+            },
+            AdsrStage::Attack => (params.attack, 1.0, params.attack_curve, AdsrStage::Decay),
+            AdsrStage::Decay => (params.decay, params.sustain, params.decay_curve, AdsrStage::Sustain),
+            AdsrStage::Release => (params.release, 0.0, params.release_curve, AdsrStage::Idle),
+        };
+
+        if self.stage_time >= duration {
+            // Carry the remaining time over into the next stage.
+            let overshoot = self.stage_time - duration;
+            self.level = target;
+            self.enter(next);
+            self.stage_time = overshoot;
+            self.update_level();
+        }
+        else {
+            let progress = curve.shape(self.stage_time / duration);
+            self.level = self.stage_start_level + (target - self.stage_start_level) * progress;
+        }
+    }
+}
+
+/// Create an ADSR envelope node driven by the given gate and velocity signals, which is stepped
+/// every time the time signal changes. The output is an amplitude in the range 0-1 which can be
+/// multiplied into a voice or used to modulate other parameters.
+pub fn adsr(time: &mut Continuous<Time>,
+            gate: &Continuous<bool>,
+            velocity: &Continuous<Velocity>,
+            params: AdsrParams)
+    -> Continuous<Sample>
+{
+    let mut clock = Clock::new();
+    let mut envelope = Adsr::new(params);
+    snapshot2(time, gate, velocity, move |time, gate, velocity| {
+        let time_step = clock.tick(time);
+        envelope.step(gate, velocity, time_step)
+    })
+}
+
+/// A single segment of a multi-stage envelope, which moves from the previous level to `level`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    /// How long the segment lasts, in seconds.
+    pub duration: Time,
+    /// The level the segment ends at.
+    pub level: f64,
+    pub curve: Curve,
+}
+
+impl Segment {
+    /// Create a new segment moving to `level` over `duration` seconds along the given curve.
+    pub fn new(duration: Time, level: f64, curve: Curve) -> Self {
+        Self { duration, level, curve }
+    }
+}
+
+/// When a multi-stage envelope should loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopMode {
+    /// Loop while the gate is open, then continue on to the segments after the loop.
+    WhileGate,
+    /// Loop forever, regardless of the gate, which allows the envelope to be used as an LFO.
+    Always,
+}
+
+/// A range of segments that a multi-stage envelope repeats. When the end of segment `end` is
+/// reached, the envelope continues from the start of segment `start`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnvelopeLoop {
+    pub start: usize,
+    pub end: usize,
+    pub mode: LoopMode,
+}
+
+/// The parameters of a multi-stage envelope.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultiStageParams {
+    /// The level the envelope starts at before it's first triggered, and is reset to when using
+    /// `Retrigger::Reset`.
+    pub start_level: f64,
+    pub segments: Vec<Segment>,
+    /// The index of the segment which the envelope holds at the end of while the gate is open.
+    /// When the gate closes, the envelope moves on to the following segment.
+    pub sustain: Option<usize>,
+    /// An optional range of segments to repeat. Looping takes priority over the sustain point.
+    pub loop_segments: Option<EnvelopeLoop>,
+    /// How much the note velocity affects the output level, from 0 (not at all) to 1 (the output
+    /// is scaled by the velocity).
+    pub velocity_sensitivity: f64,
+    pub retrigger: Retrigger,
+}
+
+impl MultiStageParams {
+    /// Create parameters for a one-shot envelope made of the given segments, with no sustain or
+    /// loop points.
+    pub fn new(segments: Vec<Segment>) -> Self {
+        Self {
+            start_level: 0.0,
+            segments,
+            sustain: None,
+            loop_segments: None,
+            velocity_sensitivity: 0.0,
+            retrigger: Retrigger::FromCurrent,
+        }
+    }
+
+    /// The segment after which the envelope skips ahead when the gate closes, if any.
+    fn release_point(&self) -> Option<usize> {
+        match (self.sustain, self.loop_segments) {
+            (Some(sustain), _) => Some(sustain),
+            (None, Some(EnvelopeLoop { end, mode: LoopMode::WhileGate, .. })) => Some(end),
+            _ => None,
+        }
+    }
+}
+
+/// An envelope made up of any number of segments, each with its own duration, level and curve,
+/// and optional sustain and loop points. Stepped once per sample with the current gate.
+#[derive(Clone, Debug)]
+pub struct MultiStageEnvelope {
+    params: MultiStageParams,
+    /// The index of the current segment, or None if the envelope is idle.
+    segment: Option<usize>,
+    level: f64,
+    segment_start_level: f64,
+    segment_time: Time,
+    sustaining: bool,
+    gate: bool,
+    velocity: Velocity,
+}
+
+impl MultiStageEnvelope {
+    /// Create a new idle envelope with the given parameters.
+    pub fn new(params: MultiStageParams) -> Self {
+        Self {
+            level: params.start_level,
+            params,
+            segment: None,
+            segment_start_level: 0.0,
+            segment_time: 0.0,
+            sustaining: false,
+            gate: false,
+            velocity: 1.0,
+        }
+    }
+
+    /// The index of the segment the envelope is currently in, or None if it's idle.
+    pub fn segment(&self) -> Option<usize> {
+        self.segment
+    }
+
+    /// Whether the envelope is holding at its sustain point.
+    pub fn is_sustaining(&self) -> bool {
+        self.sustaining
+    }
+
+    /// The current level of the envelope, before velocity scaling.
+    pub fn level(&self) -> f64 {
+        self.level
+    }
+
+    /// Step the envelope forward by `time_step` seconds with the given gate and velocity, and
+    /// return the new output level. The velocity is only latched when the gate opens.
+    pub fn step(&mut self, gate: bool, velocity: Velocity, time_step: Time) -> Sample {
+        if gate && !self.gate {
+            self.velocity = velocity.clamp(0.0, 1.0);
+            self.trigger();
+        }
+        else if !gate && self.gate && self.segment.is_some() {
+            self.release();
+        }
+        self.gate = gate;
+
+        if !self.sustaining {
+            self.segment_time += time_step;
+            self.update_level();
+        }
+
+        let velocity_scale = 1.0 - self.params.velocity_sensitivity * (1.0 - self.velocity);
+        self.level * velocity_scale
+    }
+
+    /// Handle the gate opening, according to the retrigger mode.
+    fn trigger(&mut self) {
+        match (self.params.retrigger, self.segment, self.params.sustain) {
+            (Retrigger::Legato, Some(_), Some(sustain)) => self.enter(Some(sustain)),
+            (Retrigger::Reset, _, _) => {
+                self.level = self.params.start_level;
+                self.enter(Some(0));
+            },
+            _ => self.enter(Some(0)),
+        }
+    }
+
+    /// Handle the gate closing by skipping to the segment after the release point. If there are no
+    /// segments after it, there's nothing to release through, so the envelope stops silent rather
+    /// than holding whatever level it had reached.
+    fn release(&mut self) {
+        if let (Some(segment), Some(release_point)) = (self.segment, self.params.release_point()) {
+            if segment <= release_point {
+                self.enter(Some(release_point + 1));
+                if self.segment.is_none() {
+                    self.level = 0.0;
+                }
+            }
+        }
+    }
+
+    /// Move to the given segment, starting from the current level.
+    fn enter(&mut self, segment: Option<usize>) {
+        self.segment = segment.filter(|&index| index < self.params.segments.len());
+        self.segment_start_level = self.level;
+        self.segment_time = 0.0;
+        self.sustaining = false;
+    }
+
+    /// The segment that follows the given one, taking loop and sustain points into account.
+    /// Returns None if the envelope should stop, and Some(None) if it should hold.
+    fn next_segment(&self, segment: usize) -> Option<Option<usize>> {
+        if let Some(envelope_loop) = self.params.loop_segments {
+            let looping = envelope_loop.mode == LoopMode::Always || self.gate;
+            if looping && segment == envelope_loop.end && envelope_loop.start <= envelope_loop.end {
+                return Some(Some(envelope_loop.start));
+            }
+        }
+
+        if self.gate && self.params.sustain == Some(segment) {
+            return Some(None);
+        }
+
+        let next = segment + 1;
+        (next < self.params.segments.len()).then_some(Some(next))
+    }
+
+    /// Update the level for the time spent in the current segment, moving on through the
+    /// following segments if it has finished.
+    fn update_level(&mut self) {
+        // Limit the number of segments we can skip in one step so that a loop of zero length
+        // segments can't hang.
+        for _ in 0..=self.params.segments.len() {
+            let Some(index) = self.segment else {
+                return;
+            };
+            let segment = self.params.segments[index];
+
+            if self.segment_time < segment.duration {
+                let progress = segment.curve.shape(self.segment_time / segment.duration);
+                self.level = self.segment_start_level + (segment.level - self.segment_start_level) * progress;
+                return;
+            }
+
+            // Carry the remaining time over into the next segment.
+            let overshoot = self.segment_time - segment.duration;
+            self.level = segment.level;
+            match self.next_segment(index) {
+                Some(Some(next)) => {
+                    self.enter(Some(next));
+                    self.segment_time = overshoot;
+                },
+                Some(None) => {
+                    self.sustaining = true;
+                    return;
+                },
+                None => {
+                    self.enter(None);
+                    return;
+                },
+            }
+        }
+    }
+}
+
+/// Create a multi-stage envelope node driven by the given gate and velocity signals, which is
+/// stepped every time the time signal changes.
+pub fn multi_stage_envelope(time: &mut Continuous<Time>,
+                            gate: &Continuous<bool>,
+                            velocity: &Continuous<Velocity>,
+                            params: MultiStageParams)
+    -> Continuous<Sample>
+{
+    let mut clock = Clock::new();
+    let mut envelope = MultiStageEnvelope::new(params);
+    snapshot2(time, gate, velocity, move |time, gate, velocity| {
+        let time_step = clock.tick(time);
+        envelope.step(gate, velocity, time_step)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const TIME_STEP: f64 = 0.001;
+
+    fn linear_params() -> AdsrParams {
+        AdsrParams {
+            attack: 0.01,
+            decay: 0.01,
+            sustain: 0.5,
+            release: 0.01,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
+            velocity_sensitivity: 1.0,
+            retrigger: Retrigger::Reset,
+        }
+    }
+
+    #[test]
+    fn test_curve() {
+        for curve in [Curve::Linear, Curve::Exponential(5.0), Curve::Exponential(-5.0)] {
+            assert_relative_eq!(curve.shape(0.0), 0.0);
+            assert_relative_eq!(curve.shape(1.0), 1.0);
+        }
+        assert!(Curve::Exponential(5.0).shape(0.5) > 0.5);
+        assert!(Curve::Exponential(-5.0).shape(0.5) < 0.5);
+        assert_relative_eq!(Curve::Exponential(0.0).shape(0.25), 0.25);
+    }
+
+    #[test]
+    fn test_adsr_stages() {
+        let mut envelope = Adsr::new(linear_params());
+
+        // Halfway through the attack.
+        for _ in 0..5 {
+            envelope.step(true, 1.0, TIME_STEP);
+        }
+        assert_eq!(envelope.stage(), AdsrStage::Attack);
+        assert_relative_eq!(envelope.level(), 0.5, epsilon = 1e-9);
+
+        // Into the decay, then sustain.
+        for _ in 0..10 {
+            envelope.step(true, 1.0, TIME_STEP);
+        }
+        assert_eq!(envelope.stage(), AdsrStage::Decay);
+        assert_relative_eq!(envelope.level(), 0.75, epsilon = 1e-9);
+        for _ in 0..100 {
+            envelope.step(true, 1.0, TIME_STEP);
+        }
+        assert_eq!(envelope.stage(), AdsrStage::Sustain);
+        assert_relative_eq!(envelope.level(), 0.5);
+
+        // Releasing should go back to idle.
+        envelope.step(false, 1.0, TIME_STEP);
+        assert_eq!(envelope.stage(), AdsrStage::Release);
+        for _ in 0..10 {
+            envelope.step(false, 1.0, TIME_STEP);
+        }
+        assert_eq!(envelope.stage(), AdsrStage::Idle);
+        assert_eq!(envelope.level(), 0.0);
+    }
+
+    #[test]
+    fn test_adsr_velocity() {
+        let mut envelope = Adsr::new(linear_params());
+        let mut output = 0.0;
+        for _ in 0..100 {
+            output = envelope.step(true, 0.5, TIME_STEP);
+        }
+        assert_relative_eq!(output, 0.25);
+    }
+
+    #[test]
+    fn test_adsr_retrigger() {
+        // Retrigger the envelope partway through its release and check where it goes next.
+        let retrigger = |retrigger| {
+            let mut envelope = Adsr::new(AdsrParams { retrigger, ..linear_params() });
+            for _ in 0..100 {
+                envelope.step(true, 1.0, TIME_STEP);
+            }
+            for _ in 0..5 {
+                envelope.step(false, 1.0, TIME_STEP);
+            }
+            envelope.step(true, 1.0, 0.0);
+            (envelope.stage(), envelope.level())
+        };
+
+        assert_eq!(retrigger(Retrigger::Reset), (AdsrStage::Attack, 0.0));
+        let (stage, level) = retrigger(Retrigger::FromCurrent);
+        assert_eq!(stage, AdsrStage::Attack);
+        assert_relative_eq!(level, 0.25, epsilon = 1e-9);
+        let (stage, level) = retrigger(Retrigger::Legato);
+        assert_eq!(stage, AdsrStage::Decay);
+        assert_relative_eq!(level, 0.25, epsilon = 1e-9);
+    }
+
+    fn segments() -> Vec<Segment> {
+        vec![
+            Segment::new(0.01, 1.0, Curve::Linear),
+            Segment::new(0.01, 0.5, Curve::Linear),
+            Segment::new(0.01, 0.0, Curve::Linear),
+        ]
+    }
+
+    #[test]
+    fn test_multi_stage_one_shot() {
+        let mut envelope = MultiStageEnvelope::new(MultiStageParams::new(segments()));
+
+        // The envelope should run through all of its segments and then stop, even though the
+        // gate is still open.
+        let levels: Vec<f64> = (0..40).map(|_| envelope.step(true, 1.0, TIME_STEP)).collect();
+        assert_relative_eq!(levels[4], 0.5, epsilon = 1e-9);
+        assert_relative_eq!(levels[9], 1.0, epsilon = 1e-9);
+        assert_relative_eq!(levels[14], 0.75, epsilon = 1e-9);
+        assert_relative_eq!(levels[24], 0.25, epsilon = 1e-9);
+        assert_eq!(levels[39], 0.0);
+        assert_eq!(envelope.segment(), None);
+    }
+
+    #[test]
+    fn test_multi_stage_sustain() {
+        let params = MultiStageParams { sustain: Some(1), ..MultiStageParams::new(segments()) };
+        let mut envelope = MultiStageEnvelope::new(params);
+
+        // The envelope should hold at the end of the sustain segment while the gate is open.
+        for _ in 0..100 {
+            envelope.step(true, 1.0, TIME_STEP);
+        }
+        assert!(envelope.is_sustaining());
+        assert_relative_eq!(envelope.level(), 0.5);
+
+        // Then release through the following segment.
+        envelope.step(false, 1.0, TIME_STEP);
+        assert_eq!(envelope.segment(), Some(2));
+        assert_relative_eq!(envelope.level(), 0.45, epsilon = 1e-9);
+        for _ in 0..10 {
+            envelope.step(false, 1.0, TIME_STEP);
+        }
+        assert_eq!(envelope.segment(), None);
+    }
+
+    #[test]
+    fn test_multi_stage_loop() {
+        let loop_segments = EnvelopeLoop { start: 1, end: 2, mode: LoopMode::WhileGate };
+        let params = MultiStageParams {
+            loop_segments: Some(loop_segments),
+            ..MultiStageParams::new(segments())
+        };
+        let mut envelope = MultiStageEnvelope::new(params.clone());
+
+        // Looping segments 1 and 2 should keep the envelope oscillating between 0.5 and 0.
+        for _ in 0..10 {
+            envelope.step(true, 1.0, TIME_STEP);
+        }
+        for _ in 0..5 {
+            for _ in 0..10 {
+                envelope.step(true, 1.0, TIME_STEP);
+            }
+            assert_eq!(envelope.segment(), Some(2));
+            assert_relative_eq!(envelope.level(), 0.5, epsilon = 1e-9);
+            for _ in 0..10 {
+                envelope.step(true, 1.0, TIME_STEP);
+            }
+            assert_eq!(envelope.segment(), Some(1));
+            assert_relative_eq!(envelope.level(), 0.0, epsilon = 1e-9);
+        }
+
+        // Closing the gate should leave the loop, and as there are no more segments, stop.
+        envelope.step(false, 1.0, TIME_STEP);
+        assert_eq!(envelope.segment(), None);
+
+        // An always-looping envelope should ignore the gate.
+        let loop_segments = EnvelopeLoop { mode: LoopMode::Always, ..loop_segments };
+        let params = MultiStageParams { loop_segments: Some(loop_segments), ..params };
+        let mut envelope = MultiStageEnvelope::new(params);
+        envelope.step(true, 1.0, TIME_STEP);
+        for _ in 0..1000 {
+            envelope.step(false, 1.0, TIME_STEP);
+        }
+        assert!(envelope.segment().is_some());
+    }
+
+    #[test]
+    fn test_multi_stage_release_from_last_segment() {
+        // With the loop ending on the final segment, closing the gate part way through it should
+        // silence the envelope rather than leave it stuck at its current level.
+        let loop_segments = EnvelopeLoop { start: 1, end: 2, mode: LoopMode::WhileGate };
+        let params = MultiStageParams {
+            loop_segments: Some(loop_segments),
+            ..MultiStageParams::new(segments())
+        };
+        let mut envelope = MultiStageEnvelope::new(params);
+        for _ in 0..25 {
+            envelope.step(true, 1.0, TIME_STEP);
+        }
+        assert_eq!(envelope.segment(), Some(2));
+        assert!(envelope.level() > 0.0);
+        assert_eq!(envelope.step(false, 1.0, TIME_STEP), 0.0);
+        assert_eq!(envelope.segment(), None);
+        assert_eq!(envelope.step(false, 1.0, TIME_STEP), 0.0);
+
+        // The same goes for a sustain point on the final segment.
+        let params = MultiStageParams { sustain: Some(1), ..MultiStageParams::new(segments()[..2].to_vec()) };
+        let mut envelope = MultiStageEnvelope::new(params);
+        for _ in 0..100 {
+            envelope.step(true, 1.0, TIME_STEP);
+        }
+        assert!(envelope.is_sustaining());
+        assert_eq!(envelope.step(false, 1.0, TIME_STEP), 0.0);
+        assert_eq!(envelope.segment(), None);
+    }
+}
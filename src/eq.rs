@@ -0,0 +1,278 @@
+//! A multi-band parametric EQ, for shaping the tone of the master output.
+//!
+//! Each band is a biquad with the RBJ audio EQ cookbook coefficients, whose frequency, gain and Q
+//! can all be modulated.
+
+use std::f64::consts::PI;
+
+use crate::effects::{Effect, Param};
+use crate::filter::{Biquad, Filter};
+use crate::types::{Frame, Frequency};
+
+/// The lowest Q a band can have, which keeps the shelves and cuts from misbehaving.
+const MIN_Q: f64 = 0.1;
+
+/// The response of an EQ band.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BandKind {
+    /// Boost or cut everything below the frequency.
+    LowShelf,
+    /// Boost or cut everything above the frequency.
+    HighShelf,
+    /// Boost or cut around the frequency, over a width set by the Q.
+    Peak,
+    /// Remove everything below the frequency (a high-pass filter). The gain is ignored.
+    LowCut,
+    /// Remove everything above the frequency (a low-pass filter). The gain is ignored.
+    HighCut,
+}
+
+/// The normalised coefficients of a band, where `a0` is 1.
+fn coefficients(kind: BandKind, frequency: Frequency, gain: f64, q: f64, sample_rate: f64) -> ([f64; 3], [f64; 2]) {
+    let w0 = 2.0 * PI * frequency.clamp(1.0, sample_rate * 0.49) / sample_rate;
+    let (sin_w0, cos_w0) = w0.sin_cos();
+    let alpha = sin_w0 / (2.0 * q.max(MIN_Q));
+    let level = f64::powf(10.0, gain / 40.0);
+    let shelf = 2.0 * level.sqrt() * alpha;
+
+    let (b, a) = match kind {
+        BandKind::Peak => (
+            [1.0 + alpha * level, -2.0 * cos_w0, 1.0 - alpha * level],
+            [1.0 + alpha / level, -2.0 * cos_w0, 1.0 - alpha / level],
+        ),
+        BandKind::LowShelf => (
+            [
+                level * ((level + 1.0) - (level - 1.0) * cos_w0 + shelf),
+                2.0 * level * ((level - 1.0) - (level + 1.0) * cos_w0),
+                level * ((level + 1.0) - (level - 1.0) * cos_w0 - shelf),
+            ],
+            [
+                (level + 1.0) + (level - 1.0) * cos_w0 + shelf,
+                -2.0 * ((level - 1.0) + (level + 1.0) * cos_w0),
+                (level + 1.0) + (level - 1.0) * cos_w0 - shelf,
+            ],
+        ),
+        BandKind::HighShelf => (
+            [
+                level * ((level + 1.0) + (level - 1.0) * cos_w0 + shelf),
+                -2.0 * level * ((level - 1.0) + (level + 1.0) * cos_w0),
+                level * ((level + 1.0) + (level - 1.0) * cos_w0 - shelf),
+            ],
+            [
+                (level + 1.0) - (level - 1.0) * cos_w0 + shelf,
+                2.0 * ((level - 1.0) - (level + 1.0) * cos_w0),
+                (level + 1.0) - (level - 1.0) * cos_w0 - shelf,
+            ],
+        ),
+        BandKind::LowCut => (
+            [(1.0 + cos_w0) / 2.0, -(1.0 + cos_w0), (1.0 + cos_w0) / 2.0],
+            [1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha],
+        ),
+        BandKind::HighCut => (
+            [(1.0 - cos_w0) / 2.0, 1.0 - cos_w0, (1.0 - cos_w0) / 2.0],
+            [1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha],
+        ),
+    };
+
+    ([b[0] / a[0], b[1] / a[0], b[2] / a[0]], [a[1] / a[0], a[2] / a[0]])
+}
+
+/// A single band of the EQ.
+#[derive(Clone)]
+pub struct EqBand {
+    pub kind: BandKind,
+    /// The centre frequency of a peak, or the corner frequency of a shelf or cut, in Hz.
+    pub frequency: Param,
+    /// The boost (or cut, if negative) in decibels.
+    pub gain: Param,
+    pub q: Param,
+    filters: [Biquad; 2],
+    /// The parameters the filters' coefficients were last calculated for.
+    params: Option<(Frequency, f64, f64, f64)>,
+}
+
+impl EqBand {
+    /// Create a band with the given response, frequency in Hz, gain in decibels and Q.
+    pub fn new(kind: BandKind, frequency: Param, gain: Param, q: Param) -> Self {
+        // The filters pass everything through until the first frame sets their coefficients.
+        let filter = Biquad::from_coefficients([1.0, 0.0, 0.0], [0.0, 0.0]);
+        Self {
+            kind,
+            frequency,
+            gain,
+            q,
+            filters: [filter.clone(), filter],
+            params: None,
+        }
+    }
+
+    /// The normalised coefficients for the band's current parameters.
+    fn coefficients(&self, sample_rate: f64) -> ([f64; 3], [f64; 2]) {
+        coefficients(self.kind, self.frequency.value(), self.gain.value(), self.q.value(), sample_rate)
+    }
+
+    /// The gain of the band at a frequency with its current parameters, in decibels.
+    pub fn magnitude(&self, frequency: Frequency, sample_rate: f64) -> f64 {
+        let (b, a) = self.coefficients(sample_rate);
+        let w = 2.0 * PI * frequency / sample_rate;
+        // Evaluate the numerator and denominator polynomials at e^-jw, as (real, imaginary) pairs.
+        let evaluate = |c: [f64; 3]| {
+            (c[0] + c[1] * w.cos() + c[2] * (2.0 * w).cos(), -c[1] * w.sin() - c[2] * (2.0 * w).sin())
+        };
+        let (b_re, b_im) = evaluate(b);
+        let (a_re, a_im) = evaluate([1.0, a[0], a[1]]);
+        10.0 * f64::log10((b_re * b_re + b_im * b_im) / (a_re * a_re + a_im * a_im))
+    }
+
+    /// Filter a frame, updating the coefficients if the parameters have changed.
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        let params = (self.frequency.value(), self.gain.value(), self.q.value(), sample_rate);
+        if self.params != Some(params) {
+            self.params = Some(params);
+            let (b, a) = coefficients(self.kind, params.0, params.1, params.2, sample_rate);
+            self.filters.iter_mut().for_each(|filter| filter.set_coefficients(b, a));
+        }
+        [self.filters[0].process(input[0]), self.filters[1].process(input[1])]
+    }
+}
+
+/// A parametric EQ, which runs its bands one after the other.
+#[derive(Clone, Default)]
+pub struct ParametricEq {
+    pub bands: Vec<EqBand>,
+}
+
+impl ParametricEq {
+    /// Create an EQ from its bands, which are applied in order.
+    pub fn new(bands: Vec<EqBand>) -> Self {
+        Self { bands }
+    }
+
+    /// The gain of the whole EQ at a frequency with its current parameters, in decibels.
+    pub fn magnitude(&self, frequency: Frequency, sample_rate: f64) -> f64 {
+        self.bands.iter().map(|band| band.magnitude(frequency, sample_rate)).sum()
+    }
+
+    /// The largest gain of the EQ between 20Hz and 20kHz (or the Nyquist frequency, if lower) with
+    /// its current parameters, in decibels. The response is checked every twelfth of an octave.
+    pub fn max_magnitude(&self, sample_rate: f64) -> f64 {
+        let highest = f64::min(20000.0, sample_rate * 0.5);
+        std::iter::successors(Some(20.0), |frequency| Some(frequency * 2.0_f64.powf(1.0 / 12.0)))
+            .take_while(|&frequency| frequency <= highest)
+            .map(|frequency| self.magnitude(frequency, sample_rate))
+            .fold(f64::NEG_INFINITY, f64::max)
+    }
+}
+
+impl Effect for ParametricEq {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        self.bands.iter_mut().fold(input, |frame, band| band.process(frame, sample_rate))
+    }
+
+    fn reset(&mut self) {
+        for band in &mut self.bands {
+            band.filters.iter_mut().for_each(Filter::reset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::functions::sine_wave;
+    use crate::signal::Discrete;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    fn band(kind: BandKind, frequency: Frequency, gain: f64, q: f64) -> EqBand {
+        EqBand::new(kind, frequency.into(), gain.into(), q.into())
+    }
+
+    /// Measure the gain of the EQ at a frequency in decibels, by playing a sine wave through it.
+    fn measure(eq: &mut ParametricEq, frequency: Frequency) -> f64 {
+        eq.reset();
+        let output: Vec<Frame> = (0..48000)
+            .map(|n| eq.process([sine_wave(n as f64 / SAMPLE_RATE, frequency); 2], SAMPLE_RATE))
+            .collect();
+        let settled = &output[24000..];
+        let rms = (settled.iter().map(|frame| frame[0] * frame[0]).sum::<f64>() / settled.len() as f64).sqrt();
+        20.0 * f64::log10(rms * std::f64::consts::SQRT_2)
+    }
+
+    #[test]
+    fn test_peak() {
+        let peak = band(BandKind::Peak, 1000.0, 6.0, 2.0);
+        assert_relative_eq!(peak.magnitude(1000.0, SAMPLE_RATE), 6.0, epsilon = 1e-9);
+        assert!(peak.magnitude(100.0, SAMPLE_RATE).abs() < 0.1);
+        assert!(peak.magnitude(10000.0, SAMPLE_RATE).abs() < 0.1);
+
+        let cut = band(BandKind::Peak, 1000.0, -12.0, 2.0);
+        assert_relative_eq!(cut.magnitude(1000.0, SAMPLE_RATE), -12.0, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_shelves() {
+        // Shelves reach their full gain far past the corner, and half of it at the corner.
+        let low = band(BandKind::LowShelf, 200.0, 6.0, 0.5_f64.sqrt());
+        assert_relative_eq!(low.magnitude(10.0, SAMPLE_RATE), 6.0, epsilon = 0.01);
+        assert_relative_eq!(low.magnitude(200.0, SAMPLE_RATE), 3.0, epsilon = 1e-6);
+        assert!(low.magnitude(10000.0, SAMPLE_RATE).abs() < 0.01);
+
+        let high = band(BandKind::HighShelf, 5000.0, -6.0, 0.5_f64.sqrt());
+        assert_relative_eq!(high.magnitude(20000.0, SAMPLE_RATE), -6.0, epsilon = 0.1);
+        assert_relative_eq!(high.magnitude(5000.0, SAMPLE_RATE), -3.0, epsilon = 1e-6);
+        assert!(high.magnitude(50.0, SAMPLE_RATE).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_cuts() {
+        // A Butterworth cut is 3dB down at its corner, and falls at 12dB an octave beyond it.
+        let q = 0.5_f64.sqrt();
+        let low = band(BandKind::LowCut, 100.0, 0.0, q);
+        assert_relative_eq!(low.magnitude(100.0, SAMPLE_RATE), -3.0103, epsilon = 1e-3);
+        assert_relative_eq!(low.magnitude(10.0, SAMPLE_RATE), -40.0, epsilon = 0.1);
+        assert!(low.magnitude(5000.0, SAMPLE_RATE).abs() < 0.01);
+
+        let high = band(BandKind::HighCut, 1000.0, 0.0, q);
+        assert_relative_eq!(high.magnitude(1000.0, SAMPLE_RATE), -3.0103, epsilon = 1e-3);
+        assert!(high.magnitude(8000.0, SAMPLE_RATE) < -35.0);
+        assert!(high.magnitude(50.0, SAMPLE_RATE).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_measured_response() {
+        // The bands add up, and the filtered audio matches the calculated response.
+        let mut eq = ParametricEq::new(vec![
+            band(BandKind::LowCut, 40.0, 0.0, 0.7),
+            band(BandKind::LowShelf, 150.0, 4.0, 0.7),
+            band(BandKind::Peak, 1000.0, -6.0, 1.0),
+            band(BandKind::HighShelf, 6000.0, 3.0, 0.7),
+        ]);
+        for frequency in [30.0, 100.0, 1000.0, 2500.0, 12000.0] {
+            let expected: f64 = eq.bands.iter().map(|band| band.magnitude(frequency, SAMPLE_RATE)).sum();
+            assert_relative_eq!(eq.magnitude(frequency, SAMPLE_RATE), expected);
+            assert_relative_eq!(measure(&mut eq, frequency), expected, epsilon = 0.05);
+        }
+    }
+
+    #[test]
+    fn test_modulation() {
+        // Parameters can follow signals, and the filters update when they change.
+        let mut gain = Discrete::new();
+        let mut eq = ParametricEq::new(vec![EqBand::new(BandKind::Peak, 1000.0.into(), gain.hold().into(), 1.0.into())]);
+        gain.push(6.0);
+        assert_relative_eq!(eq.magnitude(1000.0, SAMPLE_RATE), 6.0, epsilon = 1e-9);
+        assert_relative_eq!(measure(&mut eq, 1000.0), 6.0, epsilon = 0.05);
+        gain.push(-3.0);
+        assert_relative_eq!(measure(&mut eq, 1000.0), -3.0, epsilon = 0.05);
+    }
+
+    #[test]
+    fn test_max_magnitude() {
+        // The largest boost is found wherever it is, and a flat EQ has none.
+        let eq = ParametricEq::new(vec![band(BandKind::LowShelf, 100.0, 3.0, 0.7), band(BandKind::Peak, 1000.0, 6.0, 2.0)]);
+        assert_relative_eq!(eq.max_magnitude(SAMPLE_RATE), 6.0, epsilon = 0.1);
+        assert_relative_eq!(ParametricEq::new(vec![]).max_magnitude(SAMPLE_RATE), 0.0);
+    }
+}
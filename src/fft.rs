@@ -0,0 +1,146 @@
+//! A simple radix-2 fast Fourier transform, used for fast convolution.
+
+use std::f64::consts::PI;
+use std::ops::{Add, Mul, Sub};
+
+/// A complex number.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Self {
+        Self { re, im }
+    }
+
+    /// A complex number with the given magnitude of 1 and the given angle in radians.
+    pub fn from_angle(angle: f64) -> Self {
+        Self::new(angle.cos(), angle.sin())
+    }
+
+    /// The magnitude of the number.
+    pub fn abs(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self::new(self.re * other.re - self.im * other.im, self.re * other.im + self.im * other.re)
+    }
+}
+
+/// A precomputed in-place FFT of a fixed, power of two size.
+#[derive(Clone, Debug)]
+pub struct Fft {
+    twiddles: Vec<Complex>,
+    bit_reverse: Vec<usize>,
+}
+
+impl Fft {
+    /// Create an FFT of the given size, which must be a power of two.
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two(), "FFT size must be a power of two");
+        let bits = size.trailing_zeros();
+        Self {
+            twiddles: (0..size / 2).map(|i| Complex::from_angle(-2.0 * PI * i as f64 / size as f64)).collect(),
+            bit_reverse: (0..size).map(|i| i.reverse_bits().checked_shr(usize::BITS - bits).unwrap_or(0)).collect(),
+        }
+    }
+
+    /// The number of points in the transform.
+    pub fn size(&self) -> usize {
+        self.bit_reverse.len()
+    }
+
+    /// Transform from the time domain to the frequency domain in place.
+    pub fn forward(&self, data: &mut [Complex]) {
+        self.transform(data, false);
+    }
+
+    /// Transform from the frequency domain back to the time domain in place, including the 1/N
+    /// scaling so that a forward and inverse transform is the identity.
+    pub fn inverse(&self, data: &mut [Complex]) {
+        self.transform(data, true);
+        let scale = 1.0 / self.size() as f64;
+        data.iter_mut().for_each(|value| *value = Complex::new(value.re * scale, value.im * scale));
+    }
+
+    fn transform(&self, data: &mut [Complex], inverse: bool) {
+        let size = self.size();
+        assert_eq!(data.len(), size, "FFT input has the wrong size");
+
+        for (i, &j) in self.bit_reverse.iter().enumerate() {
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+
+        // Iterative Cooley-Tukey butterflies.
+        let mut length = 2;
+        while length <= size {
+            let stride = size / length;
+            for start in (0..size).step_by(length) {
+                for k in 0..length / 2 {
+                    let twiddle = self.twiddles[k * stride];
+                    let twiddle = if inverse { Complex::new(twiddle.re, -twiddle.im) } else { twiddle };
+                    let a = data[start + k];
+                    let b = data[start + k + length / 2] * twiddle;
+                    data[start + k] = a + b;
+                    data[start + k + length / 2] = a - b;
+                }
+            }
+            length *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_fft() {
+        let input: Vec<Complex> = (0..16).map(|i| Complex::new((i as f64 * 0.7).sin(), (i as f64 * 0.3).cos())).collect();
+        let fft = Fft::new(16);
+        let mut output = input.clone();
+        fft.forward(&mut output);
+
+        // Compare against a naive DFT.
+        for (k, value) in output.iter().enumerate() {
+            let expected = input.iter().enumerate().fold(Complex::default(), |sum, (n, &x)| {
+                sum + x * Complex::from_angle(-2.0 * PI * (k * n) as f64 / 16.0)
+            });
+            assert_relative_eq!(value.re, expected.re, epsilon = 1e-9);
+            assert_relative_eq!(value.im, expected.im, epsilon = 1e-9);
+        }
+
+        // The inverse should get back the input.
+        fft.inverse(&mut output);
+        for (value, expected) in output.iter().zip(&input) {
+            assert_relative_eq!(value.re, expected.re, epsilon = 1e-9);
+            assert_relative_eq!(value.im, expected.im, epsilon = 1e-9);
+        }
+    }
+}
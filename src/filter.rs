@@ -0,0 +1,381 @@
+//! Resonant filters, with cutoff and resonance that can be modulated every sample.
+
+use std::f64::consts::PI;
+
+use crate::clock::Clock;
+use crate::signal::{Continuous, snapshot3};
+use crate::types::{Frequency, Sample, Time};
+
+/// The maximum resonance, which keeps the filters just below self-oscillation.
+const MAX_RESONANCE: f64 = 0.99;
+
+/// The response of a filter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+/// A filter which processes one sample at a time, and can have its cutoff and resonance changed
+/// between samples.
+pub trait Filter {
+    /// Set the cutoff frequency (in Hz) and resonance (0 to 1) of the filter, at the given sample
+    /// rate. The cutoff is clamped to just below the Nyquist frequency.
+    fn set_params(&mut self, cutoff: Frequency, resonance: f64, sample_rate: f64);
+
+    /// Filter a single sample.
+    fn process(&mut self, input: Sample) -> Sample;
+
+    /// Clear the filter's internal state.
+    fn reset(&mut self);
+}
+
+/// Convert a resonance in the range 0-1 to a Q factor, so that 0 has no resonant peak (Q = 0.5)
+/// and values approaching 1 approach self-oscillation. A resonance of about 0.29 gives a Q of
+/// 1/sqrt(2), which is a Butterworth response.
+pub fn resonance_to_q(resonance: f64) -> f64 {
+    0.5 / (1.0 - resonance.clamp(0.0, MAX_RESONANCE))
+}
+
+/// Clamp a cutoff frequency to a range the filters can handle at the given sample rate.
+fn clamp_cutoff(cutoff: Frequency, sample_rate: f64) -> Frequency {
+    cutoff.clamp(1.0, sample_rate * 0.49)
+}
+
+/// A biquad filter using the RBJ audio EQ cookbook coefficients, in transposed direct form II.
+///
+/// Biquads are cheap but can misbehave under very fast modulation of high resonance settings, for
+/// which the state variable and ladder filters are better suited.
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    mode: FilterMode,
+    params: Option<(Frequency, f64, f64)>,
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    /// Create a new biquad filter with the given response.
+    pub fn new(mode: FilterMode) -> Self {
+        Self {
+            mode,
+            params: None,
+            b: [1.0, 0.0, 0.0],
+            a: [0.0, 0.0],
+            z: [0.0, 0.0],
+        }
+    }
+
+    /// Create a biquad filter with the given normalised coefficients, where `a0` is assumed to be
+    /// 1. Calling `set_params` replaces them with a low-pass response.
+    pub fn from_coefficients(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            ..Self::new(FilterMode::LowPass)
+        }
+    }
+
+    /// Set the normalised coefficients of the filter directly, where `a0` is assumed to be 1.
+    pub fn set_coefficients(&mut self, b: [f64; 3], a: [f64; 2]) {
+        self.b = b;
+        self.a = a;
+        self.params = None;
+    }
+}
+
+impl Filter for Biquad {
+    fn set_params(&mut self, cutoff: Frequency, resonance: f64, sample_rate: f64) {
+        // Only recalculate coefficients when something has changed.
+        if self.params == Some((cutoff, resonance, sample_rate)) {
+            return;
+        }
+        self.params = Some((cutoff, resonance, sample_rate));
+
+        let w0 = 2.0 * PI * clamp_cutoff(cutoff, sample_rate) / sample_rate;
+        let (sin_w0, cos_w0) = w0.sin_cos();
+        let alpha = sin_w0 / (2.0 * resonance_to_q(resonance));
+
+        let b = match self.mode {
+            FilterMode::LowPass => [(1.0 - cos_w0) / 2.0, 1.0 - cos_w0, (1.0 - cos_w0) / 2.0],
+            FilterMode::HighPass => [(1.0 + cos_w0) / 2.0, -(1.0 + cos_w0), (1.0 + cos_w0) / 2.0],
+            FilterMode::BandPass => [alpha, 0.0, -alpha],
+            FilterMode::Notch => [1.0, -2.0 * cos_w0, 1.0],
+        };
+        let a0 = 1.0 + alpha;
+
+        self.b = [b[0] / a0, b[1] / a0, b[2] / a0];
+        self.a = [-2.0 * cos_w0 / a0, (1.0 - alpha) / a0];
+    }
+
+    fn process(&mut self, input: Sample) -> Sample {
+        let output = self.b[0] * input + self.z[0];
+        self.z[0] = self.b[1] * input - self.a[0] * output + self.z[1];
+        self.z[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+
+    fn reset(&mut self) {
+        self.z = [0.0, 0.0];
+    }
+}
+
+/// A topology-preserving-transform state variable filter, which stays stable under fast
+/// modulation of both cutoff and resonance.
+#[derive(Clone, Debug)]
+pub struct StateVariable {
+    mode: FilterMode,
+    g: f64,
+    k: f64,
+    ic1eq: f64,
+    ic2eq: f64,
+}
+
+impl StateVariable {
+    /// Create a new state variable filter with the given response.
+    pub fn new(mode: FilterMode) -> Self {
+        Self {
+            mode,
+            g: 0.0,
+            k: 2.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+        }
+    }
+}
+
+impl Filter for StateVariable {
+    fn set_params(&mut self, cutoff: Frequency, resonance: f64, sample_rate: f64) {
+        self.g = f64::tan(PI * clamp_cutoff(cutoff, sample_rate) / sample_rate);
+        self.k = 1.0 / resonance_to_q(resonance);
+    }
+
+    fn process(&mut self, input: Sample) -> Sample {
+        // https://cytomic.com/files/dsp/SvfLinearTrapOptimised2.pdf
+        let a1 = 1.0 / (1.0 + self.g * (self.g + self.k));
+        let a2 = self.g * a1;
+        let a3 = self.g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        match self.mode {
+            FilterMode::LowPass => v2,
+            FilterMode::HighPass => input - self.k * v1 - v2,
+            FilterMode::BandPass => self.k * v1,
+            FilterMode::Notch => input - self.k * v1,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+}
+
+/// A Moog-style four pole ladder filter, implemented as a zero-delay feedback loop of one pole
+/// filters with a saturating input stage. The other responses are made by mixing the outputs of
+/// the individual poles.
+#[derive(Clone, Debug)]
+pub struct Ladder {
+    mode: FilterMode,
+    g: f64,
+    k: f64,
+    state: [f64; 4],
+}
+
+impl Ladder {
+    /// Create a new ladder filter with the given response.
+    pub fn new(mode: FilterMode) -> Self {
+        Self {
+            mode,
+            g: 0.0,
+            k: 0.0,
+            state: [0.0; 4],
+        }
+    }
+}
+
+impl Filter for Ladder {
+    fn set_params(&mut self, cutoff: Frequency, resonance: f64, sample_rate: f64) {
+        self.g = f64::tan(PI * clamp_cutoff(cutoff, sample_rate) / sample_rate);
+        // The loop self-oscillates at a feedback of 4.
+        self.k = 4.0 * resonance.clamp(0.0, MAX_RESONANCE);
+    }
+
+    fn process(&mut self, input: Sample) -> Sample {
+        let big_g = self.g / (1.0 + self.g);
+
+        // Solve the feedback loop: the output of the last pole is G^4 * u plus the contribution
+        // of each pole's state.
+        let s = self.state.iter().rev().fold((0.0, 1.0), |(sum, gain), state| {
+            (sum + gain * state / (1.0 + self.g), gain * big_g)
+        }).0;
+        let g4 = big_g.powi(4);
+        let u = f64::tanh((input - self.k * s) / (1.0 + self.k * g4));
+
+        // Run the poles.
+        let mut outputs = [0.0; 4];
+        let mut x = u;
+        for (state, output) in self.state.iter_mut().zip(outputs.iter_mut()) {
+            let v = (x - *state) * big_g;
+            let y = v + *state;
+            *state = y + v;
+            *output = y;
+            x = y;
+        }
+
+        let [y1, y2, y3, y4] = outputs;
+        match self.mode {
+            FilterMode::LowPass => y4,
+            FilterMode::HighPass => u - 4.0 * y1 + 6.0 * y2 - 4.0 * y3 + y4,
+            FilterMode::BandPass => 4.0 * (y2 - 2.0 * y3 + y4),
+            FilterMode::Notch => u - 2.0 * y1 + 2.0 * y2,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = [0.0; 4];
+    }
+}
+
+/// Create a filter node which filters the input signal with the given filter, stepped every time
+/// the time signal changes. The cutoff (in Hz) and resonance (0 to 1) can be modulated by other
+/// signals, or held constant with `Continuous::constant`.
+pub fn filter<F>(time: &mut Continuous<Time>,
+                 input: &Continuous<Sample>,
+                 cutoff: &Continuous<Frequency>,
+                 resonance: &Continuous<f64>,
+                 mut filter: F)
+    -> Continuous<Sample>
+where
+    F: Filter + Send + Sync + 'static,
+{
+    let mut clock = Clock::new();
+    snapshot3(time, input, cutoff, resonance, move |time, input, cutoff, resonance| {
+        clock.tick(time);
+        match clock.sample_rate() {
+            Some(sample_rate) => {
+                filter.set_params(cutoff, resonance, sample_rate);
+                filter.process(input)
+            },
+            None => 0.0,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::functions::sine_wave;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+    const BUTTERWORTH: f64 = 1.0 - std::f64::consts::FRAC_1_SQRT_2;
+
+    /// Measure the steady state gain of a filter at the given frequency by filtering a sine wave.
+    fn gain<F: Filter>(filter: &mut F, frequency: Frequency) -> f64 {
+        filter.reset();
+        let mut peak: f64 = 0.0;
+        for i in 0..(SAMPLE_RATE as usize / 2) {
+            let output = filter.process(sine_wave(i as f64 / SAMPLE_RATE, frequency));
+            // Skip the transient response at the start.
+            if i > SAMPLE_RATE as usize / 4 {
+                peak = peak.max(output.abs());
+            }
+        }
+        peak
+    }
+
+    fn check_response<F: Filter>(mut filter: F, mode: FilterMode) {
+        filter.set_params(1000.0, BUTTERWORTH, SAMPLE_RATE);
+        let (low, cutoff, high) = (gain(&mut filter, 50.0), gain(&mut filter, 1000.0), gain(&mut filter, 15000.0));
+        match mode {
+            FilterMode::LowPass => {
+                assert_relative_eq!(low, 1.0, epsilon = 0.01);
+                assert_relative_eq!(cutoff, std::f64::consts::FRAC_1_SQRT_2, epsilon = 0.02);
+                assert!(high < 0.01);
+            },
+            FilterMode::HighPass => {
+                assert!(low < 0.01);
+                assert_relative_eq!(cutoff, std::f64::consts::FRAC_1_SQRT_2, epsilon = 0.02);
+                assert_relative_eq!(high, 1.0, epsilon = 0.01);
+            },
+            FilterMode::BandPass => {
+                assert!(low < 0.1);
+                assert_relative_eq!(cutoff, 1.0, epsilon = 0.01);
+                assert!(high < 0.1);
+            },
+            FilterMode::Notch => {
+                assert_relative_eq!(low, 1.0, epsilon = 0.01);
+                assert!(cutoff < 0.01);
+                assert_relative_eq!(high, 1.0, epsilon = 0.01);
+            },
+        }
+    }
+
+    #[test]
+    fn test_biquad_response() {
+        for mode in [FilterMode::LowPass, FilterMode::HighPass, FilterMode::BandPass, FilterMode::Notch] {
+            check_response(Biquad::new(mode), mode);
+        }
+    }
+
+    #[test]
+    fn test_state_variable_response() {
+        for mode in [FilterMode::LowPass, FilterMode::HighPass, FilterMode::BandPass, FilterMode::Notch] {
+            check_response(StateVariable::new(mode), mode);
+        }
+    }
+
+    #[test]
+    fn test_ladder_response() {
+        // Each pole is 3db down at the cutoff, so the four pole output should be 12db down. Keep
+        // the input small so that the saturation doesn't affect the result.
+        let mut ladder = Ladder::new(FilterMode::LowPass);
+        ladder.set_params(1000.0, 0.0, SAMPLE_RATE);
+        let scaled_gain = |ladder: &mut Ladder, frequency| {
+            ladder.reset();
+            (0..24000).map(|i| ladder.process(0.01 * sine_wave(i as f64 / SAMPLE_RATE, frequency)))
+                .skip(12000)
+                .fold(0.0, |peak: f64, output| peak.max(output.abs())) / 0.01
+        };
+        assert_relative_eq!(scaled_gain(&mut ladder, 50.0), 1.0, epsilon = 0.01);
+        assert_relative_eq!(scaled_gain(&mut ladder, 1000.0), 0.25, epsilon = 0.01);
+
+        // Two octaves above the cutoff should be roughly 48db down.
+        assert!(scaled_gain(&mut ladder, 4000.0) < 0.005);
+
+        // Resonance should add a peak at the cutoff.
+        ladder.set_params(1000.0, 0.9, SAMPLE_RATE);
+        assert!(scaled_gain(&mut ladder, 1000.0) > 1.0);
+
+        // The notch should remove the cutoff frequency.
+        let mut ladder = Ladder::new(FilterMode::Notch);
+        ladder.set_params(1000.0, 0.0, SAMPLE_RATE);
+        assert!(scaled_gain(&mut ladder, 1000.0) < 0.01);
+    }
+
+    #[test]
+    fn test_fast_modulation() {
+        // Sweep the cutoff wildly every sample at maximum resonance and check nothing blows up.
+        let mut filters: Vec<Box<dyn Filter>> = vec![
+            Box::new(StateVariable::new(FilterMode::LowPass)),
+            Box::new(Ladder::new(FilterMode::LowPass)),
+        ];
+        for filter in filters.iter_mut() {
+            for i in 0..48000 {
+                let cutoff = if i % 2 == 0 { 20.0 } else { 20000.0 };
+                filter.set_params(cutoff, 1.0, SAMPLE_RATE);
+                let output = filter.process(sine_wave(i as f64 / SAMPLE_RATE, 440.0));
+                assert!(output.is_finite() && output.abs() < 100.0);
+            }
+        }
+    }
+}
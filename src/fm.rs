@@ -0,0 +1,311 @@
+//! Frequency modulation synthesis, implemented as phase modulation between sine operators in the
+//! style of classic 4 and 6 operator FM synths.
+
+use std::error::Error;
+
+use crate::clock::Clock;
+use crate::envelope::{Adsr, AdsrParams};
+use crate::functions::sine_wave;
+use crate::signal::{Continuous, snapshot3};
+use crate::types::{Frequency, Sample, Time, Velocity};
+
+/// How an operator's frequency is determined.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OperatorFrequency {
+    /// A multiple of the note frequency.
+    Ratio(f64),
+    /// A fixed frequency in Hz, regardless of the note.
+    Fixed(Frequency),
+}
+
+/// The parameters of a single FM operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OperatorParams {
+    pub frequency: OperatorFrequency,
+    /// Detune in cents, applied on top of the frequency.
+    pub detune: f64,
+    /// The output level of the operator. For modulators this is the modulation index, where 1
+    /// shifts the phase of the modulated operator by up to a whole cycle.
+    pub level: f64,
+    /// How much of the operator's own output is fed back into its phase.
+    pub feedback: f64,
+    pub envelope: AdsrParams,
+}
+
+impl Default for OperatorParams {
+    fn default() -> Self {
+        Self {
+            frequency: OperatorFrequency::Ratio(1.0),
+            detune: 0.0,
+            level: 1.0,
+            feedback: 0.0,
+            envelope: AdsrParams::default(),
+        }
+    }
+}
+
+/// A routing graph between operators, describing which operators modulate each other and which
+/// are heard.
+///
+/// Operators may only be modulated by operators with a higher index, so that the graph has no
+/// cycles and the operators can be evaluated from the last to the first. Loops are only possible
+/// through an operator's own feedback.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Algorithm {
+    modulators: Vec<Vec<usize>>,
+    carriers: Vec<usize>,
+}
+
+impl Algorithm {
+    /// Create a new algorithm where `modulators[i]` lists the operators which modulate operator
+    /// `i`, and `carriers` lists the operators which are mixed into the output.
+    pub fn new(modulators: Vec<Vec<usize>>, carriers: Vec<usize>) -> Result<Self, Box<dyn Error>> {
+        let operator_count = modulators.len();
+        for (operator, sources) in modulators.iter().enumerate() {
+            if sources.iter().any(|&source| source <= operator || source >= operator_count) {
+                return Err(format!("Operator {operator} must be modulated by higher operators only").into());
+            }
+        }
+        if carriers.is_empty() || carriers.iter().any(|&carrier| carrier >= operator_count) {
+            return Err("Algorithm must have at least one valid carrier".into());
+        }
+
+        Ok(Self {
+            modulators,
+            carriers,
+        })
+    }
+
+    /// A stack of operators where each modulates the one before, and only the first is heard.
+    /// There must be at least one operator.
+    pub fn stack(operator_count: usize) -> Result<Self, Box<dyn Error>> {
+        let modulators = (0..operator_count)
+            .map(|operator| if operator + 1 < operator_count { vec![operator + 1] } else { vec![] })
+            .collect();
+        Self::new(modulators, vec![0])
+    }
+
+    /// Operators that are all heard without modulating each other, like an organ. There must be at
+    /// least one operator.
+    pub fn parallel(operator_count: usize) -> Result<Self, Box<dyn Error>> {
+        Self::new(vec![vec![]; operator_count], (0..operator_count).collect())
+    }
+
+    /// One of the eight classic 4 operator algorithms, numbered 1 to 8. Operator 1 is index 0, and
+    /// operator 4 is usually the one with feedback.
+    pub fn four_op(number: usize) -> Option<Self> {
+        let (modulators, carriers): (Vec<Vec<usize>>, Vec<usize>) = match number {
+            // 4 -> 3 -> 2 -> 1
+            1 => (vec![vec![1], vec![2], vec![3], vec![]], vec![0]),
+            // (3 + 4) -> 2 -> 1
+            2 => (vec![vec![1], vec![2, 3], vec![], vec![]], vec![0]),
+            // (4 + (3 -> 2)) -> 1
+            3 => (vec![vec![1, 3], vec![2], vec![], vec![]], vec![0]),
+            // ((4 -> 3) + 2) -> 1
+            4 => (vec![vec![1, 2], vec![], vec![3], vec![]], vec![0]),
+            // 2 -> 1, 4 -> 3
+            5 => (vec![vec![1], vec![], vec![3], vec![]], vec![0, 2]),
+            // 4 -> (1, 2, 3)
+            6 => (vec![vec![3], vec![3], vec![3], vec![]], vec![0, 1, 2]),
+            // 4 -> 3, 1, 2
+            7 => (vec![vec![], vec![], vec![3], vec![]], vec![0, 1, 2]),
+            // 1, 2, 3, 4
+            8 => (vec![vec![]; 4], vec![0, 1, 2, 3]),
+            _ => return None,
+        };
+        Some(Self { modulators, carriers })
+    }
+
+    /// The number of operators the algorithm routes.
+    pub fn operator_count(&self) -> usize {
+        self.modulators.len()
+    }
+}
+
+/// A complete FM patch: the operators and the algorithm that routes them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FmPatch {
+    pub operators: Vec<OperatorParams>,
+    pub algorithm: Algorithm,
+}
+
+impl FmPatch {
+    /// Create a new patch, checking that the algorithm has a carrier and that there's an operator
+    /// for every one in the algorithm.
+    pub fn new(operators: Vec<OperatorParams>, algorithm: Algorithm) -> Result<Self, Box<dyn Error>> {
+        if algorithm.carriers.is_empty() {
+            return Err("Algorithm must have at least one carrier".into());
+        }
+        if operators.len() != algorithm.operator_count() {
+            return Err(format!("Algorithm needs {} operators but {} were given",
+                               algorithm.operator_count(), operators.len()).into());
+        }
+        Ok(Self { operators, algorithm })
+    }
+}
+
+/// The running state of a single operator.
+#[derive(Clone, Debug)]
+struct Operator {
+    params: OperatorParams,
+    envelope: Adsr,
+    phase: f64,
+    /// The last two outputs, which are averaged for feedback to keep it stable.
+    history: [f64; 2],
+}
+
+/// A single FM voice, which runs all of the operators in a patch for one note.
+#[derive(Clone, Debug)]
+pub struct FmVoice {
+    operators: Vec<Operator>,
+    algorithm: Algorithm,
+    outputs: Vec<f64>,
+}
+
+impl FmVoice {
+    /// Create a new voice playing the given patch.
+    pub fn new(patch: FmPatch) -> Self {
+        Self {
+            outputs: vec![0.0; patch.operators.len()],
+            operators: patch.operators.into_iter().map(|params| Operator {
+                params,
+                envelope: Adsr::new(params.envelope),
+                phase: 0.0,
+                history: [0.0; 2],
+            }).collect(),
+            algorithm: patch.algorithm,
+        }
+    }
+
+    /// Step the voice forward by `time_step` seconds, playing the given note frequency, and return
+    /// the mixed output of the carriers.
+    pub fn step(&mut self, frequency: Frequency, gate: bool, velocity: Velocity, time_step: Time) -> Sample {
+        // Evaluate the operators from the last to the first, so that modulators are always
+        // evaluated before the operators they modulate.
+        for index in (0..self.operators.len()).rev() {
+            let modulation: f64 = self.algorithm.modulators[index].iter()
+                .map(|&source| self.outputs[source])
+                .sum();
+
+            let operator = &mut self.operators[index];
+            let feedback = operator.params.feedback * (operator.history[0] + operator.history[1]) * 0.5;
+            let amplitude = operator.envelope.step(gate, velocity, time_step) * operator.params.level;
+            let output = amplitude * sine_wave(operator.phase + modulation + feedback, 1.0);
+
+            operator.history = [output, operator.history[0]];
+            self.outputs[index] = output;
+
+            // Advance the operator's phase.
+            let operator_frequency = match operator.params.frequency {
+                OperatorFrequency::Ratio(ratio) => frequency * ratio,
+                OperatorFrequency::Fixed(fixed) => fixed,
+            } * f64::powf(2.0, operator.params.detune / 1200.0);
+            operator.phase = (operator.phase + operator_frequency * time_step).fract();
+        }
+
+        let carriers = &self.algorithm.carriers;
+        carriers.iter().map(|&carrier| self.outputs[carrier]).sum::<f64>() / carriers.len() as f64
+    }
+}
+
+/// Create an FM voice node playing the given patch, which is stepped every time the time signal
+/// changes. This can be used in place of an oscillator and amplitude envelope for a voice, as the
+/// operators have their own envelopes.
+pub fn fm_voice(time: &mut Continuous<Time>,
+                frequency: &Continuous<Frequency>,
+                gate: &Continuous<bool>,
+                velocity: &Continuous<Velocity>,
+                patch: FmPatch)
+    -> Continuous<Sample>
+{
+    let mut clock = Clock::new();
+    let mut voice = FmVoice::new(patch);
+    snapshot3(time, frequency, gate, velocity, move |time, frequency, gate, velocity| {
+        let time_step = clock.tick(time);
+        voice.step(frequency, gate, velocity, time_step)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const TIME_STEP: f64 = 1.0 / 48000.0;
+
+    /// Operator parameters with an envelope that's instantly at full level.
+    fn operator(frequency: OperatorFrequency, level: f64) -> OperatorParams {
+        OperatorParams {
+            frequency,
+            level,
+            envelope: AdsrParams { attack: 0.0, decay: 0.0, sustain: 1.0, ..AdsrParams::default() },
+            ..OperatorParams::default()
+        }
+    }
+
+    #[test]
+    fn test_algorithm_validation() {
+        assert!(Algorithm::new(vec![vec![1], vec![]], vec![0]).is_ok());
+        assert!(Algorithm::new(vec![vec![0], vec![]], vec![0]).is_err());
+        assert!(Algorithm::new(vec![vec![], vec![0]], vec![0]).is_err());
+        assert!(Algorithm::new(vec![vec![], vec![]], vec![]).is_err());
+        assert!(Algorithm::new(vec![vec![], vec![]], vec![2]).is_err());
+
+        // The built in algorithms should all be valid.
+        for number in 1..=8 {
+            let algorithm = Algorithm::four_op(number).unwrap();
+            assert!(Algorithm::new(algorithm.modulators.clone(), algorithm.carriers.clone()).is_ok());
+        }
+        assert_eq!(Algorithm::four_op(9), None);
+
+        // Stacks and parallel algorithms need at least one operator.
+        assert_eq!(Algorithm::stack(3).unwrap().operator_count(), 3);
+        assert_eq!(Algorithm::parallel(2).unwrap().carriers, vec![0, 1]);
+        assert!(Algorithm::stack(0).is_err());
+        assert!(Algorithm::parallel(0).is_err());
+
+        // A patch can't be made from an algorithm with no carriers.
+        let silent = Algorithm { modulators: vec![], carriers: vec![] };
+        assert!(FmPatch::new(vec![], silent).is_err());
+    }
+
+    #[test]
+    fn test_unmodulated_carrier() {
+        // A single unmodulated carrier should just be a sine wave.
+        let patch = FmPatch::new(vec![operator(OperatorFrequency::Ratio(2.0), 1.0)], Algorithm::stack(1).unwrap()).unwrap();
+        let mut voice = FmVoice::new(patch);
+        for i in 0..1000 {
+            let output = voice.step(220.0, true, 1.0, TIME_STEP);
+            assert_relative_eq!(output, sine_wave(i as f64 * TIME_STEP, 440.0), epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_modulation() {
+        // A modulator with a level of 0 shouldn't change the carrier, but one with a level should.
+        let run = |level, feedback| {
+            let modulator = OperatorParams { feedback, ..operator(OperatorFrequency::Fixed(100.0), level) };
+            let patch = FmPatch::new(vec![operator(OperatorFrequency::Ratio(1.0), 1.0), modulator],
+                                     Algorithm::stack(2).unwrap()).unwrap();
+            let mut voice = FmVoice::new(patch);
+            (0..1000).map(|_| voice.step(440.0, true, 1.0, TIME_STEP)).collect::<Vec<f64>>()
+        };
+
+        let unmodulated = run(0.0, 0.0);
+        for (i, output) in unmodulated.iter().enumerate() {
+            assert_relative_eq!(*output, sine_wave(i as f64 * TIME_STEP, 440.0), epsilon = 1e-9);
+        }
+
+        let modulated = run(1.0, 0.0);
+        assert_ne!(modulated, unmodulated);
+        assert!(modulated.iter().all(|output| output.abs() <= 1.0));
+
+        let feedback = run(1.0, 0.5);
+        assert_ne!(feedback, modulated);
+    }
+
+    #[test]
+    fn test_patch_validation() {
+        assert!(FmPatch::new(vec![OperatorParams::default()], Algorithm::four_op(1).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! Formant filters, which shape a sound with the resonances of the vocal tract so that it sounds
+//! like a vowel.
+//!
+//! Each vowel is made of five formants, which are played by band-pass filters in parallel. The vowel
+//! input morphs smoothly through a, e, i, o and u as it goes from 0 to 4, so it can be swept by an
+//! LFO or a controller to make a sound talk.
+
+use crate::clock::Clock;
+use crate::filter::{Filter, FilterMode, StateVariable};
+use crate::signal::{Continuous, snapshot2};
+use crate::types::{Frequency, Sample, Time};
+
+/// The number of formants in each vowel.
+pub const FORMANT_COUNT: usize = 5;
+
+/// A single resonance of the vocal tract.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Formant {
+    pub frequency: Frequency,
+    /// The level of the formant, in decibels.
+    pub gain: f64,
+    pub bandwidth: Frequency,
+}
+
+impl Formant {
+    pub const fn new(frequency: Frequency, gain: f64, bandwidth: Frequency) -> Self {
+        Self { frequency, gain, bandwidth }
+    }
+
+    /// Interpolate between two formants, where `amount` goes from 0 (this one) to 1 (the other).
+    fn lerp(&self, other: &Self, amount: f64) -> Self {
+        let lerp = |a: f64, b: f64| a + (b - a) * amount;
+        Self::new(lerp(self.frequency, other.frequency), lerp(self.gain, other.gain), lerp(self.bandwidth, other.bandwidth))
+    }
+}
+
+/// The formants of a tenor singing a, e, i, o and u, from the Csound manual's formant table.
+pub const VOWELS: [[Formant; FORMANT_COUNT]; 5] = [
+    [
+        Formant::new(650.0, 0.0, 80.0),
+        Formant::new(1080.0, -6.0, 90.0),
+        Formant::new(2650.0, -7.0, 120.0),
+        Formant::new(2900.0, -8.0, 130.0),
+        Formant::new(3250.0, -22.0, 140.0),
+    ],
+    [
+        Formant::new(400.0, 0.0, 70.0),
+        Formant::new(1700.0, -14.0, 80.0),
+        Formant::new(2600.0, -12.0, 100.0),
+        Formant::new(3200.0, -14.0, 120.0),
+        Formant::new(3580.0, -20.0, 120.0),
+    ],
+    [
+        Formant::new(290.0, 0.0, 40.0),
+        Formant::new(1870.0, -15.0, 90.0),
+        Formant::new(2800.0, -18.0, 100.0),
+        Formant::new(3250.0, -20.0, 120.0),
+        Formant::new(3540.0, -30.0, 120.0),
+    ],
+    [
+        Formant::new(400.0, 0.0, 40.0),
+        Formant::new(800.0, -10.0, 80.0),
+        Formant::new(2600.0, -12.0, 100.0),
+        Formant::new(2800.0, -12.0, 120.0),
+        Formant::new(3000.0, -26.0, 120.0),
+    ],
+    [
+        Formant::new(350.0, 0.0, 40.0),
+        Formant::new(600.0, -20.0, 60.0),
+        Formant::new(2700.0, -17.0, 100.0),
+        Formant::new(2900.0, -14.0, 120.0),
+        Formant::new(3300.0, -26.0, 120.0),
+    ],
+];
+
+/// The formants of a vowel, from 0 (a) through e, i and o to 4 (u), interpolating between
+/// neighbouring vowels. Values outside this range are clamped.
+pub fn vowel_formants(vowel: f64) -> [Formant; FORMANT_COUNT] {
+    let vowel = vowel.clamp(0.0, (VOWELS.len() - 1) as f64);
+    let index = (vowel.floor() as usize).min(VOWELS.len() - 2);
+    let amount = vowel - index as f64;
+    std::array::from_fn(|formant| VOWELS[index][formant].lerp(&VOWELS[index + 1][formant], amount))
+}
+
+/// A formant filter made from a band-pass state variable filter for each formant, which stay stable
+/// as the vowel is swept.
+#[derive(Clone, Debug)]
+pub struct FormantFilter {
+    filters: [StateVariable; FORMANT_COUNT],
+}
+
+impl FormantFilter {
+    pub fn new() -> Self {
+        Self {
+            filters: std::array::from_fn(|_| StateVariable::new(FilterMode::BandPass)),
+        }
+    }
+
+    /// Filter a single sample, shaped like the given vowel.
+    pub fn process(&mut self, input: Sample, vowel: f64, sample_rate: f64) -> Sample {
+        self.filters.iter_mut().zip(vowel_formants(vowel)).map(|(filter, formant)| {
+            // The resonance is the inverse of `resonance_to_q`, for a Q of the frequency over the
+            // bandwidth.
+            let q = formant.frequency / formant.bandwidth;
+            filter.set_params(formant.frequency, 1.0 - 0.5 / q, sample_rate);
+            filter.process(input) * f64::powf(10.0, formant.gain / 20.0)
+        }).sum()
+    }
+
+    /// Clear the filters' internal state.
+    pub fn reset(&mut self) {
+        self.filters.iter_mut().for_each(Filter::reset);
+    }
+}
+
+impl Default for FormantFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create a formant filter node which filters the input signal to sound like the vowel, from 0 (a)
+/// to 4 (u).
+pub fn formant(time: &mut Continuous<Time>, input: &Continuous<Sample>, vowel: &Continuous<f64>) -> Continuous<Sample> {
+    let mut clock = Clock::new();
+    let mut filter = FormantFilter::new();
+    snapshot2(time, input, vowel, move |time, input, vowel| {
+        clock.tick(time);
+        match clock.sample_rate() {
+            Some(sample_rate) => filter.process(input, vowel, sample_rate),
+            None => 0.0,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::functions::sine_wave;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    /// The level of a sine wave after the filter has settled, shaped like the vowel.
+    fn response(frequency: Frequency, vowel: f64) -> f64 {
+        let mut filter = FormantFilter::new();
+        let output: Vec<Sample> = (0..9600)
+            .map(|n| filter.process(sine_wave(n as f64 / SAMPLE_RATE, frequency), vowel, SAMPLE_RATE))
+            .collect();
+        let settled = &output[4800..];
+        (2.0 * settled.iter().map(|sample| sample * sample).sum::<f64>() / settled.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn test_morph() {
+        assert_eq!(vowel_formants(0.0), VOWELS[0]);
+        assert_eq!(vowel_formants(4.0), VOWELS[4]);
+        assert_eq!(vowel_formants(-1.0), VOWELS[0]);
+        assert_eq!(vowel_formants(5.0), VOWELS[4]);
+
+        // Half way between a and e.
+        let formants = vowel_formants(0.5);
+        assert_relative_eq!(formants[0].frequency, 525.0);
+        assert_relative_eq!(formants[1].gain, -10.0);
+        assert_relative_eq!(formants[4].bandwidth, 130.0);
+    }
+
+    #[test]
+    fn test_response() {
+        // The first formant of a should pass at about unity gain, but be cut when the filter is
+        // morphed to i, whose first two formants are either side of it.
+        let a = response(650.0, 0.0);
+        let i = response(650.0, 2.0);
+        assert_relative_eq!(a, 1.0, max_relative = 0.1);
+        assert!(i < a * 0.2, "{i} vs {a}");
+
+        // Between the formants, the level should be well down.
+        assert!(response(1700.0, 0.0) < 0.2);
+    }
+}
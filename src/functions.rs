@@ -34,6 +34,37 @@ pub fn saw_wave(time: f64, frequency: f64) -> f64 {
     ft - f64::floor(ft)
 }
 
+/// A single partial of an additive wave, as a multiple of the fundamental frequency and an
+/// amplitude.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Partial {
+    pub ratio: f64,
+    pub amplitude: f64,
+}
+
+impl Partial {
+    /// Create a new partial at the given multiple of the fundamental frequency.
+    pub fn new(ratio: f64, amplitude: f64) -> Self {
+        Self { ratio, amplitude }
+    }
+
+    /// Create the first `count` harmonics of a wave, with amplitudes given by a function of the
+    /// harmonic number (starting from 1).
+    pub fn harmonics<F: Fn(usize) -> f64>(count: usize, amplitude: F) -> Vec<Self> {
+        (1..=count).map(|n| Self::new(n as f64, amplitude(n))).collect()
+    }
+}
+
+/// Generate the sum of a set of sine wave partials of a given fundamental frequency at a given
+/// time. Partials at or above `max_frequency` (usually the Nyquist frequency) are skipped so they
+/// don't alias.
+pub fn additive_wave(time: f64, frequency: f64, partials: &[Partial], max_frequency: f64) -> f64 {
+    partials.iter()
+        .filter(|partial| partial.amplitude != 0.0 && (frequency * partial.ratio).abs() < max_frequency)
+        .map(|partial| partial.amplitude * sine_wave(time, frequency * partial.ratio))
+        .sum()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -113,4 +144,23 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_additive_wave() {
+        // A single partial should be a sine wave at the partial's frequency.
+        let partials = [Partial::new(2.0, 0.5)];
+        for i in 0..100 {
+            let time = f64::from(i) * 0.001;
+            assert_relative_eq!(additive_wave(time, 100.0, &partials, 24000.0),
+                                0.5 * sine_wave(time, 200.0), epsilon = 1e-12);
+        }
+
+        // Partials above the maximum frequency should be skipped.
+        let partials = Partial::harmonics(4, |n| 1.0 / n as f64);
+        for i in 0..100 {
+            let time = f64::from(i) * 0.001;
+            let expected = sine_wave(time, 100.0) + 0.5 * sine_wave(time, 200.0);
+            assert_relative_eq!(additive_wave(time, 100.0, &partials, 250.0), expected, epsilon = 1e-12);
+        }
+    }
 }
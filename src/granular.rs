@@ -0,0 +1,374 @@
+//! Granular synthesis, which builds a sound from many short, overlapping grains of a source sound,
+//! each read from a slightly different position.
+//!
+//! The grains can come from a loaded sample, or a live buffer that records the input so that
+//! another sound can be turned into a texture. The random scattering of grains is seeded, so the
+//! output is always the same for the same seed and input.
+
+use std::f64::consts::{FRAC_PI_4, PI, SQRT_2};
+use std::sync::Arc;
+
+use crate::clock::Clock;
+use crate::delay_line::DelayLine;
+use crate::effects::{mix_frames, Effect, Param};
+use crate::random::Rng;
+use crate::sampler::interpolate;
+use crate::signal::{Continuous, snapshot1};
+use crate::types::{Frame, Time};
+use crate::wav::Wav;
+
+/// The most grains that can play at once. New grains are skipped while this many are playing.
+const MAX_GRAINS: usize = 128;
+
+/// The length of a live buffer, in seconds.
+const LIVE_BUFFER_LENGTH: Time = 4.0;
+
+/// Where grains are read from.
+#[derive(Clone, Debug)]
+pub enum GrainSource {
+    /// A sample, where the position goes from its start (0) to its end (1).
+    Sample(Arc<Wav>),
+    /// A recording of the last few seconds of input, where the position goes from now (0) back to
+    /// the start of the buffer (1).
+    Live([DelayLine; 2]),
+}
+
+impl GrainSource {
+    /// Create a live source, whose buffers are sized when the granulator is prepared, or when it's
+    /// first processed if it wasn't.
+    pub fn live() -> Self {
+        GrainSource::Live([DelayLine::default(), DelayLine::default()])
+    }
+
+    /// How many frames of the source pass for each output sample at the original pitch, which
+    /// differs from 1 when a sample was recorded at a different rate.
+    fn rate_ratio(&self, sample_rate: f64) -> f64 {
+        match self {
+            GrainSource::Sample(wav) => wav.sample_rate as f64 / sample_rate,
+            GrainSource::Live(_) => 1.0,
+        }
+    }
+}
+
+/// A single grain.
+#[derive(Clone, Debug)]
+struct Grain {
+    /// Where the grain started in the source, in frames from the start of a sample or back from
+    /// now in a live buffer.
+    start: f64,
+    /// How many frames of the source the grain reads through every sample.
+    rate: f64,
+    age: f64,
+    length: f64,
+    gains: [f64; 2],
+}
+
+impl Grain {
+    /// The Hann window, which fades the grain in and out.
+    fn window(&self) -> f64 {
+        0.5 - 0.5 * f64::cos(2.0 * PI * self.age / self.length)
+    }
+}
+
+/// A granular synthesiser. Grains are started at a regular rate set by the density, and read from
+/// the position, randomly offset by up to the spray in either direction. Each grain is panned
+/// randomly within the stereo spread.
+pub struct Granulator {
+    /// The length of each grain, in seconds.
+    pub size: Param,
+    /// How many grains start every second.
+    pub density: Param,
+    /// Where in the source grains start, from 0 to 1.
+    pub position: Param,
+    /// How far grains can be randomly moved from the position, in seconds.
+    pub spray: Param,
+    /// How fast grains play back the source, where 1 is the original pitch and 2 is an octave up.
+    pub pitch: Param,
+    /// How far grains are randomly panned, from 0 (all in the centre) to 1 (anywhere).
+    pub stereo_spread: Param,
+    /// The balance between the input and the grains when used as an effect.
+    pub mix: Param,
+    source: GrainSource,
+    grains: Vec<Grain>,
+    rng: Rng,
+    /// The number of samples until the next grain starts, counting this one.
+    countdown: f64,
+}
+
+impl Granulator {
+    /// Create a granulator reading from the given source, with the random number generator seeded
+    /// by `seed`. It starts with 100ms grains, 20 grains a second from the start of the source,
+    /// with no spray or stereo spread.
+    pub fn new(source: GrainSource, seed: u64) -> Self {
+        Self {
+            size: 0.1.into(),
+            density: 20.0.into(),
+            position: 0.0.into(),
+            spray: 0.0.into(),
+            pitch: 1.0.into(),
+            stereo_spread: 0.0.into(),
+            mix: 0.5.into(),
+            source,
+            grains: Vec::with_capacity(MAX_GRAINS),
+            rng: Rng::new(seed),
+            countdown: 1.0,
+        }
+    }
+
+    /// Size a live source's buffers for the sample rate, so that recording doesn't allocate. This
+    /// does nothing for a sample, or if the buffers are already the right size.
+    pub fn prepare(&mut self, sample_rate: f64) {
+        if let GrainSource::Live(buffers) = &mut self.source {
+            let length = (LIVE_BUFFER_LENGTH * sample_rate) as usize;
+            buffers.iter_mut().for_each(|buffer| buffer.resize(length));
+        }
+    }
+
+    /// Record the input (for a live source) and produce the next frame of grains.
+    pub fn step(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        // Only sizes the buffers if the granulator wasn't prepared for this sample rate.
+        self.prepare(sample_rate);
+        if let GrainSource::Live(buffers) = &mut self.source {
+            for (buffer, sample) in buffers.iter_mut().zip(input) {
+                buffer.write(sample);
+            }
+        }
+
+        self.countdown -= 1.0;
+        while self.countdown <= 0.0 {
+            let density = self.density.value();
+            if density <= 0.0 {
+                self.countdown = 1.0;
+                break;
+            }
+            self.countdown += sample_rate / density;
+            self.start_grain(density, sample_rate);
+        }
+
+        let source = &self.source;
+        let mut output = [0.0; 2];
+        self.grains.retain_mut(|grain| {
+            let window = grain.window();
+            for (channel, gain) in grain.gains.iter().enumerate() {
+                let sample = match source {
+                    GrainSource::Sample(wav) => {
+                        let data = &wav.channels[channel.min(wav.channel_count() - 1)];
+                        interpolate(data, grain.start + grain.age * grain.rate, None)
+                    },
+                    // The buffer moves on a sample every step, so the grain moves back through it
+                    // at one less than its rate.
+                    GrainSource::Live(buffers) => buffers[channel].read(grain.start - grain.age * (grain.rate - 1.0)),
+                };
+                output[channel] += sample * window * gain;
+            }
+            grain.age += 1.0;
+            grain.age < grain.length
+        });
+
+        output
+    }
+
+    /// Start a new grain, unless too many are already playing.
+    fn start_grain(&mut self, density: f64, sample_rate: f64) {
+        // Always use the random numbers, so that skipping a grain doesn't change later ones. The
+        // spray is in frames of the source.
+        let rate_ratio = self.source.rate_ratio(sample_rate);
+        let spray = self.rng.next_bipolar() * self.spray.value().max(0.0) * sample_rate * rate_ratio;
+        let pan = self.rng.next_bipolar() * self.stereo_spread.value().clamp(0.0, 1.0);
+        if self.grains.len() >= MAX_GRAINS {
+            return;
+        }
+
+        let size = self.size.value().max(0.0);
+        let length = (size * sample_rate).max(1.0);
+        let position = self.position.value().clamp(0.0, 1.0);
+        let start = match &self.source {
+            GrainSource::Sample(wav) => position * wav.frame_count() as f64 + spray,
+            GrainSource::Live(buffers) => (position * buffers[0].len() as f64 + spray).max(0.0),
+        };
+
+        // Overlapping grains are uncorrelated, so they're scaled to keep the power about the same.
+        let overlap = (density * size).max(1.0);
+        let angle = (pan + 1.0) * FRAC_PI_4;
+        let gain = SQRT_2 / overlap.sqrt();
+        self.grains.push(Grain {
+            start,
+            rate: self.pitch.value().max(0.0) * rate_ratio,
+            age: 0.0,
+            length,
+            gains: [angle.cos() * gain, angle.sin() * gain],
+        });
+    }
+}
+
+impl Effect for Granulator {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        let wet = self.step(input, sample_rate);
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        self.grains.clear();
+        self.countdown = 1.0;
+        if let GrainSource::Live(buffers) = &mut self.source {
+            buffers.iter_mut().for_each(DelayLine::clear);
+        }
+    }
+
+    fn prepare(&mut self, sample_rate: f64) {
+        Granulator::prepare(self, sample_rate);
+    }
+}
+
+/// Create a granular node, which plays grains of its source. The input is recorded if the source
+/// is live, and ignored otherwise.
+pub fn granular(time: &mut Continuous<Time>, input: &Continuous<Frame>, mut granulator: Granulator) -> Continuous<Frame> {
+    let mut clock = Clock::new();
+    snapshot1(time, input, move |time, input| {
+        clock.tick(time);
+        match clock.sample_rate() {
+            Some(sample_rate) => granulator.step(input, sample_rate),
+            None => [0.0; 2],
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const SAMPLE_RATE: f64 = 1000.0;
+
+    /// A mono sample which counts up from 0.
+    fn ramp(length: usize) -> GrainSource {
+        ramp_at(length, 1000)
+    }
+
+    /// A ramp recorded at the given sample rate.
+    fn ramp_at(length: usize, sample_rate: u32) -> GrainSource {
+        GrainSource::Sample(Arc::new(Wav {
+            sample_rate,
+            channels: vec![(0..length).map(|i| i as f64).collect()],
+            loop_points: None,
+        }))
+    }
+
+    fn run(granulator: &mut Granulator, length: usize) -> Vec<Frame> {
+        (0..length).map(|_| granulator.step([0.0; 2], SAMPLE_RATE)).collect()
+    }
+
+    #[test]
+    fn test_single_grain() {
+        // One 10 sample grain a second from the middle of the sample, in the centre.
+        let mut granulator = Granulator::new(ramp(100), 1);
+        granulator.size = 0.01.into();
+        granulator.density = 1.0.into();
+        granulator.position = 0.5.into();
+        let output = run(&mut granulator, 20);
+
+        for (n, frame) in output.iter().enumerate().take(10) {
+            let window = 0.5 - 0.5 * f64::cos(2.0 * PI * n as f64 / 10.0);
+            assert_relative_eq!(frame[0], (50 + n) as f64 * window, epsilon = 1e-9);
+            assert_relative_eq!(frame[1], frame[0], epsilon = 1e-9);
+        }
+        assert!(output[10..].iter().all(|frame| *frame == [0.0; 2]));
+
+        // An octave up reads twice as fast.
+        let mut granulator = Granulator::new(ramp(100), 1);
+        granulator.size = 0.01.into();
+        granulator.density = 1.0.into();
+        granulator.pitch = 2.0.into();
+        let output = run(&mut granulator, 10);
+        assert_relative_eq!(output[5][0], 10.0, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_sample_rate_mismatch() {
+        // A sample recorded at twice the output rate should be read two frames a sample at its
+        // original pitch, and the grain size and spray should stay in seconds.
+        let mut granulator = Granulator::new(ramp_at(200, 2000), 1);
+        granulator.size = 0.01.into();
+        granulator.density = 1.0.into();
+        granulator.position = 0.5.into();
+        let output = run(&mut granulator, 20);
+        let window = 0.5 - 0.5 * f64::cos(2.0 * PI * 5.0 / 10.0);
+        assert_relative_eq!(output[5][0], 110.0 * window, epsilon = 1e-9);
+        assert!(output[10..].iter().all(|frame| *frame == [0.0; 2]));
+
+        // Spray moves grains by up to the same time either side, which is twice as many frames.
+        let mut granulator = Granulator::new(ramp(200), 1);
+        granulator.spray = 0.02.into();
+        granulator.start_grain(1.0, SAMPLE_RATE);
+        let offset = granulator.grains[0].start;
+        let mut granulator = Granulator::new(ramp_at(200, 2000), 1);
+        granulator.spray = 0.02.into();
+        granulator.start_grain(1.0, SAMPLE_RATE);
+        assert_relative_eq!(granulator.grains[0].start, 2.0 * offset, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_density() {
+        // Grains should start at the density, and be scaled for the overlap.
+        let mut granulator = Granulator::new(ramp(1000), 1);
+        granulator.size = 0.04.into();
+        granulator.density = 100.0.into();
+        let mut started = 0;
+        for _ in 0..1000 {
+            granulator.step([0.0; 2], SAMPLE_RATE);
+            started += granulator.grains.iter().filter(|grain| grain.age == 1.0).count();
+        }
+        assert_eq!(started, 100);
+        // Four grains overlap, but the oldest has just finished.
+        assert_eq!(granulator.grains.len(), 3);
+        assert_relative_eq!(granulator.grains[0].gains[0], 0.5);
+    }
+
+    #[test]
+    fn test_seed() {
+        let granulator = |seed| {
+            let mut granulator = Granulator::new(ramp(1000), seed);
+            granulator.position = 0.5.into();
+            granulator.spray = 0.2.into();
+            granulator.stereo_spread = 1.0.into();
+            granulator
+        };
+
+        // The same seed should always give the same output, and a different seed shouldn't.
+        let output = run(&mut granulator(1), 500);
+        assert_eq!(output, run(&mut granulator(1), 500));
+        assert_ne!(output, run(&mut granulator(2), 500));
+        assert!(output.iter().any(|frame| frame[0] != frame[1]));
+    }
+
+    #[test]
+    fn test_live() {
+        // Grains from the live buffer should play back what was recorded, 20 samples ago.
+        let mut granulator = Granulator::new(GrainSource::live(), 1);
+        granulator.size = 0.01.into();
+        granulator.density = 1.0.into();
+        granulator.position = (20.0 / 4000.0).into();
+        granulator.mix = 1.0.into();
+
+        // Record a ramp before the first grain starts.
+        for n in 0..100 {
+            granulator.step([n as f64, -(n as f64)], SAMPLE_RATE);
+        }
+        granulator.countdown = 1.0;
+        let frame = (100..106).map(|n| granulator.process([n as f64, -(n as f64)], SAMPLE_RATE)).last().unwrap();
+        let window = 0.5 - 0.5 * f64::cos(2.0 * PI * 5.0 / 10.0);
+        assert_relative_eq!(frame[0], 85.0 * window, epsilon = 1e-9);
+        assert_relative_eq!(frame[1], -85.0 * window, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_prepare_live() {
+        // Preparing should size the live buffers before anything is recorded.
+        let mut granulator = Granulator::new(GrainSource::live(), 1);
+        Effect::prepare(&mut granulator, SAMPLE_RATE);
+        match &granulator.source {
+            GrainSource::Live(buffers) => assert!(buffers.iter().all(|buffer| buffer.len() == 4000)),
+            GrainSource::Sample(_) => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Low frequency oscillators, for modulating other parameters such as pitch, amplitude and cutoff.
+
+use crate::clock::Clock;
+use crate::functions::{saw_wave, sine_wave, square_wave, triangle_wave};
+use crate::random::Rng;
+use crate::signal::{Continuous, snapshot3};
+use crate::types::{Frequency, Sample, Time};
+
+/// The shape of an LFO's waveform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// A new random value at the start of every cycle, held until the next.
+    SampleAndHold,
+}
+
+/// The parameters of an LFO.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LfoParams {
+    pub shape: LfoShape,
+    /// The phase the LFO starts at, and is reset to when retriggered, from 0 to 1.
+    pub start_phase: f64,
+    /// Whether the phase is reset every time the gate opens. If not, the LFO runs freely.
+    pub retrigger: bool,
+    /// How long the LFO takes to fade in after the gate opens, in seconds.
+    pub fade_in: Time,
+    /// The seed for the sample and hold shape.
+    pub seed: u64,
+}
+
+impl Default for LfoParams {
+    fn default() -> Self {
+        Self {
+            shape: LfoShape::Sine,
+            start_phase: 0.0,
+            retrigger: false,
+            fade_in: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// A low frequency oscillator with a bipolar output in the range -1 to 1, scaled by its depth.
+#[derive(Clone, Debug)]
+pub struct Lfo {
+    params: LfoParams,
+    phase: f64,
+    fade_time: Time,
+    gate: bool,
+    held_value: f64,
+    rng: Rng,
+}
+
+impl Lfo {
+    /// Create a new LFO with the given parameters.
+    pub fn new(params: LfoParams) -> Self {
+        let mut rng = Rng::new(params.seed);
+        Self {
+            params,
+            phase: params.start_phase.rem_euclid(1.0),
+            fade_time: 0.0,
+            gate: false,
+            held_value: rng.next_bipolar(),
+            rng,
+        }
+    }
+
+    /// The current phase of the LFO, from 0 to 1.
+    pub fn phase(&self) -> f64 {
+        self.phase
+    }
+
+    /// Step the LFO forward by `time_step` seconds at the given rate (in Hz), and return the new
+    /// output. When the gate opens the fade in restarts, and the phase is reset if the LFO is set
+    /// to retrigger.
+    pub fn step(&mut self, gate: bool, rate: Frequency, depth: f64, time_step: Time) -> Sample {
+        if gate && !self.gate {
+            self.fade_time = 0.0;
+            if self.params.retrigger {
+                self.phase = self.params.start_phase.rem_euclid(1.0);
+                self.held_value = self.rng.next_bipolar();
+            }
+        }
+        self.gate = gate;
+
+        let output = self.value();
+
+        // Advance the phase, picking a new sample and hold value every time it wraps.
+        self.phase += rate * time_step;
+        if self.phase >= 1.0 || self.phase < 0.0 {
+            self.phase = self.phase.rem_euclid(1.0);
+            self.held_value = self.rng.next_bipolar();
+        }
+
+        let fade = if self.params.fade_in > 0.0 {
+            (self.fade_time / self.params.fade_in).min(1.0)
+        }
+        else {
+            1.0
+        };
+        self.fade_time += time_step;
+
+        output * depth * fade
+    }
+
+    /// The value of the waveform at the current phase. The oscillator functions all have a period
+    /// of 1 at a frequency of 1, so the phase can be passed as the time.
+    fn value(&self) -> f64 {
+        match self.params.shape {
+            LfoShape::Sine => sine_wave(self.phase, 1.0),
+            // Shift the triangle so it starts at 0 and rises, like the sine.
+            LfoShape::Triangle => triangle_wave(self.phase + 0.25, 1.0) * 2.0 - 1.0,
+            LfoShape::Saw => saw_wave(self.phase + 0.5, 1.0) * 2.0 - 1.0,
+            LfoShape::Square => square_wave(self.phase, 1.0),
+            LfoShape::SampleAndHold => self.held_value,
+        }
+    }
+}
+
+/// Create an LFO node which is stepped every time the time signal changes. The rate (in Hz) and
+/// depth can be modulated by other signals. For a free running LFO shared between voices, pass a
+/// constant gate.
+pub fn lfo(time: &mut Continuous<Time>,
+           gate: &Continuous<bool>,
+           rate: &Continuous<Frequency>,
+           depth: &Continuous<f64>,
+           params: LfoParams)
+    -> Continuous<Sample>
+{
+    let mut clock = Clock::new();
+    let mut lfo = Lfo::new(params);
+    snapshot3(time, gate, rate, depth, move |time, gate, rate, depth| {
+        let time_step = clock.tick(time);
+        lfo.step(gate, rate, depth, time_step)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const TIME_STEP: f64 = 0.001;
+
+    fn run(lfo: &mut Lfo, gate: bool, steps: usize) -> Vec<f64> {
+        (0..steps).map(|_| lfo.step(gate, 10.0, 1.0, TIME_STEP)).collect()
+    }
+
+    #[test]
+    fn test_lfo_shapes() {
+        // All of the shapes should start at 0 and rise (except square and sample and hold), and be
+        // in the range -1 to 1.
+        for shape in [LfoShape::Sine, LfoShape::Triangle, LfoShape::Saw] {
+            let mut lfo = Lfo::new(LfoParams { shape, ..LfoParams::default() });
+            let output = run(&mut lfo, true, 100);
+            assert_relative_eq!(output[0], 0.0, epsilon = 1e-9);
+            assert!(output[1] > 0.0);
+            assert!(output.iter().all(|value| (-1.0..=1.0).contains(value)));
+        }
+
+        // The triangle should peak a quarter of the way through its cycle.
+        let mut lfo = Lfo::new(LfoParams { shape: LfoShape::Triangle, ..LfoParams::default() });
+        assert_relative_eq!(run(&mut lfo, true, 26)[25], 1.0, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_lfo_sample_and_hold() {
+        let params = LfoParams { shape: LfoShape::SampleAndHold, seed: 1234, ..LfoParams::default() };
+        let mut lfo = Lfo::new(params);
+        let output = run(&mut lfo, true, 300);
+
+        // The value should be held for a whole cycle, and change every cycle.
+        assert!(output[0..100].iter().all(|value| *value == output[0]));
+        assert_ne!(output[100], output[0]);
+        assert_ne!(output[200], output[100]);
+
+        // And the same seed should give the same values.
+        assert_eq!(run(&mut Lfo::new(params), true, 300), output);
+    }
+
+    #[test]
+    fn test_lfo_retrigger_and_fade() {
+        let params = LfoParams {
+            start_phase: 0.25,
+            retrigger: true,
+            fade_in: 0.01,
+            ..LfoParams::default()
+        };
+        let mut lfo = Lfo::new(params);
+
+        // The output should fade in from 0 to the start phase's value of 1.
+        let output = run(&mut lfo, true, 11);
+        assert_eq!(output[0], 0.0);
+        assert!(output[5] < output[9]);
+        assert_relative_eq!(lfo.phase(), 0.36, epsilon = 1e-9);
+
+        // Retriggering should reset the phase.
+        run(&mut lfo, false, 5);
+        lfo.step(true, 10.0, 1.0, 0.0);
+        assert_relative_eq!(lfo.phase(), 0.25);
+
+        // And a free running LFO shouldn't.
+        let mut lfo = Lfo::new(LfoParams { retrigger: false, ..params });
+        run(&mut lfo, true, 10);
+        run(&mut lfo, false, 5);
+        lfo.step(true, 10.0, 1.0, 0.0);
+        assert_relative_eq!(lfo.phase(), 0.4, epsilon = 1e-9);
+    }
+}
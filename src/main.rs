@@ -4,71 +4,456 @@ pub mod synth;
 pub mod signal;
 pub mod types;
 pub mod functions;
+pub mod clock;
+pub mod envelope;
+pub mod filter;
+pub mod lfo;
+pub mod random;
+pub mod fm;
+pub mod additive;
+pub mod tuning;
+pub mod mts;
+pub mod delay_line;
+pub mod effects;
+pub mod reverb;
+pub mod fft;
+pub mod wav;
+pub mod convolution;
+pub mod distortion;
+pub mod dynamics;
+pub mod unison;
+pub mod oscillator;
+pub mod sampler;
+pub mod sfz;
+pub mod soundfont;
+pub mod physical;
+pub mod granular;
+pub mod drums;
+pub mod vocoder;
+pub mod resonator;
+pub mod formant;
+pub mod eq;
+pub mod oversampling;
 
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::{error::Error, thread::sleep, time::Duration};
 use midi_control::MidiMessage;
-use functions::{midi_note_to_frequency, sine_wave, triangle_wave};
+use additive::additive;
+use convolution::ConvolutionReverb;
+use drums::drums;
+use dynamics::{Compressor, GainReductionMeter, Limiter};
+use effects::{Chorus, DelayTime, EffectsRack, StereoDelay};
+use eq::{BandKind, EqBand, ParametricEq};
+use envelope::{adsr, AdsrParams};
+use filter::{filter, FilterMode, Ladder};
+use fm::{fm_voice, Algorithm, FmPatch, OperatorFrequency, OperatorParams};
+use formant::formant;
+use functions::{midi_note_to_frequency, saw_wave, triangle_wave, Partial};
+use granular::{granular, GrainSource, Granulator};
+use lfo::{lfo, LfoParams};
+use oscillator::{hard_sync, OscillatorShape};
+use oversampling::{oversample_source, OversamplingFactor};
+use physical::{noise_burst, waveguide, KarplusStrong, WaveguideTube};
+use resonator::{resonator, ModalBank};
+use reverb::Reverb;
+use sampler::{sampler, Instrument};
+use soundfont::{soundfont, SoundFont};
 use ringbuf::HeapRb;
 use signal::Continuous;
-use types::{Sample, MidiNote};
+use tuning::Tuning;
+use types::{Frame, Frequency, Time};
+use wav::Wav;
+use unison::{unison, UnisonParams};
+use vocoder::{Modulator, Vocoder};
 
-use crate::audio_device::AudioOutput;
+use crate::audio_device::{AudioInput, AudioOutput};
 use crate::midi_device::MidiInput;
-use crate::signal::{Discrete, lift2};
-use crate::synth::MidiSynth;
+use crate::signal::lift2;
+use crate::synth::{MidiSynth, SynthInputs, SynthNetwork, VoiceInput};
 
 /// The size of the audio buffer.
 const AUDIO_BUFFER_SIZE: usize = 2048;
 
-/// Create a simple synth network that takes a time and midi note(s) as input and outputs a simple
-/// sine wave. Returns a discrete input signal for each midi note pressed (up to `voices`), and a
-/// continuous signal that can be sampled to get the output of the synth.
-/// TODO: it might be worth making a new type `SynthNetwork` that contains these signals and the
-///       input_time signal and return that instead.
-fn synth_network(input_time: &mut Discrete<f64>, voice_count: usize)
-    -> (Vec<Discrete<MidiNote>>, Continuous<Sample>)
-{
+/// The number of voices for the synthesised voices, and for the sampled and drum voices, which are
+/// cheaper and often need more to play overlapping notes and drums across several channels.
+const VOICE_COUNT: usize = 2;
+const SAMPLER_VOICE_COUNT: usize = 16;
+
+/// The number of vocoder bands if not given on the command line.
+const VOCODER_BAND_COUNT: usize = 16;
+
+/// The size of the ring buffer for a live vocoder modulator.
+const VOCODER_INPUT_BUFFER_SIZE: usize = 8192;
+
+/// The highest level the master output should reach, in dB.
+const MASTER_LIMITER_CEILING: f64 = -0.3;
+
+/// How far ahead the master limiter looks for peaks, in seconds.
+const MASTER_LIMITER_LOOKAHEAD: Time = 0.005;
+
+/// A function which builds the network for a single voice from the time signal, the frequency of
+/// the voice's note and the voice's inputs, returning the voice's stereo output signal.
+type VoiceBuilder = Box<dyn Fn(&mut Continuous<Time>, &mut Continuous<Frequency>, &mut VoiceInput) -> Continuous<Frame>>;
+
+/// Create a simple synth network that takes a time, tuning and midi note(s) as input and mixes
+/// together `voice_count` voices built by `voice`.
+fn synth_network(voice_count: usize, voice: VoiceBuilder) -> SynthNetwork {
     if voice_count == 0 {
         panic!("voices cannot be 0");
     }
 
-    // Create time signal.
-    let mut time = input_time.hold();
-
-    // Create input note signals.
-    let mut input_notes: Vec<Discrete<MidiNote>> = std::iter::repeat_with(Discrete::new).take(voice_count).collect();
-
-    // Create an output oscillator for each voice.
-    let mut voices: Vec<Continuous<Sample>> = input_notes.iter_mut().map(|input_note| {
-        // Create frequency signal.
-        let mut frequency = input_note.hold().map(midi_note_to_frequency);
+    // Create input signals.
+    let mut inputs = SynthInputs::new(voice_count);
 
-        // Create oscillator for voice.
-        let oscillator = lift2(time.as_mut(), frequency.as_mut(), triangle_wave);
+    // Create time and tuning signals.
+    let mut time = inputs.time.hold();
+    let mut tuning = inputs.tuning.hold();
 
-        oscillator
+    // Create the network for each voice, with a frequency signal that follows the tuning. Unmapped
+    // notes are never given to a voice, so their frequency doesn't matter.
+    let mut voices: Vec<Continuous<Frame>> = inputs.voices.iter_mut().map(|input_voice| {
+        let mut frequency = lift2(input_voice.note.hold().as_mut(), &mut tuning, |note, tuning| {
+            tuning.frequency(note).unwrap_or(0.0)
+        });
+        voice(&mut time, &mut frequency, input_voice)
     }).collect();
 
-    // Mix voices.
-    // TODO: find out if just adding the samples is correct, or if there's a better way.
-    // TODO: sometimes one of the voices doesn't seem to play if you start playing them in the
-    // wrong order?
+    // Mix voices. The sum can go over full scale with many voices, which the master compressor and
+    // limiter in the host take care of.
     let mut mixed_signal = voices.swap_remove(0);
     for voice in voices.iter_mut() {
-        mixed_signal = lift2(mixed_signal.as_mut(), voice.as_mut(), move |a, b| {
-            a + b
+        mixed_signal = lift2(mixed_signal.as_mut(), voice, move |a, b| {
+            [a[0] + b[0], a[1] + b[1]]
         });
     }
 
-    (input_notes, mixed_signal)
+    SynthNetwork { inputs, output: mixed_signal }
+}
+
+/// A simple subtractive voice: an enveloped triangle wave through a low-pass filter.
+fn subtractive_voice(time: &mut Continuous<Time>,
+                     frequency: &mut Continuous<Frequency>,
+                     input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    // Create oscillator for voice.
+    let oscillator = lift2(time, frequency, triangle_wave);
+
+    // Create amplitude envelope for voice.
+    let gate = input_voice.gate.hold();
+    let velocity = input_voice.velocity.hold();
+    let mut envelope = adsr(time, &gate, &velocity, AdsrParams::default());
+
+    // Filter the oscillator, opening the filter with the envelope.
+    let cutoff = envelope.map(|amplitude| 200.0 + 4000.0 * amplitude);
+    let resonance = Continuous::constant(0.3);
+    let mut filtered = filter(time, &oscillator, &cutoff, &resonance, Ladder::new(FilterMode::LowPass));
+
+    lift2(&mut filtered, &mut envelope, |sample, amplitude| [sample * amplitude; 2])
+}
+
+/// A two operator FM voice, with a decaying modulator for an electric piano-like tone.
+fn fm_piano_voice(time: &mut Continuous<Time>,
+                  frequency: &mut Continuous<Frequency>,
+                  input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let gate = input_voice.gate.hold();
+    let velocity = input_voice.velocity.hold();
+
+    let carrier = OperatorParams {
+        envelope: AdsrParams { attack: 0.002, decay: 1.5, sustain: 0.3, release: 0.4, ..AdsrParams::default() },
+        ..OperatorParams::default()
+    };
+    let modulator = OperatorParams {
+        frequency: OperatorFrequency::Ratio(14.0),
+        level: 0.3,
+        feedback: 0.1,
+        envelope: AdsrParams { attack: 0.001, decay: 0.3, sustain: 0.0, release: 0.1, ..AdsrParams::default() },
+        ..OperatorParams::default()
+    };
+    let patch = Algorithm::stack(2)
+        .and_then(|algorithm| FmPatch::new(vec![carrier, modulator], algorithm))
+        .expect("FM piano patch should be valid");
+
+    fm_voice(time, frequency, &gate, &velocity, patch).map(|sample| [sample; 2])
+}
+
+/// An additive organ voice, with drawbar-style harmonics and a percussive third harmonic.
+fn organ_voice(time: &mut Continuous<Time>,
+               frequency: &mut Continuous<Frequency>,
+               input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let gate = input_voice.gate.hold();
+    let velocity = input_voice.velocity.hold();
+
+    let partials: Arc<[Partial]> = Arc::new([
+        Partial::new(0.5, 0.3),
+        Partial::new(1.0, 0.4),
+        Partial::new(2.0, 0.2),
+        Partial::new(3.0, 0.3),
+        Partial::new(4.0, 0.1),
+    ]);
+    let partials = Continuous::constant(partials);
+    let sustained = AdsrParams { attack: 0.005, decay: 0.0, sustain: 1.0, release: 0.05, ..AdsrParams::default() };
+    let percussive = AdsrParams { decay: 0.4, sustain: 0.0, ..sustained };
+
+    additive(time, frequency, &gate, &velocity, &partials,
+             vec![sustained, sustained, sustained, percussive, sustained])
+        .map(|sample| [sample; 2])
+}
+
+/// A supersaw voice: seven detuned saws spread across the stereo field.
+fn supersaw_voice(time: &mut Continuous<Time>,
+                  frequency: &mut Continuous<Frequency>,
+                  input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let gate = input_voice.gate.hold();
+    let velocity = input_voice.velocity.hold();
+
+    let mut saws = unison(time, frequency, |time, frequency| saw_wave(time, frequency) * 2.0 - 1.0, UnisonParams::default());
+    let params = AdsrParams { attack: 0.05, release: 0.5, ..AdsrParams::default() };
+    let mut envelope = adsr(time, &gate, &velocity, params);
+
+    lift2(&mut saws, &mut envelope, |frame, amplitude| [frame[0] * amplitude * 0.5, frame[1] * amplitude * 0.5])
+}
+
+/// A hard sync lead: a band-limited saw whose phase is reset at the note's frequency. The saw's own
+/// frequency starts at up to four times the note's and is swept down to 1.6 times it by an
+/// envelope, which sweeps the sync harmonics down with it.
+fn sync_voice(time: &mut Continuous<Time>,
+              frequency: &mut Continuous<Frequency>,
+              input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let gate = input_voice.gate.hold();
+    let velocity = input_voice.velocity.hold();
+
+    let sweep_params = AdsrParams { attack: 0.001, decay: 0.8, sustain: 0.2, release: 0.3, ..AdsrParams::default() };
+    let mut sweep = adsr(time, &gate, &velocity, sweep_params);
+    let slave_frequency = lift2(frequency, &mut sweep, |frequency, sweep| frequency * (1.0 + 3.0 * sweep));
+    let mut oscillator = hard_sync(time, frequency, &slave_frequency, OscillatorShape::Saw);
+    let mut envelope = adsr(time, &gate, &velocity, AdsrParams::default());
+
+    lift2(&mut oscillator, &mut envelope, |sample, amplitude| [sample * amplitude * 0.5; 2])
+}
+
+/// A plucked string voice: a Karplus-Strong string plucked with a burst of noise, which is brighter
+/// the harder it's played and is damped when the note is released.
+fn pluck_voice(time: &mut Continuous<Time>,
+               frequency: &mut Continuous<Frequency>,
+               input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let mut gate = input_voice.gate.hold();
+    let mut velocity = input_voice.velocity.hold();
+
+    let mut burst = noise_burst(time, &gate, 0.005, 1);
+    let excitation = lift2(&mut burst, &mut velocity, |noise, velocity| noise * velocity);
+    let damping = gate.map(|gate| if gate { 0.2 } else { 0.8 });
+    let brightness = velocity.map(|velocity| 0.3 + 0.7 * velocity);
+
+    waveguide(time, &excitation, frequency, &damping, &brightness, KarplusStrong::new())
+        .map(|sample| [sample * 0.5; 2])
+}
+
+/// A clarinet voice: a reed and tube model blown with an envelope.
+fn clarinet_voice(time: &mut Continuous<Time>,
+                  frequency: &mut Continuous<Frequency>,
+                  input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let gate = input_voice.gate.hold();
+    let velocity = input_voice.velocity.hold();
+
+    let params = AdsrParams { attack: 0.05, decay: 0.1, sustain: 0.9, release: 0.05, ..AdsrParams::default() };
+    let mut breath = adsr(time, &gate, &velocity, params);
+    let breath = breath.map(|level| 0.9 * level);
+    let damping = Continuous::constant(0.3);
+    let brightness = Continuous::constant(0.6);
+
+    waveguide(time, &breath, frequency, &damping, &brightness, WaveguideTube::new())
+        .map(|sample| [sample * 0.3; 2])
+}
+
+/// A talking lead: an oversampled saw through a formant filter, with the vowel set by the mod wheel
+/// and swept a little by an LFO.
+fn talk_voice(time: &mut Continuous<Time>,
+              frequency: &mut Continuous<Frequency>,
+              input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let gate = input_voice.gate.hold();
+    let velocity = input_voice.velocity.hold();
+    let mut mod_wheel = input_voice.mod_wheel.hold();
+
+    // The naive saw is oversampled to keep its aliasing down.
+    let oscillator = oversample_source(time, OversamplingFactor::X4, |time| {
+        lift2(time, frequency, |time, frequency| 2.0 * saw_wave(time, frequency) - 1.0)
+    });
+    let mut wobble = lfo(time, &gate, &Continuous::constant(0.5), &Continuous::constant(0.5), LfoParams::default());
+    let vowel = lift2(&mut mod_wheel, &mut wobble, |mod_wheel, wobble| 4.0 * mod_wheel + wobble);
+    let mut filtered = formant(time, &oscillator, &vowel);
+
+    let params = AdsrParams { attack: 0.05, sustain: 1.0, release: 0.3, ..AdsrParams::default() };
+    let mut envelope = adsr(time, &gate, &velocity, params);
+    lift2(&mut filtered, &mut envelope, |sample, amplitude| [sample * amplitude; 2])
+}
+
+/// A bell voice: a modal bell struck with a short burst of noise.
+fn bell_voice(time: &mut Continuous<Time>,
+              frequency: &mut Continuous<Frequency>,
+              input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let gate = input_voice.gate.hold();
+    let mut velocity = input_voice.velocity.hold();
+
+    let mut strike = noise_burst(time, &gate, 0.001, 1);
+    let excitation = lift2(&mut strike, &mut velocity, |noise, velocity| noise * velocity);
+    resonator(time, &excitation, frequency, ModalBank::bell()).map(|sample| [sample * 0.5; 2])
+}
+
+/// A marimba voice: a modal marimba bar struck with a slightly longer, softer burst of noise.
+fn marimba_voice(time: &mut Continuous<Time>,
+                 frequency: &mut Continuous<Frequency>,
+                 input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let gate = input_voice.gate.hold();
+    let mut velocity = input_voice.velocity.hold();
+
+    let mut strike = noise_burst(time, &gate, 0.003, 1);
+    let excitation = lift2(&mut strike, &mut velocity, |noise, velocity| noise * velocity * 0.5);
+    resonator(time, &excitation, frequency, ModalBank::marimba()).map(|sample| [sample * 0.5; 2])
+}
+
+/// A drum voice, which plays the synthesised drum mapped to each note.
+fn drum_voice(time: &mut Continuous<Time>,
+              _frequency: &mut Continuous<Frequency>,
+              input_voice: &mut VoiceInput)
+    -> Continuous<Frame>
+{
+    let note = input_voice.note.hold();
+    let gate = input_voice.gate.hold();
+    let velocity = input_voice.velocity.hold();
+    drums(time, &note, &gate, &velocity, 1).map(|frame| [frame[0] * 0.5, frame[1] * 0.5])
+}
+
+/// A granular voice, which scatters grains from around a third of the way through a sample, pitched
+/// so that middle C plays the sample at its original pitch.
+fn granular_voice(sample: Arc<Wav>) -> VoiceBuilder {
+    Box::new(move |time, frequency, input_voice| {
+        let gate = input_voice.gate.hold();
+        let velocity = input_voice.velocity.hold();
+
+        let mut granulator = Granulator::new(GrainSource::Sample(sample.clone()), 1);
+        granulator.size = 0.08.into();
+        granulator.density = 40.0.into();
+        granulator.position = 0.3.into();
+        granulator.spray = 0.05.into();
+        granulator.stereo_spread = 0.7.into();
+        granulator.pitch = frequency.map(|frequency| frequency / midi_note_to_frequency(60)).into();
+        let mut grains = granular(time, &Continuous::constant([0.0; 2]), granulator);
+
+        let params = AdsrParams { attack: 0.2, release: 0.8, ..AdsrParams::default() };
+        let mut envelope = adsr(time, &gate, &velocity, params);
+        lift2(&mut grains, &mut envelope, |frame, amplitude| [frame[0] * amplitude, frame[1] * amplitude])
+    })
+}
+
+/// A sampler voice, which plays the regions of an instrument that match the note.
+fn sampler_voice(instrument: Arc<Instrument>) -> VoiceBuilder {
+    Box::new(move |time, frequency, input_voice| {
+        let note = input_voice.note.hold();
+        let gate = input_voice.gate.hold();
+        let velocity = input_voice.velocity.hold();
+        sampler(time, &note, frequency, &gate, &velocity, instrument.clone())
+    })
+}
+
+/// A SoundFont voice, which plays the preset for the program of each note's channel.
+fn soundfont_voice(font: Arc<SoundFont>) -> VoiceBuilder {
+    Box::new(move |time, frequency, input_voice| {
+        let note = input_voice.note.hold();
+        let gate = input_voice.gate.hold();
+        let velocity = input_voice.velocity.hold();
+        let program = input_voice.program.hold();
+        soundfont(time, &note, frequency, &gate, &velocity, &program, font.clone())
+    })
+}
+
+/// The master effects: a gentle chorus, a dotted eighth note ping-pong delay and a reverb, which
+/// is a convolution reverb if an impulse response is given, otherwise a medium room.
+fn master_effects(impulse_response: Option<String>) -> Result<EffectsRack, Box<dyn Error>> {
+    let mut delay = StereoDelay::new(DelayTime::Beats { beats: 0.75, tempo: 120.0.into() }, 0.35.into(), 0.2.into());
+    delay.ping_pong = true;
+    delay.low_cut = Some(200.0.into());
+    delay.high_cut = Some(4000.0.into());
+
+    let rack = EffectsRack::new()
+        .with(Chorus::new(0.5.into(), 0.5.into(), 0.3.into()))
+        .with(delay);
+
+    Ok(match impulse_response {
+        Some(path) => rack.with(ConvolutionReverb::load(path, 0.25.into())?),
+        None => rack.with(Reverb::new(0.6.into(), 0.4.into(), 0.25.into())),
+    })
+}
+
+/// Create the master EQ: a low cut to remove rumble and DC, a little low end warmth and air, and a
+/// gentle dip in the harsh upper mids.
+fn master_eq() -> ParametricEq {
+    ParametricEq::new(vec![
+        EqBand::new(BandKind::LowCut, 25.0.into(), 0.0.into(), 0.7.into()),
+        EqBand::new(BandKind::LowShelf, 120.0.into(), 1.5.into(), 0.7.into()),
+        EqBand::new(BandKind::Peak, 3000.0.into(), (-1.5).into(), 1.0.into()),
+        EqBand::new(BandKind::HighShelf, 10000.0.into(), 1.0.into(), 0.7.into()),
+    ])
+}
+
+/// Create a vocoder whose modulator is either the default audio input (if `modulator` is `input`)
+/// or a WAV file. The audio input is returned too, as it stops when it's dropped.
+fn vocoder(modulator: &str, band_count: usize, sample_rate: u32)
+    -> Result<(Vocoder, Option<AudioInput>), Box<dyn Error>>
+{
+    if modulator == "input" {
+        let (prod, cons) = HeapRb::<f32>::new(VOCODER_INPUT_BUFFER_SIZE).split();
+        let audio_input = AudioInput::connect_default(prod, sample_rate)?;
+        Ok((Vocoder::new(band_count, Modulator::Live(cons)), Some(audio_input)))
+    }
+    else {
+        let wav = Arc::new(Wav::load(modulator)?);
+        Ok((Vocoder::new(band_count, Modulator::sample(wav)), None))
+    }
+}
+
+/// Remove an option and its value (e.g. `--ir hall.wav`) from the command line arguments, returning
+/// the value if the option was given.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let Some(index) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    if index + 1 >= args.len() {
+        return Err(format!("Missing value for {name}").into());
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(value))
 }
 
 /// A standalone command-line midi synth host.
-fn midi_synth_host(input_time: Discrete<f64>,
-                   input_notes: Vec<Discrete<u8>>,
-                   network: Continuous<f64>)
+///
+/// The master compressor, limiter and EQ are added to the end of the effects, with the EQ last. The
+/// limiter's ceiling is lowered by the EQ's largest boost, so that the EQ doesn't push the output
+/// past the ceiling. The gain reduction of the dynamics is logged while notes are playing.
+fn midi_synth_host(network: SynthNetwork,
+                   tuning: Tuning,
+                   effects: EffectsRack,
+                   vocoder_settings: Option<(String, usize)>)
     -> Result<(), Box<dyn Error>>
 {
     // Initialise logging.
@@ -93,14 +478,35 @@ fn midi_synth_host(input_time: Discrete<f64>,
     log::info!("Attempting to connect to midi device: {midi_device}");
     let mut _midi_input = MidiInput::connect("SubSynth", midi_device, sender)?;
 
-    // Create sine wave oscillator.
+    // Put the vocoder before the other effects, so that they process the vocoded sound.
+    let (mut effects, _audio_input) = match vocoder_settings {
+        Some((modulator, band_count)) => {
+            let (vocoder, audio_input) = vocoder(&modulator, band_count, audio_output.sample_rate())?;
+            (EffectsRack::new().with(vocoder).with(effects), audio_input)
+        },
+        None => (effects, None),
+    };
+
+    // Add master dynamics, leaving headroom for the EQ's boosts.
+    let eq = master_eq();
+    let headroom = eq.max_magnitude(audio_output.sample_rate() as f64).max(0.0);
+    let compressor = Compressor::new((-12.0).into(), 3.0.into());
+    let limiter = Limiter::new((MASTER_LIMITER_CEILING - headroom).into(), MASTER_LIMITER_LOOKAHEAD);
+    let meters = [compressor.meter(), limiter.meter()];
+    effects.push(compressor);
+    effects.push(limiter);
+
+    // The EQ is the last stage.
+    effects.push(eq);
+
+    // Start the synth.
     let _midi_synth = MidiSynth::new(receiver,
                                      prod,
                                      audio_output.sample_rate() as usize,
                                      audio_output.channel_count() as usize,
-                                     input_time,
-                                     input_notes,
-                                     network);
+                                     network,
+                                     tuning,
+                                     effects);
 
     // Register ctrl-c handler for clean exit.
     let should_exit = Arc::new(AtomicBool::new(false));
@@ -110,6 +516,11 @@ fn midi_synth_host(input_time: Discrete<f64>,
     log::info!("Running... press ctrl-C to exit.");
     while !should_exit.load(Ordering::Relaxed) {
         sleep(Duration::from_millis(100));
+
+        let [compressor, limiter] = meters.each_ref().map(GainReductionMeter::get);
+        if compressor >= 0.1 || limiter >= 0.1 {
+            log::debug!("Gain reduction: compressor {compressor:.1}dB, limiter {limiter:.1}dB");
+        }
     }
 
     log::info!("Exit requested");
@@ -137,11 +548,48 @@ fn signal_on_ctrlc(ctrlc_sent: Arc<AtomicBool>) -> Result<(), Box<dyn Error>> {
 
 /// Entry point
 fn main() -> Result<(), Box<dyn Error>> {
+    // Usage: subsynth [--ir impulse_response.wav] [--vocoder input | modulator.wav] [--bands count]
+    //                 [voice | instrument.sfz | soundfont.sf2 | grains.wav] [scale.scl [mapping.kbm]]
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let impulse_response = take_option(&mut args, "--ir")?;
+    let vocoder_modulator = take_option(&mut args, "--vocoder")?;
+    let band_count = match take_option(&mut args, "--bands")? {
+        Some(bands) => bands.parse().map_err(|_| format!("Invalid band count {bands}"))?,
+        None => VOCODER_BAND_COUNT,
+    };
+
+    // Pick the voice type from the command line.
+    let (voice, voice_count): (VoiceBuilder, usize) = match args.first().map(String::as_str) {
+        None | Some("subtractive") => (Box::new(subtractive_voice), VOICE_COUNT),
+        Some("fm") => (Box::new(fm_piano_voice), VOICE_COUNT),
+        Some("organ") => (Box::new(organ_voice), VOICE_COUNT),
+        Some("supersaw") => (Box::new(supersaw_voice), VOICE_COUNT),
+        Some("sync") => (Box::new(sync_voice), VOICE_COUNT),
+        Some("pluck") => (Box::new(pluck_voice), VOICE_COUNT),
+        Some("clarinet") => (Box::new(clarinet_voice), VOICE_COUNT),
+        Some("talk") => (Box::new(talk_voice), VOICE_COUNT),
+        Some("bell") => (Box::new(bell_voice), VOICE_COUNT),
+        Some("marimba") => (Box::new(marimba_voice), VOICE_COUNT),
+        Some("drums") => (Box::new(drum_voice), SAMPLER_VOICE_COUNT),
+        Some(path) if path.ends_with(".sfz") => (sampler_voice(Arc::new(Instrument::load_sfz(path)?)), SAMPLER_VOICE_COUNT),
+        Some(path) if path.ends_with(".sf2") => (soundfont_voice(Arc::new(SoundFont::load(path)?)), SAMPLER_VOICE_COUNT),
+        Some(path) if path.ends_with(".wav") => (granular_voice(Arc::new(Wav::load(path)?)), VOICE_COUNT),
+        Some(other) => return Err(format!("Unknown voice type {other}, expected subtractive, fm, organ, supersaw, sync, pluck, clarinet, talk, bell, marimba, drums, or an SFZ, SoundFont or WAV file").into()),
+    };
+
     // Create synth network.
-    let mut input_time = Discrete::<f64>::new();
+    let network = synth_network(voice_count, voice);
 
-    let (input_notes, network) = synth_network(input_time.as_mut(), 2);
+    // Load the tuning from a scale file and optional keyboard mapping file if given on the command
+    // line, otherwise use standard tuning.
+    let tuning = match args.get(1) {
+        Some(scale_path) => Tuning::load(scale_path, args.get(2))?,
+        None => Tuning::default(),
+    };
 
     // Start standalone synth host.
-    midi_synth_host(input_time, input_notes, network)
+    midi_synth_host(network,
+                    tuning,
+                    master_effects(impulse_response)?,
+                    vocoder_modulator.map(|modulator| (modulator, band_count)))
 }
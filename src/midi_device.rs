@@ -59,7 +59,7 @@ impl MidiInput {
         let ports: Vec<String> = midi_input
         .ports()
         .iter()
-        .map(|port| midi_input.port_name(&port))
+        .map(|port| midi_input.port_name(port))
         .filter_map(|res| res.ok())
         .collect();
 
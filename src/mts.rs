@@ -0,0 +1,270 @@
+//! Parsing of MIDI Tuning Standard (MTS) system exclusive messages, which retune individual notes or
+//! the whole keyboard.
+//!
+//! Tuning program and bank numbers are parsed but not stored separately: every message is applied
+//! to the synth's current tuning, which is how most software instruments treat them.
+
+use midi_control::{SysExEvent, message::SysExType};
+
+use crate::tuning::Tuning;
+use crate::types::{Frequency, MidiNote};
+
+/// The universal sysex sub ID 1 for MIDI tuning standard messages.
+const MIDI_TUNING: u8 = 0x08;
+
+/// The sub ID 2 of each supported MTS message.
+const BULK_DUMP: u8 = 0x01;
+const SINGLE_NOTE_CHANGE: u8 = 0x02;
+const KEY_BASED_DUMP: u8 = 0x04;
+const SINGLE_NOTE_CHANGE_BANK: u8 = 0x07;
+const OCTAVE_TUNING_1_BYTE: u8 = 0x08;
+const OCTAVE_TUNING_2_BYTE: u8 = 0x09;
+
+/// The byte that terminates a sysex message.
+const EOX: u8 = 0xF7;
+
+/// The length of a tuning name in a bulk dump.
+const NAME_LENGTH: usize = 16;
+
+/// A parsed MIDI tuning standard message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TuningMessage {
+    /// A new frequency for some or all notes. Notes that are None should be left unchanged.
+    Notes {
+        program: u8,
+        name: Option<String>,
+        frequencies: Vec<(MidiNote, Option<Frequency>)>,
+    },
+    /// An offset in cents from equal temperament for each pitch class, starting from C, which is
+    /// applied in every octave.
+    Octave {
+        offsets: [f64; 12],
+    },
+}
+
+impl TuningMessage {
+    /// Parse an MTS message from a sysex event, returning None if the event isn't a supported
+    /// tuning message or is malformed.
+    pub fn parse(event: &SysExEvent) -> Option<Self> {
+        let (realtime, device, sub_id) = match event.get_type() {
+            SysExType::NonRealTime(device, [MIDI_TUNING, sub_id]) => (false, *device, *sub_id),
+            SysExType::RealTime(device, [MIDI_TUNING, sub_id]) => (true, *device, *sub_id),
+            _ => return None,
+        };
+
+        // Strip the terminating byte.
+        let data = event.get_data().as_slice();
+        let data = data.strip_suffix(&[EOX]).unwrap_or(data);
+
+        match (realtime, sub_id) {
+            (false, BULK_DUMP) | (false, KEY_BASED_DUMP) => parse_dump(data, device, sub_id),
+            (true, SINGLE_NOTE_CHANGE) => parse_note_change(data, false),
+            (_, SINGLE_NOTE_CHANGE_BANK) => parse_note_change(data, true),
+            (_, OCTAVE_TUNING_1_BYTE) => parse_octave(data, 1),
+            (_, OCTAVE_TUNING_2_BYTE) => parse_octave(data, 2),
+            _ => None,
+        }
+    }
+
+    /// Apply the message to a tuning.
+    pub fn apply(&self, tuning: &mut Tuning) {
+        match self {
+            TuningMessage::Notes { frequencies, .. } => {
+                for (note, frequency) in frequencies {
+                    if let Some(frequency) = frequency {
+                        tuning.set_frequency(*note, *frequency);
+                    }
+                }
+            },
+            TuningMessage::Octave { offsets } => {
+                for note in 0..=127 {
+                    let semitones = note as f64 - 69.0 + offsets[note as usize % 12] / 100.0;
+                    tuning.set_frequency(note, 440.0 * f64::powf(2.0, semitones / 12.0));
+                }
+            },
+        }
+    }
+}
+
+/// Convert an MTS frequency (a semitone and a 14-bit fraction of a semitone above it) to Hz, or
+/// None if it's the reserved "no change" value.
+fn frequency_from_bytes(bytes: &[u8]) -> Option<Frequency> {
+    if bytes == [0x7F, 0x7F, 0x7F] {
+        return None;
+    }
+    let fraction = ((bytes[1] as u16) << 7 | bytes[2] as u16) as f64 / 16384.0;
+    let semitones = bytes[0] as f64 + fraction;
+    Some(440.0 * f64::powf(2.0, (semitones - 69.0) / 12.0))
+}
+
+/// Parse a bulk tuning dump, which contains a frequency for every note. The key-based version has
+/// a bank number before the tuning program number.
+fn parse_dump(data: &[u8], device: u8, sub_id: u8) -> Option<TuningMessage> {
+    let name_start = if sub_id == KEY_BASED_DUMP { 2 } else { 1 };
+    let tunings_start = name_start + NAME_LENGTH;
+    let tunings_end = tunings_start + 128 * 3;
+
+    let program = *data.get(name_start - 1)?;
+    let name = data.get(name_start..tunings_start)?;
+    let tunings = data.get(tunings_start..tunings_end)?;
+
+    // The checksum is an XOR of everything from the universal sysex byte up to the checksum. Some
+    // devices get this wrong, so only warn about it.
+    if let Some(&checksum) = data.get(tunings_end) {
+        let expected = [0x7E, device, MIDI_TUNING, sub_id].iter()
+            .chain(&data[..tunings_end])
+            .fold(0, |checksum, byte| checksum ^ byte) & 0x7F;
+        if checksum != expected {
+            log::warn!("MTS bulk dump has checksum {checksum:#04x}, expected {expected:#04x}");
+        }
+    }
+
+    Some(TuningMessage::Notes {
+        program,
+        name: Some(String::from_utf8_lossy(name).trim_end().to_string()),
+        frequencies: tunings.chunks_exact(3)
+            .enumerate()
+            .map(|(note, bytes)| (note as MidiNote, frequency_from_bytes(bytes)))
+            .collect(),
+    })
+}
+
+/// Parse a single note tuning change, with or without a bank number.
+fn parse_note_change(data: &[u8], has_bank: bool) -> Option<TuningMessage> {
+    let data = if has_bank { data.get(1..)? } else { data };
+    let program = *data.first()?;
+    let count = *data.get(1)? as usize;
+    let changes = data.get(2..2 + count * 4)?;
+
+    Some(TuningMessage::Notes {
+        program,
+        name: None,
+        frequencies: changes.chunks_exact(4)
+            .map(|change| (change[0] & 0x7F, frequency_from_bytes(&change[1..])))
+            .collect(),
+    })
+}
+
+/// Parse a scale/octave tuning message, with either 1 or 2 bytes per pitch class. These start
+/// with a three byte channel mask, which is ignored as the synth only has one channel.
+fn parse_octave(data: &[u8], bytes_per_offset: usize) -> Option<TuningMessage> {
+    let values = data.get(3..3 + 12 * bytes_per_offset)?;
+
+    let mut offsets = [0.0; 12];
+    for (offset, bytes) in offsets.iter_mut().zip(values.chunks_exact(bytes_per_offset)) {
+        *offset = match *bytes {
+            // 1 byte: 0x40 is no change, with 1 cent steps either side.
+            [value] => value as f64 - 64.0,
+            // 2 bytes: a 14-bit value where 0x2000 is no change and the range is +/- 100 cents.
+            [msb, lsb] => ((msb as u16) << 7 | lsb as u16) as f64 / 8192.0 * 100.0 - 100.0,
+            _ => return None,
+        };
+    }
+
+    Some(TuningMessage::Octave { offsets })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use midi_control::MidiMessage;
+
+    /// Parse a raw sysex message the same way messages from the midi device are parsed.
+    fn parse(bytes: &[u8]) -> Option<TuningMessage> {
+        match MidiMessage::from(bytes) {
+            MidiMessage::SysEx(event) => TuningMessage::parse(&event),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_frequency_from_bytes() {
+        assert_eq!(frequency_from_bytes(&[69, 0, 0]), Some(440.0));
+        assert_relative_eq!(frequency_from_bytes(&[69, 0x40, 0]).unwrap(), 440.0 * f64::powf(2.0, 0.5 / 12.0));
+        assert_eq!(frequency_from_bytes(&[0x7F, 0x7F, 0x7F]), None);
+    }
+
+    #[test]
+    fn test_single_note_change() {
+        // Retune A4 to a quarter tone sharp, and C4 to 440Hz.
+        let message = parse(&[0xF0, 0x7F, 0x7F, 0x08, 0x02, 0x00, 0x02,
+                              69, 69, 0x40, 0x00,
+                              60, 69, 0x00, 0x00,
+                              0xF7]).unwrap();
+        let mut tuning = Tuning::default();
+        message.apply(&mut tuning);
+        assert_relative_eq!(tuning.frequency(69).unwrap(), 440.0 * f64::powf(2.0, 0.5 / 12.0));
+        assert_relative_eq!(tuning.frequency(60).unwrap(), 440.0);
+        assert_relative_eq!(tuning.frequency(61).unwrap(), Tuning::default().frequency(61).unwrap());
+
+        // The banked version should do the same.
+        let banked = parse(&[0xF0, 0x7F, 0x7F, 0x08, 0x07, 0x00, 0x00, 0x02,
+                             69, 69, 0x40, 0x00,
+                             60, 69, 0x00, 0x00,
+                             0xF7]).unwrap();
+        assert_eq!(banked, message);
+    }
+
+    #[test]
+    fn test_bulk_dump() {
+        // Tune every note a semitone higher, except note 0 which is left unchanged.
+        let mut bytes = vec![0xF0, 0x7E, 0x00, 0x08, 0x01, 0x05];
+        bytes.extend_from_slice(b"Up a semitone   ");
+        bytes.extend_from_slice(&[0x7F, 0x7F, 0x7F]);
+        for note in 1..128u8 {
+            bytes.extend_from_slice(&[note.saturating_add(1).min(127), 0, 0]);
+        }
+        let checksum = bytes[1..].iter().fold(0, |checksum, byte| checksum ^ byte) & 0x7F;
+        bytes.extend_from_slice(&[checksum, 0xF7]);
+
+        let message = parse(&bytes).unwrap();
+        match &message {
+            TuningMessage::Notes { program, name, .. } => {
+                assert_eq!(*program, 5);
+                assert_eq!(name.as_deref(), Some("Up a semitone"));
+            },
+            _ => panic!("Expected a note tuning message"),
+        }
+
+        let mut tuning = Tuning::default();
+        message.apply(&mut tuning);
+        assert_relative_eq!(tuning.frequency(0).unwrap(), Tuning::default().frequency(0).unwrap());
+        assert_relative_eq!(tuning.frequency(60).unwrap(), Tuning::default().frequency(61).unwrap());
+
+        // A truncated dump should be ignored.
+        assert_eq!(parse(&bytes[..100]), None);
+    }
+
+    #[test]
+    fn test_octave_tuning() {
+        // Tune every E 14 cents flat and every A 10 cents sharp with 1 byte offsets.
+        let mut bytes = vec![0xF0, 0x7E, 0x7F, 0x08, 0x08, 0x03, 0x7F, 0x7F];
+        bytes.extend((0..12).map(|pitch_class| match pitch_class {
+            4 => 64 - 14,
+            9 => 64 + 10,
+            _ => 64,
+        }));
+        bytes.push(0xF7);
+
+        let mut tuning = Tuning::default();
+        parse(&bytes).unwrap().apply(&mut tuning);
+        assert_relative_eq!(tuning.frequency(64).unwrap(), Tuning::default().frequency(64).unwrap() * f64::powf(2.0, -14.0 / 1200.0));
+        assert_relative_eq!(tuning.frequency(81).unwrap(), 880.0 * f64::powf(2.0, 10.0 / 1200.0));
+        assert_relative_eq!(tuning.frequency(60).unwrap(), Tuning::default().frequency(60).unwrap());
+
+        // 2 byte offsets at the centre value should leave the tuning unchanged.
+        let mut bytes = vec![0xF0, 0x7F, 0x7F, 0x08, 0x09, 0x03, 0x7F, 0x7F];
+        bytes.extend(std::iter::repeat_n([0x40, 0x00], 12).flatten());
+        bytes.push(0xF7);
+        let mut tuning = Tuning::default();
+        parse(&bytes).unwrap().apply(&mut tuning);
+        assert_relative_eq!(tuning.frequency(64).unwrap(), Tuning::default().frequency(64).unwrap());
+    }
+
+    #[test]
+    fn test_other_sysex() {
+        // A non-tuning universal message should be ignored.
+        assert_eq!(parse(&[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7]), None);
+    }
+}
@@ -0,0 +1,227 @@
+//! Oscillators which combine two frequencies: hard sync, where a master oscillator resets the phase
+//! of a slave, and ring modulation.
+//!
+//! Resetting the slave's phase causes a jump in its output, which would alias badly if it was just
+//! sampled, so the sync oscillator smooths every jump with a polynomial band-limited step (PolyBLEP).
+//! This needs to correct the samples either side of the jump, so the output is one sample late.
+
+use std::f64::consts::PI;
+
+use crate::clock::Clock;
+use crate::signal::{Continuous, lift2, snapshot2};
+use crate::types::{Frequency, Sample, Time};
+
+/// The shape of a sync oscillator's slave, with an output from -1 to 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OscillatorShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+}
+
+impl OscillatorShape {
+    /// The value of the shape at the given phase, from 0 to 1.
+    pub fn value(&self, phase: f64) -> Sample {
+        match self {
+            OscillatorShape::Sine => f64::sin(2.0 * PI * phase),
+            OscillatorShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            OscillatorShape::Saw => 2.0 * phase - 1.0,
+            OscillatorShape::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+        }
+    }
+
+    /// The phases within a cycle where the shape jumps, and the size of the jump.
+    fn edges(&self) -> &'static [(f64, f64)] {
+        match self {
+            OscillatorShape::Sine | OscillatorShape::Triangle => &[],
+            OscillatorShape::Saw => &[(1.0, -2.0)],
+            OscillatorShape::Square => &[(0.5, -2.0), (1.0, 2.0)],
+        }
+    }
+}
+
+/// The PolyBLEP corrections for a jump of the given height, `offset` samples before the current
+/// sample. Returns the corrections for the previous and current samples.
+fn poly_blep(height: f64, offset: f64) -> (Sample, Sample) {
+    (height * offset * offset / 2.0, -height * (1.0 - offset) * (1.0 - offset) / 2.0)
+}
+
+/// An oscillator whose phase is reset every cycle of a master oscillator.
+#[derive(Clone, Debug)]
+pub struct SyncOscillator {
+    shape: OscillatorShape,
+    master_phase: f64,
+    phase: f64,
+    /// The current sample, which is output next time so that jumps can correct it.
+    current: Sample,
+}
+
+impl SyncOscillator {
+    /// Create a new sync oscillator with the given slave shape.
+    pub fn new(shape: OscillatorShape) -> Self {
+        Self {
+            shape,
+            master_phase: 0.0,
+            phase: 0.0,
+            current: shape.value(0.0),
+        }
+    }
+
+    /// Advance by one sample, returning the output from one sample ago.
+    pub fn step(&mut self, master_frequency: Frequency, frequency: Frequency, time_step: Time) -> Sample {
+        // Keep the increments below Nyquist, so there's at most one of each edge per sample.
+        let master_increment = (master_frequency * time_step).clamp(0.0, 0.5);
+        let increment = (frequency * time_step).clamp(0.0, 0.5);
+        let mut corrections = (0.0, 0.0);
+
+        // Find when the master wraps during this sample, in samples before the current one.
+        self.master_phase += master_increment;
+        let reset = (self.master_phase >= 1.0).then(|| {
+            self.master_phase -= 1.0;
+            self.master_phase / master_increment
+        });
+
+        match reset {
+            Some(offset) => {
+                // Run up to the reset, then jump back to the start of the cycle.
+                self.advance(increment, 1.0, offset, &mut corrections);
+                let height = self.shape.value(0.0) - self.shape.value(self.phase);
+                add(&mut corrections, poly_blep(height, offset));
+                self.phase = 0.0;
+                self.advance(increment, offset, 0.0, &mut corrections);
+            },
+            None => self.advance(increment, 1.0, 0.0, &mut corrections),
+        }
+
+        let output = self.current + corrections.0;
+        self.current = self.shape.value(self.phase) + corrections.1;
+        output
+    }
+
+    /// Advance the phase over part of a sample, from `start` to `end` samples before the current
+    /// sample, correcting any jumps in the shape along the way.
+    fn advance(&mut self, increment: f64, start: f64, end: f64, corrections: &mut (Sample, Sample)) {
+        let next = self.phase + increment * (start - end);
+        for &(edge, height) in self.shape.edges() {
+            if self.phase < edge && next >= edge {
+                let offset = start - (edge - self.phase) / increment;
+                add(corrections, poly_blep(height, offset));
+            }
+        }
+        self.phase = if next >= 1.0 { next - 1.0 } else { next };
+    }
+}
+
+/// Add a pair of corrections to the running totals.
+fn add(corrections: &mut (Sample, Sample), (previous, current): (Sample, Sample)) {
+    corrections.0 += previous;
+    corrections.1 += current;
+}
+
+/// Create a hard synced oscillator node, whose phase resets every cycle of the master frequency.
+/// Sweeping the slave frequency while the master follows the note gives the classic sync sound.
+pub fn hard_sync(time: &mut Continuous<Time>,
+                 master_frequency: &Continuous<Frequency>,
+                 frequency: &Continuous<Frequency>,
+                 shape: OscillatorShape)
+    -> Continuous<Sample>
+{
+    let mut clock = Clock::new();
+    let mut oscillator = SyncOscillator::new(shape);
+    snapshot2(time, master_frequency, frequency, move |time, master_frequency, frequency| {
+        let time_step = clock.tick(time);
+        oscillator.step(master_frequency, frequency, time_step)
+    })
+}
+
+/// Ring modulate two signals by multiplying them together, giving the sum and difference of their
+/// frequencies. The inputs should be centred around 0, for example `functions::sine_wave`, or the
+/// result also contains the original signals.
+pub fn ring_modulate(a: &mut Continuous<Sample>, b: &mut Continuous<Sample>) -> Continuous<Sample> {
+    lift2(a, b, |a, b| a * b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::functions::sine_wave;
+    use crate::signal::Discrete;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    fn run(oscillator: &mut SyncOscillator, master_frequency: Frequency, frequency: Frequency, length: usize) -> Vec<Sample> {
+        (0..length).map(|_| oscillator.step(master_frequency, frequency, 1.0 / SAMPLE_RATE)).collect()
+    }
+
+    /// The magnitude of a signal at the given frequency.
+    fn magnitude(signal: &[Sample], frequency: Frequency) -> f64 {
+        let (re, im) = signal.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, sample)| {
+            let angle = 2.0 * PI * frequency * n as f64 / SAMPLE_RATE;
+            (re + sample * angle.cos(), im - sample * angle.sin())
+        });
+        re.hypot(im) * 2.0 / signal.len() as f64
+    }
+
+    #[test]
+    fn test_free_running() {
+        // Without a master the slave is a band-limited saw, which matches the naive saw away from
+        // the jumps.
+        let mut oscillator = SyncOscillator::new(OscillatorShape::Saw);
+        let output = run(&mut oscillator, 0.0, 480.0, 200);
+        assert_relative_eq!(output[50], OscillatorShape::Saw.value(0.5), epsilon = 1e-9);
+        assert_relative_eq!(output[75], OscillatorShape::Saw.value(0.75), epsilon = 1e-9);
+        // A jump exactly on a sample should be halfway, with the samples either side unchanged.
+        assert_relative_eq!(output[100], 0.0, epsilon = 1e-6);
+        assert_relative_eq!(output[99], OscillatorShape::Saw.value(0.99), epsilon = 1e-6);
+        assert_relative_eq!(output[101], OscillatorShape::Saw.value(0.01), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn test_sync_period() {
+        // The output should repeat at the master frequency, whatever the slave's frequency.
+        let mut oscillator = SyncOscillator::new(OscillatorShape::Square);
+        let output = run(&mut oscillator, 100.0, 270.0, 2000);
+        for n in 500..1000 {
+            assert_relative_eq!(output[n], output[n + 480], epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_sync_aliasing() {
+        // The 30th harmonic of a 1100Hz sync sound aliases to 15kHz, away from any real harmonic.
+        let mut oscillator = SyncOscillator::new(OscillatorShape::Saw);
+        let band_limited = run(&mut oscillator, 1100.0, 2900.0, 48000);
+
+        let mut master_phase: f64 = 0.0;
+        let mut phase: f64 = 0.0;
+        let naive: Vec<Sample> = (0..48000).map(|_| {
+            master_phase += 1100.0 / SAMPLE_RATE;
+            phase += 2900.0 / SAMPLE_RATE;
+            if master_phase >= 1.0 {
+                master_phase -= 1.0;
+                phase = master_phase * 2900.0 / 1100.0;
+            }
+            phase = phase.fract();
+            OscillatorShape::Saw.value(phase)
+        }).collect();
+
+        assert!(magnitude(&band_limited, 15000.0) < magnitude(&naive, 15000.0) * 0.5);
+        assert_relative_eq!(magnitude(&band_limited, 1100.0), magnitude(&naive, 1100.0), max_relative = 0.05);
+    }
+
+    #[test]
+    fn test_ring_modulate() {
+        let mut time = Discrete::new();
+        let mut a = time.hold().map(|time| sine_wave(time, 100.0));
+        let mut b = time.hold().map(|time| sine_wave(time, 30.0));
+        let output = ring_modulate(&mut a, &mut b);
+
+        // The product of two sines is half the difference of cosines at the difference and sum
+        // frequencies.
+        time.push(0.001);
+        let expected = 0.5 * (f64::cos(2.0 * PI * 70.0 * 0.001) - f64::cos(2.0 * PI * 130.0 * 0.001));
+        assert_relative_eq!(output.sample().unwrap(), expected, epsilon = 1e-12);
+    }
+}
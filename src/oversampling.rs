@@ -0,0 +1,405 @@
+//! Oversampling, which runs part of a network at a multiple of the sample rate so that nonlinear
+//! processors and naive oscillators alias less.
+//!
+//! The signal is upsampled in stages of two with polyphase half-band filters, processed, and then
+//! filtered and decimated back down through the same stages. Half of a half-band filter's taps are
+//! zero, and splitting it into its two phases means those taps are never computed.
+
+use std::f64::consts::PI;
+
+use crate::clock::Clock;
+use crate::delay_line::DelayLine;
+use crate::signal::{Continuous, Discrete, snapshot1};
+use crate::types::{Sample, Time};
+
+/// How many samples the half-band filter of each stage spans either side of its centre, which must
+/// be even. The first stage has a narrow transition band around the original Nyquist frequency, but
+/// later stages only need to remove content far above the original band, so they can be shorter.
+const STAGE_HALF_LENGTHS: [usize; 3] = [32, 8, 8];
+
+/// How much a signal is oversampled by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OversamplingFactor {
+    X2,
+    X4,
+    X8,
+}
+
+impl OversamplingFactor {
+    /// The number of halving stages.
+    pub fn stages(self) -> usize {
+        match self {
+            OversamplingFactor::X2 => 1,
+            OversamplingFactor::X4 => 2,
+            OversamplingFactor::X8 => 3,
+        }
+    }
+
+    /// The multiple of the sample rate that the oversampled signal runs at.
+    pub fn ratio(self) -> usize {
+        1 << self.stages()
+    }
+}
+
+/// A Blackman windowed sinc half-band filter, split into its two phases. One phase is just the
+/// centre tap, which is a delay, and the other holds the taps an odd distance from the centre.
+#[derive(Clone, Debug)]
+pub struct HalfBand {
+    /// The taps an odd distance from the centre, in order of delay.
+    taps: Vec<f64>,
+    /// The input history of the phases, for the odd taps and the centre tap.
+    odd: DelayLine,
+    centre: DelayLine,
+}
+
+impl HalfBand {
+    /// Create a half-band filter spanning `half_length` samples either side of its centre, which
+    /// must be even. Every other tap is zero, so only `half_length` taps and the centre tap are
+    /// used.
+    pub fn new(half_length: usize) -> Self {
+        let length = 2 * half_length + 1;
+        let window = |n: f64| {
+            let x = PI * n / (half_length + 1) as f64;
+            0.42 + 0.5 * x.cos() + 0.08 * (2.0 * x).cos()
+        };
+
+        // The odd taps are normalised so that they sum to a half, like the centre tap, so that DC
+        // passes through both phases at exactly unity gain.
+        let mut taps: Vec<f64> = (0..half_length)
+            .map(|tap| {
+                let n = (2 * tap + 1) as f64 - half_length as f64;
+                window(n) * f64::sin(PI * n / 2.0) / (PI * n)
+            })
+            .collect();
+        let sum: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|tap| *tap *= 0.5 / sum);
+
+        Self {
+            taps,
+            odd: DelayLine::new(length),
+            centre: DelayLine::new(length),
+        }
+    }
+
+    /// How many samples the filter spans either side of its centre.
+    fn half_length(&self) -> usize {
+        self.taps.len()
+    }
+
+    /// The sum of the odd taps applied to the history of the odd phase.
+    fn convolve_odd(&self) -> Sample {
+        self.taps.iter().enumerate().map(|(delay, tap)| tap * self.odd.tap(delay)).sum()
+    }
+
+    /// Upsample a sample to two at twice the rate, with the image above the original Nyquist
+    /// frequency removed.
+    pub fn upsample(&mut self, input: Sample) -> [Sample; 2] {
+        self.odd.write(input);
+        [self.odd.tap(self.half_length() / 2), 2.0 * self.convolve_odd()]
+    }
+
+    /// Decimate two samples to one at half the rate, with everything above the new Nyquist
+    /// frequency removed first.
+    pub fn downsample(&mut self, input: [Sample; 2]) -> Sample {
+        self.odd.write(input[0]);
+        self.centre.write(input[1]);
+        self.convolve_odd() + 0.5 * self.centre.tap(self.half_length() / 2)
+    }
+
+    /// Clear the filter's history.
+    pub fn reset(&mut self) {
+        self.odd.clear();
+        self.centre.clear();
+    }
+}
+
+/// A stage of oversampling, which doubles the rate on the way up and halves it on the way down.
+#[derive(Clone, Debug)]
+struct Stage {
+    up: HalfBand,
+    down: HalfBand,
+}
+
+/// Runs a function at a multiple of the sample rate.
+#[derive(Clone, Debug)]
+pub struct Oversampler {
+    factor: OversamplingFactor,
+    stages: Vec<Stage>,
+    /// Buffers for the samples between stages, kept to avoid allocating every sample.
+    buffer: Vec<Sample>,
+    scratch: Vec<Sample>,
+}
+
+impl Oversampler {
+    /// Create an oversampler which runs at `factor` times the sample rate, with a stage of
+    /// half-band filters for each doubling.
+    pub fn new(factor: OversamplingFactor) -> Self {
+        let stages = STAGE_HALF_LENGTHS[..factor.stages()].iter()
+            .map(|&half_length| Stage { up: HalfBand::new(half_length), down: HalfBand::new(half_length) })
+            .collect();
+        Self {
+            factor,
+            stages,
+            buffer: Vec::with_capacity(factor.ratio()),
+            scratch: Vec::with_capacity(factor.ratio()),
+        }
+    }
+
+    /// How much the oversampler oversamples by.
+    pub fn factor(&self) -> OversamplingFactor {
+        self.factor
+    }
+
+    /// Upsample the input, call the function on each oversampled sample in turn along with its
+    /// index, and return the result decimated back to the original rate.
+    pub fn process<F: FnMut(usize, Sample) -> Sample>(&mut self, input: Sample, mut function: F) -> Sample {
+        self.buffer.clear();
+        self.buffer.push(input);
+        for stage in &mut self.stages {
+            self.scratch.clear();
+            self.scratch.extend(self.buffer.iter().flat_map(|&sample| stage.up.upsample(sample)));
+            std::mem::swap(&mut self.buffer, &mut self.scratch);
+        }
+
+        self.buffer.iter_mut().enumerate().for_each(|(index, sample)| *sample = function(index, *sample));
+        self.decimate()
+    }
+
+    /// Call the function for each oversampled sample in turn with its index, and return the
+    /// results decimated back to the original rate. This is for functions which generate a signal
+    /// rather than process one, so nothing is upsampled.
+    pub fn generate<F: FnMut(usize) -> Sample>(&mut self, function: F) -> Sample {
+        self.buffer.clear();
+        self.buffer.extend((0..self.factor.ratio()).map(function));
+        self.decimate()
+    }
+
+    /// Decimate the oversampled buffer back down to a single sample.
+    fn decimate(&mut self) -> Sample {
+        for stage in self.stages.iter_mut().rev() {
+            self.scratch.clear();
+            self.scratch.extend(self.buffer.chunks_exact(2).map(|pair| stage.down.downsample([pair[0], pair[1]])));
+            std::mem::swap(&mut self.buffer, &mut self.scratch);
+        }
+        self.buffer[0]
+    }
+
+    /// Clear the filters.
+    pub fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.up.reset();
+            stage.down.reset();
+        }
+    }
+}
+
+/// Create an oversampled node, which runs the sub-network made by `build` at a multiple of the
+/// sample rate and decimates its output back down.
+///
+/// The sub-network is given its own time signal, which steps at the oversampled rate, and the input
+/// signal upsampled to match. It can also use any other signal directly, such as a frequency, which
+/// is held for each oversampled step.
+pub fn oversample<F>(time: &mut Continuous<Time>,
+                     input: &Continuous<Sample>,
+                     factor: OversamplingFactor,
+                     build: F)
+    -> Continuous<Sample>
+where
+    F: FnOnce(&mut Continuous<Time>, &Continuous<Sample>) -> Continuous<Sample>,
+{
+    let mut inner_time = Discrete::new();
+    let mut inner_input = Discrete::new();
+    let output = build(&mut inner_time.hold(), &inner_input.hold());
+
+    let mut clock = Clock::new();
+    let mut oversampler = Oversampler::new(factor);
+    let ratio = factor.ratio() as f64;
+    snapshot1(time, input, move |time, input| {
+        let time_step = clock.tick(time);
+        if clock.sample_rate().is_none() {
+            // Start the sub-network's clocks, which can't step until the time step is known.
+            inner_input.push(input);
+            inner_time.push(time);
+            return 0.0;
+        }
+
+        // Push the input before the time, so that nodes stepped by the time see it.
+        oversampler.process(input, |index, sample| {
+            inner_input.push(sample);
+            inner_time.push(oversampled_time(time, time_step, index, ratio));
+            output.sample().unwrap_or(0.0)
+        })
+    })
+}
+
+/// Create an oversampled node for a sub-network with no input signal, such as an oscillator, which
+/// is run at a multiple of the sample rate and decimated back down. See `oversample`.
+pub fn oversample_source<F>(time: &mut Continuous<Time>, factor: OversamplingFactor, build: F) -> Continuous<Sample>
+where
+    F: FnOnce(&mut Continuous<Time>) -> Continuous<Sample>,
+{
+    let mut inner_time = Discrete::new();
+    let output = build(&mut inner_time.hold());
+
+    let mut clock = Clock::new();
+    let mut oversampler = Oversampler::new(factor);
+    let ratio = factor.ratio() as f64;
+    // The node only needs the clock, so it snapshots a constant.
+    snapshot1(time, &Continuous::constant(()), move |time, ()| {
+        let time_step = clock.tick(time);
+        if clock.sample_rate().is_none() {
+            inner_time.push(time);
+            return 0.0;
+        }
+
+        oversampler.generate(|index| {
+            inner_time.push(oversampled_time(time, time_step, index, ratio));
+            output.sample().unwrap_or(0.0)
+        })
+    })
+}
+
+/// The time of an oversampled step within the sample ending at `time`, where the last step is at
+/// `time` itself.
+fn oversampled_time(time: Time, time_step: Time, index: usize, ratio: f64) -> Time {
+    time - time_step + time_step * (index + 1) as f64 / ratio
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::functions::sine_wave;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    /// The RMS level of the second half of a signal.
+    fn settled_rms(signal: &[Sample]) -> f64 {
+        let settled = &signal[signal.len() / 2..];
+        (settled.iter().map(|sample| sample * sample).sum::<f64>() / settled.len() as f64).sqrt()
+    }
+
+    /// The magnitude of a frequency in a signal, from a single bin of its discrete Fourier
+    /// transform.
+    fn magnitude(signal: &[Sample], frequency: f64, sample_rate: f64) -> f64 {
+        let (re, im) = signal.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, sample)| {
+            let phase = 2.0 * PI * frequency * n as f64 / sample_rate;
+            (re + sample * phase.cos(), im - sample * phase.sin())
+        });
+        2.0 * (re * re + im * im).sqrt() / signal.len() as f64
+    }
+
+    #[test]
+    fn test_half_band() {
+        // A half-band filter passes DC at unity gain through both phases.
+        let mut filter = HalfBand::new(32);
+        let up = (0..100).map(|_| filter.upsample(1.0)).last().unwrap();
+        assert_relative_eq!(up[0], 1.0, epsilon = 1e-12);
+        assert_relative_eq!(up[1], 1.0, epsilon = 1e-12);
+        let mut filter = HalfBand::new(32);
+        let down = (0..100).map(|_| filter.downsample([1.0, 1.0])).last().unwrap();
+        assert_relative_eq!(down, 1.0, epsilon = 1e-12);
+
+        // Decimating keeps the band below the new Nyquist frequency, and removes what's above it.
+        let decimate = |frequency: f64| {
+            let mut filter = HalfBand::new(32);
+            let rate = 2.0 * SAMPLE_RATE;
+            let output: Vec<Sample> = (0..4800)
+                .map(|n| filter.downsample([sine_wave((2 * n) as f64 / rate, frequency), sine_wave((2 * n + 1) as f64 / rate, frequency)]))
+                .collect();
+            settled_rms(&output) * std::f64::consts::SQRT_2
+        };
+        assert_relative_eq!(decimate(1000.0), 1.0, epsilon = 0.01);
+        assert_relative_eq!(decimate(0.35 * SAMPLE_RATE), 1.0, epsilon = 0.01);
+        assert!(decimate(0.65 * SAMPLE_RATE) < 1e-3);
+        assert!(decimate(0.9 * SAMPLE_RATE) < 1e-3);
+    }
+
+    #[test]
+    fn test_passthrough() {
+        // With nothing in between, a signal in the audio band comes back out at the same level.
+        for factor in [OversamplingFactor::X2, OversamplingFactor::X4, OversamplingFactor::X8] {
+            let mut oversampler = Oversampler::new(factor);
+            let mut calls = 0;
+            let output: Vec<Sample> = (0..4800)
+                .map(|n| oversampler.process(sine_wave(n as f64 / SAMPLE_RATE, 5000.0), |_, sample| {
+                    calls += 1;
+                    sample
+                }))
+                .collect();
+            assert_eq!(calls, 4800 * factor.ratio());
+            assert_relative_eq!(settled_rms(&output) * std::f64::consts::SQRT_2, 1.0, epsilon = 0.01);
+        }
+    }
+
+    #[test]
+    fn test_aliasing() {
+        // Hard clipping a 7kHz sine makes a 5th harmonic at 35kHz, which aliases to 13kHz unless
+        // it's oversampled.
+        let clip = |factor: Option<OversamplingFactor>| {
+            let mut oversampler = factor.map(Oversampler::new);
+            let output: Vec<Sample> = (0..9600)
+                .map(|n| {
+                    let input = sine_wave(n as f64 / SAMPLE_RATE, 7000.0);
+                    let clip = |_, sample: Sample| (sample * 4.0).clamp(-1.0, 1.0);
+                    match &mut oversampler {
+                        Some(oversampler) => oversampler.process(input, clip),
+                        None => clip(0, input),
+                    }
+                })
+                .collect();
+            let settled = &output[4800..];
+            (magnitude(settled, 7000.0, SAMPLE_RATE), magnitude(settled, 13000.0, SAMPLE_RATE))
+        };
+
+        let (naive_fundamental, naive_alias) = clip(None);
+        let (fundamental, alias) = clip(Some(OversamplingFactor::X4));
+        assert_relative_eq!(fundamental, naive_fundamental, max_relative = 0.05);
+        assert!(naive_alias > 0.05);
+        assert!(alias < naive_alias * 0.01, "{alias} vs {naive_alias}");
+    }
+
+    #[test]
+    fn test_node() {
+        // The sub-network should see the oversampled rate, and its output should be decimated back.
+        let mut time = Discrete::new();
+        let mut input = Discrete::new();
+        let input_signal = input.hold();
+        let output = oversample(&mut time.hold(), &input_signal, OversamplingFactor::X4, |time, input| {
+            let mut clock = Clock::new();
+            snapshot1(time, input, move |time, input| {
+                clock.tick(time);
+                clock.sample_rate().unwrap_or(0.0) * input
+            })
+        });
+
+        for n in 0..200 {
+            input.push(0.001);
+            time.push(n as f64 / SAMPLE_RATE);
+        }
+        assert_relative_eq!(output.sample().unwrap(), 4.0 * SAMPLE_RATE * 0.001, max_relative = 1e-9);
+    }
+
+    #[test]
+    fn test_source_node() {
+        // A sub-network without an input should also see the oversampled rate, and its output
+        // should come back out at the same level.
+        let mut time = Discrete::new();
+        let output = oversample_source(&mut time.hold(), OversamplingFactor::X2, |time| {
+            let mut clock = Clock::new();
+            snapshot1(time, &Continuous::constant(()), move |time, ()| {
+                clock.tick(time);
+                clock.sample_rate().map_or(0.0, |sample_rate| sample_rate / (2.0 * SAMPLE_RATE))
+            })
+        });
+
+        for n in 0..200 {
+            time.push(n as f64 / SAMPLE_RATE);
+        }
+        assert_relative_eq!(output.sample().unwrap(), 1.0, max_relative = 1e-9);
+        let mut oversampler = Oversampler::new(OversamplingFactor::X8);
+        assert_eq!(oversampler.factor(), OversamplingFactor::X8);
+        let output = (0..200).map(|_| oversampler.generate(|_| 0.5)).last().unwrap();
+        assert_relative_eq!(output, 0.5, epsilon = 1e-9);
+    }
+}
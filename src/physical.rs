@@ -0,0 +1,395 @@
+//! Physical models of strings and tubes, built from delay line waveguides.
+//!
+//! A waveguide simulates waves travelling along a string or through the air in a tube with delay
+//! lines, reflecting them at the ends. Energy and high frequencies are lost a little every time a
+//! wave goes round, which is lumped into a single loop filter, controlled by the damping and
+//! brightness. The models are excited by an input signal, such as a burst of noise for a pluck or
+//! the breath pressure for a reed.
+
+use crate::clock::Clock;
+use crate::delay_line::{AllpassInterpolator, DelayLine};
+use crate::random::Rng;
+use crate::signal::{Continuous, snapshot1, snapshot4};
+use crate::types::{Frequency, Sample, Time};
+
+/// The lowest frequency the models can play, which sets the size of their delay lines.
+const MIN_FREQUENCY: Frequency = 20.0;
+
+/// How long it takes a sound to die away by 60dB at no damping and full damping.
+const MAX_DECAY: Time = 10.0;
+const MIN_DECAY: Time = 0.05;
+
+/// A physical model that can be played by a waveguide node.
+pub trait Waveguide {
+    /// Process a single sample of excitation. The damping and brightness are from 0 to 1.
+    fn process(&mut self, excitation: Sample, frequency: Frequency, damping: f64, brightness: f64, sample_rate: f64) -> Sample;
+
+    /// Silence the model.
+    fn reset(&mut self);
+}
+
+/// The filter in a waveguide's loop, a one zero low-pass which averages neighbouring samples more
+/// as the brightness goes down.
+#[derive(Clone, Debug, Default)]
+struct LoopFilter {
+    previous: Sample,
+}
+
+impl LoopFilter {
+    fn process(&mut self, input: Sample, brightness: f64) -> Sample {
+        let weight = Self::delay(brightness);
+        let output = (1.0 - weight) * input + weight * self.previous;
+        self.previous = input;
+        output
+    }
+
+    /// The delay of the filter at low frequencies, in samples, which is taken out of the loop's
+    /// delay line to keep it in tune.
+    fn delay(brightness: f64) -> f64 {
+        0.5 * (1.0 - brightness.clamp(0.0, 1.0))
+    }
+}
+
+/// The gain to apply every `period` samples so that the sound dies away by 60dB over a time set by
+/// the damping, from `MAX_DECAY` to `MIN_DECAY`.
+fn loop_gain(period: f64, damping: f64, sample_rate: f64) -> f64 {
+    let decay = MAX_DECAY * f64::powf(MIN_DECAY / MAX_DECAY, damping.clamp(0.0, 1.0));
+    f64::powf(10.0, -3.0 * period / (decay * sample_rate))
+}
+
+/// The period of a frequency in samples, limited to what the models can play.
+fn period(frequency: Frequency, sample_rate: f64) -> f64 {
+    sample_rate / frequency.clamp(MIN_FREQUENCY, sample_rate / 4.0)
+}
+
+/// The length of delay line needed to play the lowest frequency.
+fn capacity(sample_rate: f64) -> usize {
+    (sample_rate / MIN_FREQUENCY).ceil() as usize + 2
+}
+
+/// The Karplus-Strong plucked string: a single delay line one period long, fed back through the
+/// loop filter.
+#[derive(Clone, Debug, Default)]
+pub struct KarplusStrong {
+    delay_line: DelayLine,
+    interpolator: AllpassInterpolator,
+    filter: LoopFilter,
+}
+
+impl KarplusStrong {
+    /// Create a silent string, whose delay line is sized on the first sample.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Waveguide for KarplusStrong {
+    fn process(&mut self, excitation: Sample, frequency: Frequency, damping: f64, brightness: f64, sample_rate: f64) -> Sample {
+        self.delay_line.resize(capacity(sample_rate));
+        let period = period(frequency, sample_rate);
+
+        // Reading before writing adds a sample of delay.
+        let output = self.interpolator.read(&self.delay_line, period - LoopFilter::delay(brightness) - 1.0);
+        let feedback = self.filter.process(output, brightness) * loop_gain(period, damping, sample_rate);
+        self.delay_line.write(feedback + excitation);
+        output
+    }
+
+    fn reset(&mut self) {
+        self.delay_line.clear();
+        self.interpolator.reset();
+        self.filter = LoopFilter::default();
+    }
+}
+
+/// A string with waves travelling in both directions between the nut and the bridge, where they
+/// reflect inverted. Unlike the Karplus-Strong string it can be excited and picked up anywhere
+/// along its length, which changes its tone: exciting it in the middle cancels the even harmonics.
+#[derive(Clone, Debug)]
+pub struct WaveguideString {
+    /// Where the string is excited, from 0 (at the nut) to 1 (at the bridge).
+    pub excitation_position: f64,
+    /// Where the string's vibration is picked up, from 0 to 1.
+    pub pickup_position: f64,
+    /// The waves travelling towards the bridge and towards the nut.
+    right: DelayLine,
+    left: DelayLine,
+    interpolator: AllpassInterpolator,
+    filter: LoopFilter,
+}
+
+impl WaveguideString {
+    /// Create a silent string excited and picked up at the given positions along it, from 0 to 1.
+    pub fn new(excitation_position: f64, pickup_position: f64) -> Self {
+        Self {
+            excitation_position,
+            pickup_position,
+            right: DelayLine::default(),
+            left: DelayLine::default(),
+            interpolator: AllpassInterpolator::new(),
+            filter: LoopFilter::default(),
+        }
+    }
+}
+
+impl Waveguide for WaveguideString {
+    fn process(&mut self, excitation: Sample, frequency: Frequency, damping: f64, brightness: f64, sample_rate: f64) -> Sample {
+        self.right.resize(capacity(sample_rate));
+        self.left.resize(capacity(sample_rate));
+        let period = period(frequency, sample_rate);
+
+        // A wave goes along the string and back once per period. The left rail has a whole number
+        // of samples, and the right rail has the rest, read with the interpolator.
+        let length = period - LoopFilter::delay(brightness);
+        let left_length = (length / 2.0).floor();
+        let right_length = length - left_length;
+        let at_bridge = self.interpolator.read(&self.right, right_length - 1.0);
+        let at_nut = self.left.tap(left_length as usize - 1);
+
+        // Both ends reflect inverted, with the losses lumped together at the bridge.
+        let reflected = self.filter.process(at_bridge, brightness) * loop_gain(period, damping, sample_rate);
+        self.left.write(-reflected);
+        self.right.write(-at_nut);
+
+        // Half of the excitation travels in each direction.
+        let position = self.excitation_position.clamp(0.0, 1.0);
+        self.right.add((position * right_length) as usize, excitation * 0.5);
+        self.left.add(((1.0 - position) * left_length) as usize, excitation * 0.5);
+
+        // The string's displacement is the sum of the waves.
+        let pickup = self.pickup_position.clamp(0.0, 1.0);
+        self.right.tap((pickup * right_length) as usize) + self.left.tap(((1.0 - pickup) * left_length) as usize)
+    }
+
+    fn reset(&mut self) {
+        self.right.clear();
+        self.left.clear();
+        self.interpolator.reset();
+        self.filter = LoopFilter::default();
+    }
+}
+
+/// A cylindrical tube blown through a reed, like a clarinet. The excitation is the breath pressure,
+/// from 0 to 1, and the tube only sounds when it's blown hard enough.
+///
+/// The reed lets air in depending on the pressure difference across it, which is a nonlinear
+/// function of the pressure wave coming back from the bell. The tube is closed at the reed, so a
+/// wave goes along it and back twice per period, and it only has odd harmonics.
+#[derive(Clone, Debug)]
+pub struct WaveguideTube {
+    /// How open the reed is at rest, and how much it closes as the pressure difference increases.
+    pub reed_offset: f64,
+    pub reed_slope: f64,
+    bore: DelayLine,
+    interpolator: AllpassInterpolator,
+    filter: LoopFilter,
+}
+
+impl WaveguideTube {
+    /// Create a silent tube with a reed that's fairly open at rest.
+    pub fn new() -> Self {
+        Self {
+            reed_offset: 0.7,
+            reed_slope: -0.3,
+            bore: DelayLine::default(),
+            interpolator: AllpassInterpolator::new(),
+            filter: LoopFilter::default(),
+        }
+    }
+}
+
+impl Default for WaveguideTube {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Waveguide for WaveguideTube {
+    fn process(&mut self, excitation: Sample, frequency: Frequency, damping: f64, brightness: f64, sample_rate: f64) -> Sample {
+        self.bore.resize(capacity(sample_rate));
+        let round_trip = period(frequency, sample_rate) / 2.0;
+
+        // The open bell reflects the wave inverted.
+        let output = self.interpolator.read(&self.bore, round_trip - LoopFilter::delay(brightness) - 1.0);
+        let reflected = -self.filter.process(output, brightness) * loop_gain(round_trip, damping, sample_rate);
+
+        // The reed lets through more of the breath the smaller the pressure difference.
+        let difference = reflected - excitation;
+        let reed = (self.reed_offset + self.reed_slope * difference).clamp(-1.0, 1.0);
+        self.bore.write(excitation + difference * reed);
+        output
+    }
+
+    fn reset(&mut self) {
+        self.bore.clear();
+        self.interpolator.reset();
+        self.filter = LoopFilter::default();
+    }
+}
+
+/// Create a physical model node, which plays the model excited by the input signal.
+pub fn waveguide<W>(time: &mut Continuous<Time>,
+                    excitation: &Continuous<Sample>,
+                    frequency: &Continuous<Frequency>,
+                    damping: &Continuous<f64>,
+                    brightness: &Continuous<f64>,
+                    mut model: W)
+    -> Continuous<Sample>
+where
+    W: Waveguide + Send + Sync + 'static,
+{
+    let mut clock = Clock::new();
+    snapshot4(time, excitation, frequency, damping, brightness, move |time, excitation, frequency, damping, brightness| {
+        clock.tick(time);
+        match clock.sample_rate() {
+            Some(sample_rate) => model.process(excitation, frequency, damping, brightness, sample_rate),
+            None => 0.0,
+        }
+    })
+}
+
+/// Create a noise burst node, which plays white noise for `length` seconds every time the gate
+/// opens, for plucking or striking a physical model.
+pub fn noise_burst(time: &mut Continuous<Time>, gate: &Continuous<bool>, length: Time, seed: u64) -> Continuous<Sample> {
+    let mut clock = Clock::new();
+    let mut rng = Rng::new(seed);
+    let mut previous_gate = false;
+    let mut remaining = 0.0;
+    snapshot1(time, gate, move |time, gate| {
+        let time_step = clock.tick(time);
+        if gate && !previous_gate {
+            remaining = length;
+        }
+        previous_gate = gate;
+
+        if remaining > 0.0 {
+            remaining -= time_step;
+            rng.next_bipolar()
+        } else {
+            0.0
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::signal::Discrete;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    /// Run a model from an impulse.
+    fn impulse_response<W: Waveguide>(model: &mut W, frequency: Frequency, damping: f64, brightness: f64, length: usize) -> Vec<Sample> {
+        (0..length).map(|n| {
+            let excitation = if n == 0 { 1.0 } else { 0.0 };
+            model.process(excitation, frequency, damping, brightness, SAMPLE_RATE)
+        }).collect()
+    }
+
+    /// Estimate the frequency of a signal from the average time between upward zero crossings.
+    fn zero_crossing_frequency(signal: &[Sample]) -> Frequency {
+        let crossings: Vec<usize> = (1..signal.len()).filter(|&n| signal[n - 1] < 0.0 && signal[n] >= 0.0).collect();
+        let periods = (crossings.len() - 1) as f64;
+        SAMPLE_RATE * periods / (crossings[crossings.len() - 1] - crossings[0]) as f64
+    }
+
+    /// Estimate the frequency of a signal from the peak of its autocorrelation between the given
+    /// periods, refined with a parabola through the neighbouring lags.
+    fn autocorrelation_frequency(signal: &[Sample], periods: std::ops::Range<usize>) -> Frequency {
+        let correlation = |lag: usize| (0..signal.len() - lag).map(|n| signal[n] * signal[n + lag]).sum::<f64>();
+        let lag = periods.max_by(|&a, &b| correlation(a).total_cmp(&correlation(b))).unwrap();
+        let (before, peak, after) = (correlation(lag - 1), correlation(lag), correlation(lag + 1));
+        let offset = 0.5 * (before - after) / (before - 2.0 * peak + after);
+        SAMPLE_RATE / (lag as f64 + offset)
+    }
+
+    #[test]
+    fn test_karplus_strong() {
+        // With full brightness and a whole number of samples per period, the impulse should come
+        // round exactly once every period, getting quieter each time.
+        let mut string = KarplusStrong::new();
+        let output = impulse_response(&mut string, SAMPLE_RATE / 100.0, 0.0, 1.0, 301);
+        let gain = loop_gain(100.0, 0.0, SAMPLE_RATE);
+        assert_relative_eq!(output[100], 1.0, epsilon = 1e-9);
+        assert_relative_eq!(output[200], gain, epsilon = 1e-9);
+        assert_relative_eq!(output[300], gain * gain, epsilon = 1e-9);
+        assert_relative_eq!(output[150], 0.0, epsilon = 1e-9);
+
+        // More damping should make it die away faster.
+        let decay = |damping| {
+            let mut string = KarplusStrong::new();
+            let output = impulse_response(&mut string, 220.0, damping, 0.5, 48000);
+            output[24000..].iter().map(|sample| sample * sample).sum::<f64>()
+        };
+        assert!(decay(0.8) < decay(0.2) * 0.01);
+    }
+
+    #[test]
+    fn test_karplus_strong_tuning() {
+        // A fractional period should still be in tune.
+        let mut string = KarplusStrong::new();
+        let excitation: Vec<Sample> = {
+            let mut rng = Rng::new(1);
+            (0..200).map(|_| rng.next_bipolar()).collect()
+        };
+        let output: Vec<Sample> = (0..48000).map(|n| {
+            string.process(excitation.get(n).copied().unwrap_or(0.0), 440.0, 0.1, 0.2, SAMPLE_RATE)
+        }).collect();
+        assert_relative_eq!(autocorrelation_frequency(&output[4800..24000], 80..140), 440.0, max_relative = 0.002);
+    }
+
+    #[test]
+    fn test_waveguide_string() {
+        // Plucking in the middle should cancel the even harmonics, so each half period is the
+        // inverse of the one before.
+        let mut string = WaveguideString::new(0.5, 0.3);
+        let output = impulse_response(&mut string, SAMPLE_RATE / 100.0, 0.0, 1.0, 1000);
+        assert!(output.iter().any(|sample| sample.abs() > 0.1));
+        for n in 200..900 {
+            assert_relative_eq!(output[n + 50], -output[n], epsilon = 0.02);
+        }
+
+        // Plucking near the end should keep them, and be periodic.
+        let mut string = WaveguideString::new(0.1, 0.3);
+        let output = impulse_response(&mut string, SAMPLE_RATE / 100.0, 0.0, 1.0, 1000);
+        assert!((200..900).any(|n| (output[n + 50] + output[n]).abs() > 0.1));
+        for n in 200..800 {
+            assert_relative_eq!(output[n + 100], output[n], epsilon = 0.02);
+        }
+    }
+
+    #[test]
+    fn test_waveguide_tube() {
+        let run = |breath| {
+            let mut tube = WaveguideTube::new();
+            (0..48000).map(|_| tube.process(breath, 220.0, 0.1, 0.5, SAMPLE_RATE)).collect::<Vec<_>>()
+        };
+
+        // Blowing hard enough should make the tube sound at its frequency.
+        let output = run(0.8);
+        let peak = output[24000..].iter().fold(0.0, |peak: f64, sample| peak.max(sample.abs()));
+        assert!(peak > 0.1);
+        assert_relative_eq!(zero_crossing_frequency(&output[24000..]), 220.0, max_relative = 0.02);
+
+        // Without breath it should be silent.
+        assert!(run(0.0).iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn test_noise_burst() {
+        let mut time = Discrete::new();
+        let mut gate = Discrete::new();
+        let burst = noise_burst(&mut time.hold(), &gate.hold(), 0.0035, 1);
+
+        let mut output = Vec::new();
+        for n in 0..10 {
+            gate.push(n < 8);
+            time.push(n as f64 * 0.001);
+            output.push(burst.sample().unwrap());
+        }
+        // The first tick has no time step, so the burst lasts one sample longer.
+        assert!(output[..5].iter().all(|&sample| sample != 0.0));
+        assert!(output[5..].iter().all(|&sample| sample == 0.0));
+    }
+}
@@ -0,0 +1,50 @@
+//! A small, fast pseudo-random number generator for noise and randomised parameters.
+//!
+//! This is an xorshift64* generator, which isn't suitable for anything security related, but is
+//! cheap enough to call every sample and deterministic for a given seed so it can be tested.
+
+/// A pseudo-random number generator.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a new generator with the given seed.
+    pub fn new(seed: u64) -> Self {
+        // The state must never be zero, so mix the seed and make sure of it.
+        let state = (seed ^ 0x9E37_79B9_7F4A_7C15).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        Self {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    /// Generate the next random integer.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Generate a random number in the range [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Generate a random number in the range [-1, 1), e.g. a white noise sample.
+    pub fn next_bipolar(&mut self) -> f64 {
+        self.next_f64() * 2.0 - 1.0
+    }
+
+    /// Generate a random number in the range [min, max).
+    pub fn range(&mut self, min: f64, max: f64) -> f64 {
+        min + (max - min) * self.next_f64()
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
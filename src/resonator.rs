@@ -0,0 +1,295 @@
+//! Banks of tuned resonators, for bells, bars and metallic sounds.
+//!
+//! A modal bank models an object by its modes of vibration: each mode is a resonant filter which
+//! rings at a multiple of the note's frequency, with its own level and decay. A comb bank instead
+//! uses feedback comb filters, each of which rings at a frequency and all of its harmonics, which
+//! gives a denser and more metallic sound. Both are excited by an input signal, such as an impulse
+//! or a burst of noise.
+
+use std::f64::consts::PI;
+
+use crate::clock::Clock;
+use crate::delay_line::{AllpassInterpolator, DelayLine};
+use crate::signal::{Continuous, snapshot2};
+use crate::tuning::Tuning;
+use crate::types::{Frequency, MidiNote, Sample, Time};
+
+/// The highest a mode can ring, as a proportion of the sample rate. Modes above this are silent.
+const MAX_MODE_FREQUENCY: f64 = 0.45;
+
+/// The lowest frequency the comb filters can play, which sets the size of their delay lines.
+const MIN_COMB_FREQUENCY: Frequency = 20.0;
+
+/// A bank of resonators that can be played by a resonator node.
+pub trait Resonator {
+    /// Process a single sample of excitation, with the resonators tuned relative to the frequency.
+    fn process(&mut self, excitation: Sample, frequency: Frequency, sample_rate: f64) -> Sample;
+
+    /// Silence the resonators.
+    fn reset(&mut self);
+}
+
+/// The gain to apply every `period` samples so that a resonator dies away by 60dB over `decay`
+/// seconds.
+fn decay_gain(period: f64, decay: Time, sample_rate: f64) -> f64 {
+    if decay > 0.0 { f64::powf(10.0, -3.0 * period / (decay * sample_rate)) } else { 0.0 }
+}
+
+/// The ratios of the frequencies of some notes to that of a root note in a tuning, for tuning the
+/// resonators in a bank to a chord or scale. Notes which aren't mapped in the tuning have a ratio
+/// of 0, which is silent.
+pub fn note_ratios(tuning: &Tuning, root: MidiNote, notes: &[MidiNote]) -> Vec<f64> {
+    let root_frequency = tuning.frequency(root);
+    notes.iter()
+        .map(|&note| root_frequency.zip(tuning.frequency(note)).map_or(0.0, |(root, note)| note / root))
+        .collect()
+}
+
+/// A single mode of vibration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mode {
+    /// The frequency of the mode as a multiple of the note's frequency.
+    pub ratio: f64,
+    /// The level the mode rings at when struck by an impulse.
+    pub amplitude: f64,
+    /// How long the mode takes to die away by 60dB, in seconds.
+    pub decay: Time,
+}
+
+impl Mode {
+    pub fn new(ratio: f64, amplitude: f64, decay: Time) -> Self {
+        Self { ratio, amplitude, decay }
+    }
+}
+
+/// A bank of two pole resonant filters, one for each mode.
+#[derive(Clone, Debug)]
+pub struct ModalBank {
+    pub modes: Vec<Mode>,
+    /// The last two outputs of each mode's filter.
+    states: Vec<[Sample; 2]>,
+}
+
+impl ModalBank {
+    /// Create a bank with the given modes.
+    pub fn new(modes: Vec<Mode>) -> Self {
+        let states = vec![[0.0; 2]; modes.len()];
+        Self { modes, states }
+    }
+
+    /// Create a bank with a mode for each of the ratios, all at the same level and decay.
+    pub fn from_ratios(ratios: &[f64], decay: Time) -> Self {
+        let amplitude = 1.0 / ratios.len().max(1) as f64;
+        Self::new(ratios.iter().map(|&ratio| Mode::new(ratio, amplitude, decay)).collect())
+    }
+
+    /// The partials of a church bell, from the hum note an octave below the strike note up to the
+    /// bright upper partials, which die away first.
+    pub fn bell() -> Self {
+        Self::new(vec![
+            Mode::new(0.5, 0.25, 6.0),
+            Mode::new(1.0, 0.2, 4.0),
+            Mode::new(1.183, 0.15, 3.5),
+            Mode::new(1.506, 0.1, 3.0),
+            Mode::new(2.0, 0.12, 2.5),
+            Mode::new(2.514, 0.08, 2.0),
+            Mode::new(2.662, 0.06, 1.5),
+            Mode::new(3.011, 0.05, 1.2),
+            Mode::new(4.166, 0.04, 0.8),
+        ])
+    }
+
+    /// A marimba bar, which is cut away underneath so that its first two overtones are tuned two
+    /// octaves and about three and a half octaves above the fundamental.
+    pub fn marimba() -> Self {
+        Self::new(vec![
+            Mode::new(1.0, 0.6, 0.8),
+            Mode::new(3.99, 0.25, 0.25),
+            Mode::new(10.65, 0.1, 0.08),
+        ])
+    }
+
+    /// A uniform metal bar free at both ends, like a glockenspiel, with its inharmonic overtones.
+    pub fn metal_bar() -> Self {
+        Self::new(vec![
+            Mode::new(1.0, 0.5, 2.5),
+            Mode::new(2.756, 0.25, 1.2),
+            Mode::new(5.404, 0.15, 0.6),
+            Mode::new(8.933, 0.1, 0.3),
+        ])
+    }
+}
+
+impl Resonator for ModalBank {
+    fn process(&mut self, excitation: Sample, frequency: Frequency, sample_rate: f64) -> Sample {
+        self.states.resize(self.modes.len(), [0.0; 2]);
+        self.modes.iter().zip(&mut self.states).map(|(mode, state)| {
+            let mode_frequency = frequency * mode.ratio;
+            if mode_frequency <= 0.0 || mode_frequency >= MAX_MODE_FREQUENCY * sample_rate {
+                *state = [0.0; 2];
+                return 0.0;
+            }
+
+            // The input is scaled by sin(w), so that an impulse rings as a sine wave with the
+            // mode's amplitude.
+            let w = 2.0 * PI * mode_frequency / sample_rate;
+            let r = decay_gain(1.0, mode.decay, sample_rate);
+            let output = 2.0 * r * w.cos() * state[0] - r * r * state[1] + excitation * mode.amplitude * w.sin();
+            *state = [output, state[0]];
+            output
+        }).sum()
+    }
+
+    fn reset(&mut self) {
+        self.states.iter_mut().for_each(|state| *state = [0.0; 2]);
+    }
+}
+
+/// A single feedback comb filter.
+#[derive(Clone, Debug, Default)]
+struct Comb {
+    delay_line: DelayLine,
+    interpolator: AllpassInterpolator,
+}
+
+/// A bank of feedback comb filters in parallel, each ringing at a multiple of the note's frequency
+/// and all of its harmonics.
+#[derive(Clone, Debug)]
+pub struct CombBank {
+    /// The frequency of each comb filter as a multiple of the note's frequency.
+    pub ratios: Vec<f64>,
+    /// How long the combs take to die away by 60dB, in seconds.
+    pub decay: Time,
+    combs: Vec<Comb>,
+}
+
+impl CombBank {
+    /// Create a bank with a comb filter for each of the ratios.
+    pub fn new(ratios: Vec<f64>, decay: Time) -> Self {
+        let combs = vec![Comb::default(); ratios.len()];
+        Self { ratios, decay, combs }
+    }
+}
+
+impl Resonator for CombBank {
+    fn process(&mut self, excitation: Sample, frequency: Frequency, sample_rate: f64) -> Sample {
+        self.combs.resize(self.ratios.len(), Comb::default());
+        let capacity = (sample_rate / MIN_COMB_FREQUENCY).ceil() as usize + 2;
+        let gain = 1.0 / self.ratios.len().max(1) as f64;
+        self.ratios.iter().zip(&mut self.combs).map(|(ratio, comb)| {
+            comb.delay_line.resize(capacity);
+            let comb_frequency = frequency * ratio;
+            if comb_frequency <= 0.0 {
+                return 0.0;
+            }
+
+            // Reading before writing adds a sample of delay.
+            let period = sample_rate / comb_frequency.clamp(MIN_COMB_FREQUENCY, sample_rate / 4.0);
+            let output = comb.interpolator.read(&comb.delay_line, period - 1.0);
+            comb.delay_line.write(excitation + output * decay_gain(period, self.decay, sample_rate));
+            output * gain
+        }).sum()
+    }
+
+    fn reset(&mut self) {
+        for comb in &mut self.combs {
+            comb.delay_line.clear();
+            comb.interpolator.reset();
+        }
+    }
+}
+
+/// Create a resonator node, which plays the resonators excited by the input signal and tuned to the
+/// frequency.
+pub fn resonator<R>(time: &mut Continuous<Time>,
+                    excitation: &Continuous<Sample>,
+                    frequency: &Continuous<Frequency>,
+                    mut resonators: R)
+    -> Continuous<Sample>
+where
+    R: Resonator + Send + Sync + 'static,
+{
+    let mut clock = Clock::new();
+    snapshot2(time, excitation, frequency, move |time, excitation, frequency| {
+        clock.tick(time);
+        match clock.sample_rate() {
+            Some(sample_rate) => resonators.process(excitation, frequency, sample_rate),
+            None => 0.0,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    /// Run a bank from an impulse.
+    fn impulse_response<R: Resonator>(resonators: &mut R, frequency: Frequency, length: usize) -> Vec<Sample> {
+        (0..length).map(|n| {
+            let excitation = if n == 0 { 1.0 } else { 0.0 };
+            resonators.process(excitation, frequency, SAMPLE_RATE)
+        }).collect()
+    }
+
+    /// The value of a sine wave at sample `n`.
+    fn sine_at(frequency: Frequency, n: usize) -> f64 {
+        f64::sin(2.0 * PI * frequency * n as f64 / SAMPLE_RATE)
+    }
+
+    #[test]
+    fn test_modal() {
+        // A single mode should ring as a decaying sine wave at its frequency and amplitude.
+        let mut bank = ModalBank::new(vec![Mode::new(2.0, 0.5, 1.0)]);
+        let output = impulse_response(&mut bank, 500.0, 48000);
+        let r = decay_gain(1.0, 1.0, SAMPLE_RATE);
+        for n in [0, 10, 1000, 30000] {
+            let expected = 0.5 * r.powi(n as i32) * sine_at(1000.0, n + 1);
+            assert_relative_eq!(output[n], expected, epsilon = 1e-9);
+        }
+        // After the decay time, it should be 60dB down.
+        assert_relative_eq!(r.powf(SAMPLE_RATE), 0.001, max_relative = 1e-9);
+
+        // Modes above the limit should be silent.
+        let mut bank = ModalBank::new(vec![Mode::new(1.0, 1.0, 1.0), Mode::new(100.0, 1.0, 1.0)]);
+        let high = impulse_response(&mut bank, 500.0, 100);
+        let mut bank = ModalBank::new(vec![Mode::new(1.0, 1.0, 1.0)]);
+        assert_eq!(high, impulse_response(&mut bank, 500.0, 100));
+    }
+
+    #[test]
+    fn test_comb() {
+        // With a whole number of samples per period, the impulse should come round exactly once
+        // every period, getting quieter each time.
+        let mut bank = CombBank::new(vec![1.0], 1.0);
+        let output = impulse_response(&mut bank, SAMPLE_RATE / 100.0, 301);
+        let gain = decay_gain(100.0, 1.0, SAMPLE_RATE);
+        assert_relative_eq!(output[100], 1.0, epsilon = 1e-9);
+        assert_relative_eq!(output[200], gain, epsilon = 1e-9);
+        assert_relative_eq!(output[300], gain * gain, epsilon = 1e-9);
+        assert_relative_eq!(output[150], 0.0, epsilon = 1e-9);
+
+        // A second comb an octave up comes round twice as often, and the bank is scaled down.
+        let mut bank = CombBank::new(vec![1.0, 2.0], 1.0);
+        let output = impulse_response(&mut bank, SAMPLE_RATE / 100.0, 101);
+        assert_relative_eq!(output[50], 0.5, epsilon = 1e-9);
+        assert_relative_eq!(output[100], 0.5 + 0.5 * decay_gain(50.0, 1.0, SAMPLE_RATE), epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_note_ratios() {
+        // A major triad in equal temperament, and in just intonation.
+        let ratios = note_ratios(&Tuning::default(), 60, &[60, 64, 67]);
+        assert_relative_eq!(ratios[1], f64::powf(2.0, 4.0 / 12.0), max_relative = 1e-9);
+        assert_relative_eq!(ratios[2], f64::powf(2.0, 7.0 / 12.0), max_relative = 1e-9);
+
+        let mut tuning = Tuning::default();
+        tuning.set_frequency(64, tuning.frequency(60).unwrap() * 5.0 / 4.0);
+        tuning.set_frequency(67, tuning.frequency(60).unwrap() * 3.0 / 2.0);
+        let bank = ModalBank::from_ratios(&note_ratios(&tuning, 60, &[60, 64, 67]), 1.0);
+        let ratios: Vec<f64> = bank.modes.iter().map(|mode| mode.ratio).collect();
+        assert_relative_eq!(ratios[..], [1.0, 1.25, 1.5][..], max_relative = 1e-9);
+    }
+}
@@ -0,0 +1,247 @@
+//! An algorithmic reverb effect based on Freeverb: a bank of parallel damped comb filters followed
+//! by series allpass filters for each channel, with the right channel's delays slightly longer to
+//! decorrelate it from the left.
+
+use crate::delay_line::DelayLine;
+use crate::effects::{mix_frames, Effect, Param};
+use crate::types::{Frame, Sample, Time};
+
+/// The comb and allpass delays in samples at 44.1kHz, which are scaled to the actual sample rate.
+const COMB_DELAYS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_DELAYS: [usize; 4] = [556, 441, 341, 225];
+const TUNING_SAMPLE_RATE: f64 = 44100.0;
+
+/// How much longer the right channel's delays are than the left's, in samples at 44.1kHz.
+const STEREO_SPREAD: usize = 23;
+
+/// The gain applied to the input, so that the sum of the combs doesn't clip.
+const INPUT_GAIN: f64 = 0.015;
+
+/// The range of the comb feedback that the room size maps to.
+const ROOM_SCALE: f64 = 0.28;
+const ROOM_OFFSET: f64 = 0.7;
+
+/// The maximum amount of damping, so that fully damped combs still ring.
+const DAMPING_SCALE: f64 = 0.4;
+
+/// The feedback of the allpass filters.
+const ALLPASS_FEEDBACK: f64 = 0.5;
+
+/// The longest supported pre-delay, in seconds.
+const MAX_PRE_DELAY: Time = 0.5;
+
+/// A feedback comb filter with a one pole low-pass filter in the feedback path.
+#[derive(Clone, Debug)]
+struct Comb {
+    line: DelayLine,
+    filter_state: Sample,
+}
+
+impl Comb {
+    fn new(delay: usize) -> Self {
+        Self {
+            line: DelayLine::new(delay),
+            filter_state: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: Sample, feedback: f64, damping: f64) -> Sample {
+        let output = self.line.tap(self.line.len() - 1);
+        self.filter_state = output * (1.0 - damping) + self.filter_state * damping;
+        self.line.write(input + self.filter_state * feedback);
+        output
+    }
+
+    fn clear(&mut self) {
+        self.line.clear();
+        self.filter_state = 0.0;
+    }
+}
+
+/// A Schroeder allpass filter, which diffuses the echoes of the combs.
+#[derive(Clone, Debug)]
+struct Allpass {
+    line: DelayLine,
+}
+
+impl Allpass {
+    fn new(delay: usize) -> Self {
+        Self {
+            line: DelayLine::new(delay),
+        }
+    }
+
+    fn process(&mut self, input: Sample) -> Sample {
+        let delayed = self.line.tap(self.line.len() - 1);
+        self.line.write(input + delayed * ALLPASS_FEEDBACK);
+        delayed - input
+    }
+
+    fn clear(&mut self) {
+        self.line.clear();
+    }
+}
+
+/// The combs and allpasses for one channel.
+#[derive(Clone, Debug)]
+struct Tank {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Tank {
+    /// Create a tank for the given sample rate, with every delay lengthened by `spread` samples at
+    /// 44.1kHz.
+    fn new(sample_rate: f64, spread: usize) -> Self {
+        let scale = |delay: usize| (((delay + spread) as f64 * sample_rate / TUNING_SAMPLE_RATE).round() as usize).max(1);
+        Self {
+            combs: COMB_DELAYS.iter().map(|&delay| Comb::new(scale(delay))).collect(),
+            allpasses: ALLPASS_DELAYS.iter().map(|&delay| Allpass::new(scale(delay))).collect(),
+        }
+    }
+
+    fn process(&mut self, input: Sample, feedback: f64, damping: f64) -> Sample {
+        let combed = self.combs.iter_mut().map(|comb| comb.process(input, feedback, damping)).sum();
+        self.allpasses.iter_mut().fold(combed, |sample, allpass| allpass.process(sample))
+    }
+
+    /// Silence the combs and allpasses, keeping their buffers.
+    fn clear(&mut self) {
+        self.combs.iter_mut().for_each(Comb::clear);
+        self.allpasses.iter_mut().for_each(Allpass::clear);
+    }
+}
+
+/// A stereo algorithmic reverb. Its delay lines are built for the sample rate by `prepare`, or on
+/// the first call to `process` if it wasn't prepared.
+pub struct Reverb {
+    /// The size of the room from 0 to 1, which controls how long the reverb tail is.
+    pub size: Param,
+    /// How quickly high frequencies die away, from 0 to 1.
+    pub damping: Param,
+    /// The time before the reverb starts, in seconds.
+    pub pre_delay: Param,
+    /// The stereo width of the reverb from 0 (mono) to 1.
+    pub width: Param,
+    pub mix: Param,
+    pre_delay_line: DelayLine,
+    /// The tanks for the left and right channels, which are empty until prepared.
+    tanks: [Tank; 2],
+    sample_rate: f64,
+}
+
+impl Reverb {
+    /// Create a new reverb with the given size, damping and mix, no pre-delay and full width.
+    pub fn new(size: Param, damping: Param, mix: Param) -> Self {
+        Self {
+            size,
+            damping,
+            pre_delay: Param::Constant(0.0),
+            width: Param::Constant(1.0),
+            mix,
+            pre_delay_line: DelayLine::default(),
+            tanks: [Tank::new(0.0, 0), Tank::new(0.0, 0)],
+            sample_rate: 0.0,
+        }
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        self.prepare(sample_rate);
+
+        // The reverb is fed a mono mix of the input, after the pre-delay.
+        self.pre_delay_line.write((input[0] + input[1]) * 0.5 * INPUT_GAIN);
+        let reverb_input = self.pre_delay_line.read(self.pre_delay.value() * sample_rate);
+
+        let feedback = self.size.value().clamp(0.0, 1.0) * ROOM_SCALE + ROOM_OFFSET;
+        let damping = self.damping.value().clamp(0.0, 1.0) * DAMPING_SCALE;
+        let left = self.tanks[0].process(reverb_input, feedback, damping);
+        let right = self.tanks[1].process(reverb_input, feedback, damping);
+
+        // Cross-mix the channels to narrow the stereo image.
+        let width = self.width.value().clamp(0.0, 1.0);
+        let (direct, cross) = (0.5 + width * 0.5, (1.0 - width) * 0.5);
+        let wet = [left * direct + right * cross, right * direct + left * cross];
+
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        self.pre_delay_line.clear();
+        self.tanks.iter_mut().for_each(Tank::clear);
+    }
+
+    fn prepare(&mut self, sample_rate: f64) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        self.pre_delay_line.resize((MAX_PRE_DELAY * sample_rate).ceil() as usize);
+        self.tanks = [Tank::new(sample_rate, 0), Tank::new(sample_rate, STEREO_SPREAD)];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    fn impulse_response(reverb: &mut Reverb, length: usize) -> Vec<Frame> {
+        (0..length).map(|i| {
+            let input = if i == 0 { 1.0 } else { 0.0 };
+            reverb.process([input, input], SAMPLE_RATE)
+        }).collect()
+    }
+
+    /// The energy of a channel of a response in the given range of samples.
+    fn energy(response: &[Frame], channel: usize, range: std::ops::Range<usize>) -> f64 {
+        response[range].iter().map(|frame| frame[channel] * frame[channel]).sum()
+    }
+
+    #[test]
+    fn test_impulse_response() {
+        let mut reverb = Reverb::new(0.5.into(), 0.5.into(), 1.0.into());
+        reverb.pre_delay = 0.01.into();
+        let response = impulse_response(&mut reverb, 44100);
+
+        // Nothing should come out until the pre-delay and the shortest comb have passed.
+        let first = response.iter().position(|frame| frame[0] != 0.0).unwrap();
+        assert_eq!(first, 441 + COMB_DELAYS[0]);
+        // The first echo passes through each allpass inverted, so comes out with the input gain.
+        assert_relative_eq!(response[first][0], INPUT_GAIN);
+
+        // The tail should decay, and the channels should be decorrelated.
+        assert!(energy(&response, 0, 0..11025) > energy(&response, 0, 33075..44100) * 10.0);
+        assert!(energy(&response, 0, 33075..44100) > 0.0);
+        assert_ne!(response[5000][0], response[5000][1]);
+
+        // The response should be the same every time, including after a reset.
+        reverb.reset();
+        assert_eq!(impulse_response(&mut reverb, 44100), response);
+
+        // Preparing up front should give the same response as preparing on the first sample.
+        let mut prepared = Reverb::new(0.5.into(), 0.5.into(), 1.0.into());
+        prepared.pre_delay = 0.01.into();
+        prepared.prepare(SAMPLE_RATE);
+        assert_eq!(impulse_response(&mut prepared, 44100), response);
+    }
+
+    #[test]
+    fn test_size() {
+        // A bigger room should have a longer tail.
+        let small = impulse_response(&mut Reverb::new(0.2.into(), 0.5.into(), 1.0.into()), 44100);
+        let large = impulse_response(&mut Reverb::new(0.9.into(), 0.5.into(), 1.0.into()), 44100);
+        assert!(energy(&large, 0, 22050..44100) > energy(&small, 0, 22050..44100) * 10.0);
+    }
+
+    #[test]
+    fn test_width() {
+        // With no width the reverb should be mono.
+        let mut reverb = Reverb::new(0.5.into(), 0.5.into(), 1.0.into());
+        reverb.width = 0.0.into();
+        assert!(impulse_response(&mut reverb, 10000).iter().all(|frame| frame[0] == frame[1]));
+    }
+}
@@ -0,0 +1,539 @@
+//! A sampler, which plays recorded instruments made of regions of samples mapped across the
+//! keyboard, pitch shifted to the played frequency.
+
+use std::f64::consts::{FRAC_PI_4, SQRT_2};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::clock::Clock;
+use crate::envelope::{Adsr, AdsrParams, AdsrStage, Curve};
+use crate::functions::midi_note_to_frequency;
+use crate::signal::{Continuous, snapshot4};
+use crate::types::{Frame, Frequency, MidiNote, Sample, Time, Velocity};
+use crate::wav::Wav;
+
+/// The most regions a voice can play at once, including ones still releasing. New regions are
+/// skipped while this many are playing.
+const MAX_PLAYING: usize = 32;
+
+/// How a region's sample loops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopMode {
+    /// Play the sample once, stopping early if the note is released.
+    NoLoop,
+    /// Play the whole sample once, ignoring the note being released.
+    OneShot,
+    /// Loop for as long as the region is playing, including the release.
+    Continuous,
+    /// Loop while the note is held, then play on through the rest of the sample.
+    Sustain,
+}
+
+/// What a note modulation follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoteSource {
+    Key,
+    Velocity,
+}
+
+/// The shape of a note modulation's response to its source, as defined by the SoundFont format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModulationCurve {
+    Linear,
+    /// Slow to start, then rising quickly, like the loudness of a velocity sensitive instrument.
+    Concave,
+    /// Quick to start, then rising slowly.
+    Convex,
+    /// Off below halfway, on above.
+    Switch,
+}
+
+impl ModulationCurve {
+    /// Apply the curve to a value from 0 to 1.
+    fn apply(&self, x: f64) -> f64 {
+        let concave = |x: f64| if x >= 1.0 { 1.0 } else { (-20.0 / 96.0 * f64::log10((1.0 - x).powi(2))).min(1.0) };
+        match self {
+            ModulationCurve::Linear => x,
+            ModulationCurve::Concave => concave(x),
+            ModulationCurve::Convex => 1.0 - concave(1.0 - x),
+            ModulationCurve::Switch => if x >= 0.5 { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+/// What a note modulation changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModulationTarget {
+    /// The region's gain, in decibels.
+    Gain,
+    /// The region's pan, from -1 to 1.
+    Pan,
+    /// The region's pitch, in cents.
+    Tune,
+}
+
+/// A change to a region's sound that follows the key or velocity of the note, which is applied
+/// when the note starts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteModulation {
+    pub source: NoteSource,
+    pub curve: ModulationCurve,
+    /// Whether the source goes from -1 to 1 rather than 0 to 1.
+    pub bipolar: bool,
+    /// Whether the source is reversed, so that it's highest for the lowest key or velocity.
+    pub inverted: bool,
+    pub target: ModulationTarget,
+    /// The change to the target when the source is at its maximum.
+    pub amount: f64,
+}
+
+impl NoteModulation {
+    /// The change to the target for the given note and midi velocity.
+    fn value(&self, note: MidiNote, velocity: u8) -> f64 {
+        let source = match self.source {
+            NoteSource::Key => note,
+            NoteSource::Velocity => velocity,
+        };
+        let mut x = source as f64 / 128.0;
+        if self.inverted {
+            x = 1.0 - x;
+        }
+        let y = if self.bipolar {
+            // Bipolar curves are mirrored around the middle.
+            let x = 2.0 * x - 1.0;
+            x.signum() * self.curve.apply(x.abs())
+        } else {
+            self.curve.apply(x)
+        };
+        y * self.amount
+    }
+}
+
+/// A sample mapped to a range of keys and velocities.
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub sample: Arc<Wav>,
+    pub keys: RangeInclusive<MidiNote>,
+    pub velocities: RangeInclusive<u8>,
+    /// The note the sample plays at its original pitch.
+    pub root_key: MidiNote,
+    /// A pitch offset in cents.
+    pub tune: f64,
+    /// How much the pitch follows the played note, in cents per semitone. At 0 every key plays the
+    /// root key's pitch, which suits drums.
+    pub pitch_keytrack: f64,
+    /// A linear gain.
+    pub gain: f64,
+    /// The position of the region in the stereo field, from -1 (left) to 1 (right).
+    pub pan: f64,
+    /// The sample to start playing from.
+    pub offset: usize,
+    pub loop_mode: LoopMode,
+    /// The first and last sample of the loop.
+    pub loop_points: Option<(usize, usize)>,
+    /// The number of round robin alternatives this region is part of, and which one it is
+    /// (starting from 1).
+    pub sequence_length: usize,
+    pub sequence_position: usize,
+    pub envelope: AdsrParams,
+    /// Changes to the gain, pan or pitch that follow the note.
+    pub modulations: Vec<NoteModulation>,
+}
+
+impl Region {
+    /// Create a region which plays the sample at its original pitch on middle C, but covers every
+    /// key and velocity. If the sample has loop points it loops continuously.
+    pub fn new(sample: Arc<Wav>) -> Self {
+        Self {
+            loop_mode: if sample.loop_points.is_some() { LoopMode::Continuous } else { LoopMode::NoLoop },
+            loop_points: sample.valid_loop(sample.loop_points),
+            sample,
+            keys: 0..=127,
+            velocities: 0..=127,
+            root_key: 60,
+            tune: 0.0,
+            pitch_keytrack: 100.0,
+            gain: 1.0,
+            pan: 0.0,
+            offset: 0,
+            sequence_length: 1,
+            sequence_position: 1,
+            envelope: AdsrParams {
+                attack: 0.0,
+                decay: 0.0,
+                sustain: 1.0,
+                release: 0.001,
+                decay_curve: Curve::Linear,
+                release_curve: Curve::Linear,
+                ..AdsrParams::default()
+            },
+            modulations: Vec::new(),
+        }
+    }
+
+    /// Whether the region should play for the given note, velocity and round robin counter.
+    fn matches(&self, note: MidiNote, velocity: u8, counter: usize) -> bool {
+        self.keys.contains(&note)
+            && self.velocities.contains(&velocity)
+            && counter % self.sequence_length.max(1) + 1 == self.sequence_position
+    }
+
+    /// Whether the loop applies with the note held or released.
+    fn is_looping(&self, gate: bool) -> bool {
+        match self.loop_mode {
+            LoopMode::Continuous => self.loop_points.is_some(),
+            LoopMode::Sustain => gate && self.loop_points.is_some(),
+            LoopMode::NoLoop | LoopMode::OneShot => false,
+        }
+    }
+}
+
+/// A playable instrument made of regions.
+#[derive(Debug, Default)]
+pub struct Instrument {
+    pub regions: Vec<Region>,
+    /// The number of notes played, for choosing round robin regions. This is shared by every voice
+    /// so that repeated notes alternate whichever voice plays them.
+    counter: AtomicUsize,
+}
+
+impl Instrument {
+    /// Create an instrument from its regions, with the round robin counter at the start.
+    pub fn new(regions: Vec<Region>) -> Self {
+        Self {
+            regions,
+            counter: AtomicUsize::new(0),
+        }
+    }
+
+    /// Find the regions to play for a note on, advancing the round robin counter.
+    fn trigger(&self, note: MidiNote, velocity: u8) -> impl Iterator<Item = usize> + '_ {
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+        (0..self.regions.len()).filter(move |&index| self.regions[index].matches(note, velocity, counter))
+    }
+}
+
+/// A region that's currently playing.
+#[derive(Clone, Debug)]
+struct Playback {
+    instrument: Arc<Instrument>,
+    region: usize,
+    position: f64,
+    envelope: Adsr,
+    /// The region's settings, after the note modulations.
+    gain: f64,
+    pan: f64,
+    tune: f64,
+}
+
+impl Playback {
+    fn new(instrument: &Arc<Instrument>, region: usize, note: MidiNote, velocity: u8) -> Self {
+        let settings = &instrument.regions[region];
+        let modulation = |target| settings.modulations.iter()
+            .filter(|modulation| modulation.target == target)
+            .map(|modulation| modulation.value(note, velocity))
+            .sum::<f64>();
+        Self {
+            instrument: instrument.clone(),
+            region,
+            position: settings.offset as f64,
+            envelope: Adsr::new(settings.envelope),
+            gain: settings.gain * f64::powf(10.0, modulation(ModulationTarget::Gain) / 20.0),
+            pan: (settings.pan + modulation(ModulationTarget::Pan)).clamp(-1.0, 1.0),
+            tune: settings.tune + modulation(ModulationTarget::Tune),
+        }
+    }
+}
+
+/// Plays the regions of an instrument for a single voice. The instrument can be changed, which
+/// takes effect from the next note.
+#[derive(Clone, Debug)]
+pub struct SamplerVoice {
+    instrument: Arc<Instrument>,
+    playing: Vec<Playback>,
+    gate: bool,
+}
+
+impl SamplerVoice {
+    /// Create a silent voice playing the given instrument.
+    pub fn new(instrument: Arc<Instrument>) -> Self {
+        Self {
+            instrument,
+            playing: Vec::with_capacity(MAX_PLAYING),
+            gate: false,
+        }
+    }
+
+    /// Change the instrument played by new notes. Notes that are already playing carry on with
+    /// their instrument.
+    pub fn set_instrument(&mut self, instrument: Arc<Instrument>) {
+        self.instrument = instrument;
+    }
+
+    /// Step the voice forward by one sample. The regions to play are chosen by the note when the
+    /// gate opens, and pitched so that the root key plays at its equal tempered frequency.
+    pub fn step(&mut self, note: MidiNote, frequency: Frequency, gate: bool, velocity: Velocity, time_step: Time) -> Frame {
+        if gate && !self.gate {
+            let midi_velocity = (velocity * 127.0).round().clamp(0.0, 127.0) as u8;
+            for region in self.instrument.trigger(note, midi_velocity) {
+                // Skipping regions past the limit keeps this from allocating.
+                if self.playing.len() < MAX_PLAYING {
+                    self.playing.push(Playback::new(&self.instrument, region, note, midi_velocity));
+                }
+            }
+        }
+        self.gate = gate;
+
+        let mut output = [0.0; 2];
+        self.playing.retain_mut(|playback| {
+            let region = &playback.instrument.regions[playback.region];
+            let sample = &region.sample;
+
+            // One shots play to the end however long the note is held.
+            let region_gate = gate || region.loop_mode == LoopMode::OneShot;
+            let amplitude = playback.envelope.step(region_gate, velocity, time_step) * playback.gain;
+            let looping = region.is_looping(region_gate);
+            let loop_points = region.loop_points.filter(|_| looping);
+
+            // Mono samples are panned, stereo samples are balanced.
+            let angle = (playback.pan + 1.0) * FRAC_PI_4;
+            let gains = [angle.cos() * SQRT_2, angle.sin() * SQRT_2];
+            for (channel, gain) in gains.iter().enumerate() {
+                let data = &sample.channels[channel.min(sample.channel_count() - 1)];
+                output[channel] += interpolate(data, playback.position, loop_points) * amplitude * gain;
+            }
+
+            // Advance through the sample at the rate needed to reach the played frequency.
+            let ratio = f64::powf(frequency / midi_note_to_frequency(region.root_key), region.pitch_keytrack / 100.0)
+                * f64::powf(2.0, playback.tune / 1200.0);
+            playback.position += ratio * sample.sample_rate as f64 * time_step;
+            if let Some((start, end)) = loop_points {
+                let length = (end + 1 - start) as f64;
+                while playback.position >= (end + 1) as f64 && length > 0.0 {
+                    playback.position -= length;
+                }
+            }
+
+            let finished = playback.position >= sample.frame_count() as f64;
+            let released = playback.envelope.stage() == AdsrStage::Idle;
+            !finished && !released
+        });
+
+        output
+    }
+}
+
+/// Read a sample at a fractional position using cubic Hermite interpolation. Samples past the end
+/// of the loop wrap around to its start, and samples outside the data are silent.
+pub fn interpolate(data: &[Sample], position: f64, loop_points: Option<(usize, usize)>) -> Sample {
+    let index = position.floor() as isize;
+    let fraction = position - index as f64;
+    let at = |offset: isize| {
+        let mut index = index + offset;
+        if let Some((start, end)) = loop_points {
+            if index > end as isize {
+                index -= (end + 1 - start) as isize;
+            }
+        }
+        usize::try_from(index).ok().and_then(|index| data.get(index)).copied().unwrap_or(0.0)
+    };
+    let (y0, y1, y2, y3) = (at(-1), at(0), at(1), at(2));
+
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+    ((c3 * fraction + c2) * fraction + c1) * fraction + y1
+}
+
+/// Create a sampler node which plays the given instrument.
+pub fn sampler(time: &mut Continuous<Time>,
+               note: &Continuous<MidiNote>,
+               frequency: &Continuous<Frequency>,
+               gate: &Continuous<bool>,
+               velocity: &Continuous<Velocity>,
+               instrument: Arc<Instrument>)
+    -> Continuous<Frame>
+{
+    let mut clock = Clock::new();
+    let mut voice = SamplerVoice::new(instrument);
+    snapshot4(time, note, frequency, gate, velocity, move |time, note, frequency, gate, velocity| {
+        let time_step = clock.tick(time);
+        voice.step(note, frequency, gate, velocity, time_step)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    /// A mono sample which counts up from 0.
+    fn ramp(length: usize, loop_points: Option<(usize, usize)>) -> Arc<Wav> {
+        Arc::new(Wav {
+            sample_rate: 1000,
+            channels: vec![(0..length).map(|i| i as Sample).collect()],
+            loop_points,
+        })
+    }
+
+    fn play(voice: &mut SamplerVoice, note: MidiNote, gates: &[bool]) -> Vec<Sample> {
+        let frequency = midi_note_to_frequency(note);
+        gates.iter().map(|&gate| voice.step(note, frequency, gate, 1.0, 0.001)[0]).collect()
+    }
+
+    fn assert_samples(actual: &[Sample], expected: &[Sample]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert_relative_eq!(actual, expected, epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let data = [0.0, 1.0, 4.0, 9.0, 16.0];
+        assert_eq!(interpolate(&data, 2.0, None), 4.0);
+        assert_relative_eq!(interpolate(&data, 2.5, None), 6.25);
+        assert_eq!(interpolate(&data, 10.0, None), 0.0);
+        // Reading past the loop end wraps to the loop start, so halfway between 9 and 1 (with 4 and
+        // 4 either side) rather than between 9 and 16.
+        assert_relative_eq!(interpolate(&data, 3.5, Some((1, 3))), (-4.0 + 9.0 * 9.0 + 9.0 * 1.0 - 4.0) / 16.0);
+    }
+
+    #[test]
+    fn test_key_and_velocity_ranges() {
+        let mut low = Region::new(ramp(10, None));
+        low.keys = 0..=59;
+        let mut soft = Region::new(ramp(10, None));
+        soft.keys = 60..=127;
+        soft.velocities = 0..=63;
+        let mut loud = soft.clone();
+        loud.velocities = 64..=127;
+        let instrument = Instrument::new(vec![low, soft, loud]);
+
+        assert!(instrument.trigger(40, 100).eq([0]));
+        assert!(instrument.trigger(60, 10).eq([1]));
+        assert!(instrument.trigger(72, 127).eq([2]));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let regions = (1..=3).map(|position| {
+            let mut region = Region::new(ramp(10, None));
+            region.sequence_length = 3;
+            region.sequence_position = position;
+            region
+        }).collect();
+        let instrument = Instrument::new(regions);
+        let played: Vec<Vec<usize>> = (0..4).map(|_| instrument.trigger(60, 100).collect()).collect();
+        assert_eq!(played, vec![vec![0], vec![1], vec![2], vec![0]]);
+    }
+
+    #[test]
+    fn test_pitch_shift() {
+        // Playing the root key plays at the original rate, and an octave up plays twice as fast.
+        let instrument = Arc::new(Instrument::new(vec![Region::new(ramp(100, None))]));
+        let mut voice = SamplerVoice::new(instrument.clone());
+        let output = play(&mut voice, 60, &[true; 4]);
+        assert_samples(&output, &[0.0, 1.0, 2.0, 3.0]);
+
+        let mut voice = SamplerVoice::new(instrument);
+        let output = play(&mut voice, 72, &[true; 4]);
+        assert_relative_eq!(output[3], 6.0, epsilon = 1e-9);
+
+        // A fifth up should interpolate between samples.
+        let mut voice = SamplerVoice::new(Arc::new(Instrument::new(vec![Region::new(ramp(100, None))])));
+        let output = play(&mut voice, 67, &[true; 3]);
+        assert_relative_eq!(output[2], 2.0 * f64::powf(2.0, 7.0 / 12.0), epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_max_playing() {
+        // Regions past the limit are skipped, so the voice never grows its list of playing regions.
+        let regions = (0..MAX_PLAYING + 8).map(|_| Region::new(ramp(100, None))).collect();
+        let mut voice = SamplerVoice::new(Arc::new(Instrument::new(regions)));
+        play(&mut voice, 60, &[true, false, true]);
+        assert_eq!(voice.playing.len(), MAX_PLAYING);
+        assert_eq!(voice.playing.capacity(), MAX_PLAYING);
+    }
+
+    #[test]
+    fn test_note_modulation() {
+        assert_eq!(ModulationCurve::Concave.apply(0.0), 0.0);
+        assert_eq!(ModulationCurve::Concave.apply(1.0), 1.0);
+        assert_relative_eq!(ModulationCurve::Concave.apply(0.5), -20.0 / 96.0 * f64::log10(0.25));
+        assert_relative_eq!(ModulationCurve::Convex.apply(0.5), 1.0 - ModulationCurve::Concave.apply(0.5));
+
+        // A quieter note should be turned down, and a bipolar key modulation should pan low notes
+        // left and high notes right.
+        let mut region = Region::new(ramp(100, None));
+        let velocity = NoteModulation {
+            source: NoteSource::Velocity,
+            curve: ModulationCurve::Linear,
+            bipolar: false,
+            inverted: true,
+            target: ModulationTarget::Gain,
+            amount: -12.0,
+        };
+        let key = NoteModulation { source: NoteSource::Key, bipolar: true, inverted: false, target: ModulationTarget::Pan, amount: 1.0, ..velocity };
+        region.modulations = vec![velocity, key];
+        let instrument = Arc::new(Instrument::new(vec![region]));
+
+        let playback = Playback::new(&instrument, 0, 32, 64);
+        assert_relative_eq!(playback.gain, f64::powf(10.0, -6.0 / 20.0));
+        assert_relative_eq!(playback.pan, -0.5);
+    }
+
+    #[test]
+    fn test_pitch_keytrack() {
+        // Without key tracking, every note should play the sample at its original pitch.
+        let mut region = Region::new(ramp(100, None));
+        region.pitch_keytrack = 0.0;
+        let mut voice = SamplerVoice::new(Arc::new(Instrument::new(vec![region])));
+        let output = play(&mut voice, 84, &[true; 4]);
+        assert_samples(&output, &[0.0, 1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_loops() {
+        // A continuous loop should keep going around the loop points.
+        let instrument = Arc::new(Instrument::new(vec![Region::new(ramp(6, Some((2, 4))))]));
+        let mut voice = SamplerVoice::new(instrument);
+        let output = play(&mut voice, 60, &[true; 9]);
+        assert_samples(&output, &[0.0, 1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 2.0]);
+
+        // A sustain loop should play on to the end of the sample when released, and a one shot
+        // should play to the end regardless.
+        let mut sustain = Region::new(ramp(6, Some((2, 4))));
+        sustain.loop_mode = LoopMode::Sustain;
+        sustain.envelope.release = 1.0;
+        let mut voice = SamplerVoice::new(Arc::new(Instrument::new(vec![sustain])));
+        let mut gates = [false; 10];
+        gates[..5].fill(true);
+        let output = play(&mut voice, 60, &gates);
+        let expected = [0.0, 1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 5.0, 0.0];
+        for (actual, expected) in output.iter().zip(expected) {
+            // Allow for the slow release.
+            assert_relative_eq!(actual, &expected, max_relative = 0.01);
+        }
+
+        let mut one_shot = Region::new(ramp(6, None));
+        one_shot.loop_mode = LoopMode::OneShot;
+        let mut voice = SamplerVoice::new(Arc::new(Instrument::new(vec![one_shot])));
+        let output = play(&mut voice, 60, &[true, false, false, false, false, false, false]);
+        assert_samples(&output, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 0.0]);
+    }
+
+    #[test]
+    fn test_invalid_loops() {
+        // Loops which run backwards or past the end of the sample are dropped, and the sample plays
+        // straight through.
+        for loop_points in [(4, 2), (2, 6)] {
+            let region = Region::new(ramp(6, Some(loop_points)));
+            assert_eq!(region.loop_points, None);
+            let mut voice = SamplerVoice::new(Arc::new(Instrument::new(vec![region])));
+            let output = play(&mut voice, 60, &[true; 7]);
+            assert_samples(&output, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 0.0]);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+//! Loading of SFZ instruments for the sampler.
+//!
+//! An SFZ file is a text file of headers such as `<region>`, each followed by `opcode=value` pairs.
+//! Opcodes under `<global>`, `<master>` and `<group>` headers are inherited by the regions after
+//! them. See https://sfzformat.com for the format. Only the opcodes the sampler supports are read,
+//! and the rest are ignored.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::sampler::{Instrument, LoopMode, Region};
+use crate::types::MidiNote;
+use crate::wav::Wav;
+
+/// The opcodes set under a header.
+type Opcodes = HashMap<String, String>;
+
+impl Instrument {
+    /// Parse an instrument from the contents of an SFZ file. `load_sample` loads a sample from its
+    /// path in the file, including any `default_path`, and is only called once per sample.
+    pub fn parse_sfz<F>(text: &str, mut load_sample: F) -> Result<Self, Box<dyn Error>>
+        where F: FnMut(&str) -> Result<Wav, Box<dyn Error>>
+    {
+        let mut samples: HashMap<String, Arc<Wav>> = HashMap::new();
+        let mut regions = Vec::new();
+
+        // The opcodes at each level of the hierarchy, and the header currently being read.
+        let mut control = Opcodes::new();
+        let mut global = Opcodes::new();
+        let mut master = Opcodes::new();
+        let mut group = Opcodes::new();
+        let mut region: Option<Opcodes> = None;
+        let mut header = String::new();
+
+        for token in tokenize(text)? {
+            match token {
+                Token::Header(name) => {
+                    if let Some(opcodes) = region.take() {
+                        regions.push(build_region(&opcodes, &control, &mut samples, &mut load_sample)?);
+                    }
+                    match name.as_str() {
+                        "global" => { global.clear(); master.clear(); group.clear(); },
+                        "master" => { master.clear(); group.clear(); },
+                        "group" => group.clear(),
+                        "region" => {
+                            let mut opcodes = global.clone();
+                            opcodes.extend(master.clone());
+                            opcodes.extend(group.clone());
+                            region = Some(opcodes);
+                        },
+                        "control" => {},
+                        other => log::debug!("Ignoring unsupported SFZ header <{other}>"),
+                    }
+                    header = name;
+                },
+                Token::Opcode(name, value) => {
+                    let opcodes = match header.as_str() {
+                        "control" => &mut control,
+                        "global" => &mut global,
+                        "master" => &mut master,
+                        "group" => &mut group,
+                        "region" => region.as_mut().ok_or("SFZ opcode outside a region")?,
+                        _ => continue,
+                    };
+                    opcodes.insert(name, value);
+                },
+            }
+        }
+        if let Some(opcodes) = region.take() {
+            regions.push(build_region(&opcodes, &control, &mut samples, &mut load_sample)?);
+        }
+
+        Ok(Self::new(regions))
+    }
+
+    /// Load an SFZ file, with its samples relative to the file.
+    pub fn load_sfz<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let text = std::fs::read_to_string(path)?;
+        Self::parse_sfz(&text, |sample| {
+            let sample_path = directory.join(sample);
+            Wav::load(&sample_path).map_err(|error| format!("Failed to load {}: {error}", sample_path.display()).into())
+        })
+    }
+}
+
+/// A header or opcode in an SFZ file.
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Header(String),
+    Opcode(String, String),
+}
+
+/// Split an SFZ file into headers and opcodes, removing comments and expanding `#define`d
+/// variables. Opcode values can contain spaces (which sample paths often do), so a value runs
+/// until the next opcode or header.
+fn tokenize(text: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let mut defines: Vec<(String, String)> = Vec::new();
+    let mut tokens = Vec::new();
+
+    for line in text.lines() {
+        let line = line.split("//").next().unwrap_or("").trim();
+        if let Some(define) = line.strip_prefix("#define") {
+            let mut parts = define.split_whitespace();
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                defines.push((name.to_string(), value.to_string()));
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            log::debug!("Ignoring unsupported SFZ directive {line}");
+            continue;
+        }
+
+        // Longer variable names first, so that $A doesn't replace the start of $AB.
+        let mut line = line.to_string();
+        defines.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        for (name, value) in &defines {
+            line = line.replace(name.as_str(), value);
+        }
+
+        let spaced = line.replace('<', " <").replace('>', "> ");
+        for word in spaced.split_whitespace() {
+            if let Some(header) = word.strip_prefix('<') {
+                let header = header.strip_suffix('>').ok_or_else(|| format!("Invalid SFZ header {word}"))?;
+                tokens.push(Token::Header(header.to_string()));
+            }
+            else if let Some((name, value)) = word.split_once('=') {
+                tokens.push(Token::Opcode(name.to_string(), value.to_string()));
+            }
+            else if let Some(Token::Opcode(_, value)) = tokens.last_mut() {
+                value.push(' ');
+                value.push_str(word);
+            }
+            else {
+                return Err(format!("Unexpected {word} in SFZ file").into());
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Build a region from its opcodes, loading its sample if it hasn't been already.
+fn build_region<F>(opcodes: &Opcodes,
+                   control: &Opcodes,
+                   samples: &mut HashMap<String, Arc<Wav>>,
+                   load_sample: &mut F)
+    -> Result<Region, Box<dyn Error>>
+    where F: FnMut(&str) -> Result<Wav, Box<dyn Error>>
+{
+    let name = opcodes.get("sample").ok_or("SFZ region has no sample")?;
+    let default_path = control.get("default_path").map_or("", String::as_str);
+    let path = format!("{default_path}{name}").replace('\\', "/");
+    let sample = match samples.get(&path) {
+        Some(sample) => sample.clone(),
+        None => {
+            let sample = Arc::new(load_sample(&path)?);
+            samples.insert(path, sample.clone());
+            sample
+        },
+    };
+
+    let mut region = Region::new(sample);
+    let get = |name: &str| opcodes.get(name).map(String::as_str);
+    let number = |name: &str| -> Result<Option<f64>, Box<dyn Error>> {
+        get(name).map(|value| value.parse().map_err(|_| format!("Invalid value {value} for SFZ opcode {name}").into()))
+            .transpose()
+    };
+    let note = |name: &str| get(name).map(parse_note).transpose();
+
+    if let Some(key) = note("key")? {
+        region.keys = key..=key;
+        region.root_key = key;
+    }
+    let (low, high) = (note("lokey")?, note("hikey")?);
+    region.keys = low.unwrap_or(*region.keys.start())..=high.unwrap_or(*region.keys.end());
+    region.root_key = note("pitch_keycenter")?.unwrap_or(region.root_key);
+    let (low, high) = (number("lovel")?, number("hivel")?);
+    region.velocities = low.map_or(*region.velocities.start(), |v| v as u8)..=high.map_or(*region.velocities.end(), |v| v as u8);
+
+    region.tune = number("tune")?.unwrap_or(0.0) + number("transpose")?.unwrap_or(0.0) * 100.0;
+    region.pitch_keytrack = number("pitch_keytrack")?.unwrap_or(100.0);
+    region.gain = f64::powf(10.0, number("volume")?.unwrap_or(0.0) / 20.0);
+    region.pan = number("pan")?.unwrap_or(0.0) / 100.0;
+    region.offset = number("offset")?.unwrap_or(0.0) as usize;
+
+    if let Some(mode) = get("loop_mode").or(get("loopmode")) {
+        region.loop_mode = match mode {
+            "no_loop" => LoopMode::NoLoop,
+            "one_shot" => LoopMode::OneShot,
+            "loop_continuous" => LoopMode::Continuous,
+            "loop_sustain" => LoopMode::Sustain,
+            other => return Err(format!("Unknown SFZ loop mode {other}").into()),
+        };
+    }
+    let start = number("loop_start")?.or(number("loopstart")?);
+    let end = number("loop_end")?.or(number("loopend")?);
+    if let (Some(start), Some(end)) = (start, end) {
+        region.loop_points = region.sample.valid_loop(Some((start as usize, end as usize)));
+    }
+
+    region.sequence_length = number("seq_length")?.map_or(1, |length| length as usize);
+    region.sequence_position = number("seq_position")?.map_or(1, |position| position as usize);
+
+    let envelope = &mut region.envelope;
+    envelope.attack = number("ampeg_attack")?.unwrap_or(envelope.attack);
+    envelope.decay = number("ampeg_decay")?.unwrap_or(envelope.decay);
+    envelope.sustain = number("ampeg_sustain")?.map_or(envelope.sustain, |sustain| sustain / 100.0);
+    envelope.release = number("ampeg_release")?.unwrap_or(envelope.release);
+
+    Ok(region)
+}
+
+/// Parse a note, either as a midi note number or a name like `c#4`, where `c4` is middle C (60).
+fn parse_note(value: &str) -> Result<MidiNote, Box<dyn Error>> {
+    let invalid = || format!("Invalid SFZ note {value}");
+    if let Ok(note) = value.parse::<u8>() {
+        return Ok(note);
+    }
+
+    let lower = value.to_ascii_lowercase();
+    let mut chars = lower.chars();
+    let semitone: i32 = match chars.next() {
+        Some('c') => 0,
+        Some('d') => 2,
+        Some('e') => 4,
+        Some('f') => 5,
+        Some('g') => 7,
+        Some('a') => 9,
+        Some('b') => 11,
+        _ => return Err(invalid().into()),
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next() {
+        Some('#') => (1, &rest[1..]),
+        Some('b') => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let octave: i32 = octave.parse().map_err(|_| invalid())?;
+
+    u8::try_from((octave + 1) * 12 + semitone + accidental).ok()
+        .filter(|&note| note < 128)
+        .ok_or_else(|| invalid().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(sample_rate: u32) -> Wav {
+        Wav { sample_rate, channels: vec![vec![0.0; 10]], loop_points: Some((2, 8)) }
+    }
+
+    #[test]
+    fn test_parse_note() {
+        assert_eq!(parse_note("60").unwrap(), 60);
+        assert_eq!(parse_note("c4").unwrap(), 60);
+        assert_eq!(parse_note("C#4").unwrap(), 61);
+        assert_eq!(parse_note("eb3").unwrap(), 51);
+        assert_eq!(parse_note("c-1").unwrap(), 0);
+        assert!(parse_note("h4").is_err());
+        assert!(parse_note("g9").unwrap() == 127 && parse_note("a9").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = "
+            // A comment.
+            #define $ROOT 48
+            <control> default_path=samples\\
+            <global> ampeg_release=0.5 loop_mode=no_loop
+            <group> lovel=1 hivel=64 volume=-6
+            <region> sample=piano soft.wav lokey=c3 hikey=b3 pitch_keycenter=$ROOT
+            <region>sample=other.wav key=72 tune=-20 transpose=1
+            <group> seq_length=2
+            <region> sample=piano soft.wav seq_position=2 loop_mode=loop_sustain loop_start=1 loop_end=5
+        ";
+        let mut loaded = Vec::new();
+        let instrument = Instrument::parse_sfz(text, |path| {
+            loaded.push(path.to_string());
+            Ok(sample(44100))
+        }).unwrap();
+
+        // Samples should be loaded once each, from the default path.
+        assert_eq!(loaded, vec!["samples/piano soft.wav", "samples/other.wav"]);
+        assert_eq!(instrument.regions.len(), 3);
+
+        let [first, second, third] = &instrument.regions[..] else { unreachable!() };
+        assert_eq!(first.keys, 48..=59);
+        assert_eq!(first.root_key, 48);
+        assert_eq!(first.velocities, 1..=64);
+        assert!((first.gain - 0.501).abs() < 0.001);
+        assert_eq!(first.envelope.release, 0.5);
+        assert_eq!(first.loop_mode, LoopMode::NoLoop);
+
+        assert_eq!(second.keys, 72..=72);
+        assert_eq!(second.root_key, 72);
+        assert_eq!(second.tune, 80.0);
+
+        // A new group should reset the group's opcodes but keep the global ones.
+        assert_eq!(third.velocities, 0..=127);
+        assert_eq!(third.gain, 1.0);
+        assert_eq!(third.envelope.release, 0.5);
+        assert_eq!((third.sequence_length, third.sequence_position), (2, 2));
+        assert_eq!(third.loop_mode, LoopMode::Sustain);
+        assert_eq!(third.loop_points, Some((1, 5)));
+        assert!(Arc::ptr_eq(&first.sample, &third.sample));
+
+        // A loop that runs backwards or past the end of the sample is ignored.
+        let reversed = Instrument::parse_sfz("<region> sample=a.wav loop_start=5 loop_end=1", |_| Ok(sample(44100))).unwrap();
+        assert_eq!(reversed.regions[0].loop_points, None);
+        let long = Instrument::parse_sfz("<region> sample=a.wav loop_start=1 loop_end=10", |_| Ok(sample(44100))).unwrap();
+        assert_eq!(long.regions[0].loop_points, None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Instrument::parse_sfz("<region> lokey=60", |_| Ok(sample(44100))).is_err());
+        assert!(Instrument::parse_sfz("<region> sample=a.wav lokey=x", |_| Ok(sample(44100))).is_err());
+        assert!(Instrument::parse_sfz("<region> sample=a.wav", |_| Err("missing".into())).is_err());
+    }
+}
@@ -3,7 +3,7 @@
 use std::sync::{Arc, Mutex};
 
 /// A callback for signals to notify their subscribers that their value has been updated.
-type CallbackClosure = Box<dyn FnMut() -> () + Send + Sync + 'static>;
+type CallbackClosure = Box<dyn FnMut() + Send + Sync + 'static>;
 
 /// The "base" component of Discrete and Continous signals, which is basically a thread-safe value
 /// holder, which also holds references to the update callbacks of those dependent on it.
@@ -37,7 +37,7 @@ impl<T: Clone + PartialEq> SignalBase<T> {
     /// in value.
     /// TODO: we should probably have anything that attaches also detach automatically when it's dropped.
     fn attach<F>(&mut self, closure: F)
-        where F: FnMut() -> () + Send + Sync + 'static
+        where F: FnMut() + Send + Sync + 'static
     {
         self.subscribers.lock()
             .expect("Failed to acquire lock to attach to signal")
@@ -100,6 +100,15 @@ where
     }
 }
 
+impl<T> Default for Discrete<T>
+where
+    T: Clone + PartialEq + Send + Sync + 'static
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> AsMut<Discrete<T>> for Discrete<T> {
     fn as_mut(&mut self) -> &mut Discrete<T> {
         self
@@ -158,11 +167,8 @@ where
         let mut signal_base = signal.base.clone();
 
         let update_closure = move || {
-            match (parent_a_base.get(), parent_b_base.get()) {
-                (Some(a), Some(b)) => {
-                    signal_base.set(update(a, b));                
-                },
-                _ => {}
+            if let (Some(a), Some(b)) = (parent_a_base.get(), parent_b_base.get()) {
+                signal_base.set(update(a, b));
             }
         };
 
@@ -172,6 +178,37 @@ where
         signal
     }
 
+    /// The internal definition of the snapshot functions. Produces a new signal which is updated
+    /// by a stateful closure every time the clock signal changes. The closure is responsible for
+    /// sampling any other input signals, and can return None to leave the value unchanged.
+    fn new_snapshot<C, F>(clock: &mut SignalBase<C>, mut update: F) -> Self
+    where
+        C: Clone + PartialEq + Send + Sync + 'static,
+        F: FnMut(C) -> Option<T> + Send + Sync + 'static,
+    {
+        let signal = Continuous {
+            base: SignalBase::new(),
+        };
+
+        let clock_base = clock.clone();
+        let mut signal_base = signal.base.clone();
+
+        clock.attach(move || {
+            if let Some(value) = clock_base.get().and_then(&mut update) {
+                signal_base.set(value);
+            }
+        });
+
+        signal
+    }
+
+    /// Create a signal which always holds the given value.
+    pub fn constant(value: T) -> Self {
+        let mut base = SignalBase::new();
+        base.set(value);
+        Continuous { base }
+    }
+
     /// Sample the current value of the signal.
     pub fn sample(&self) -> Option<T> {
         self.base.get()
@@ -214,6 +251,119 @@ where
     Continuous::new2(&mut signal_a.base, &mut signal_b.base, closure)
 }
 
+/// Sample the given signal every time the clock signal changes, passing both values to a stateful
+/// closure and producing a new signal.
+///
+/// Unlike the lift functions, the closure is only called when the clock changes, so it's suitable
+/// for nodes with internal state such as envelopes and filters, which need to be stepped exactly
+/// once per sample even if their inputs haven't changed. Input signals should be constructed before
+/// the snapshot so that they're updated first when the clock changes.
+pub fn snapshot1<F, C, A, T>(clock: &mut Continuous<C>, signal_a: &Continuous<A>, mut closure: F) -> Continuous<T>
+where
+    A: Clone + PartialEq + Send + Sync + 'static,
+    C: Clone + PartialEq + Send + Sync + 'static,
+    T: Clone + PartialEq + Send + Sync + 'static,
+    F: FnMut(C, A) -> T + Send + Sync + 'static,
+{
+    let a = signal_a.base.clone();
+    Continuous::new_snapshot(&mut clock.base, move |c| {
+        Some(closure(c, a.get()?))
+    })
+}
+
+/// Sample the given signals every time the clock signal changes, passing their values to a stateful
+/// closure and producing a new signal. See `snapshot1`.
+pub fn snapshot2<F, C, A, B, T>(clock: &mut Continuous<C>,
+                                signal_a: &Continuous<A>,
+                                signal_b: &Continuous<B>,
+                                mut closure: F)
+    -> Continuous<T>
+where
+    A: Clone + PartialEq + Send + Sync + 'static,
+    B: Clone + PartialEq + Send + Sync + 'static,
+    C: Clone + PartialEq + Send + Sync + 'static,
+    T: Clone + PartialEq + Send + Sync + 'static,
+    F: FnMut(C, A, B) -> T + Send + Sync + 'static,
+{
+    let (a, b) = (signal_a.base.clone(), signal_b.base.clone());
+    Continuous::new_snapshot(&mut clock.base, move |c| {
+        Some(closure(c, a.get()?, b.get()?))
+    })
+}
+
+/// Sample the given signals every time the clock signal changes, passing their values to a stateful
+/// closure and producing a new signal. See `snapshot1`.
+pub fn snapshot3<F, C, A, B, D, T>(clock: &mut Continuous<C>,
+                                   signal_a: &Continuous<A>,
+                                   signal_b: &Continuous<B>,
+                                   signal_d: &Continuous<D>,
+                                   mut closure: F)
+    -> Continuous<T>
+where
+    A: Clone + PartialEq + Send + Sync + 'static,
+    B: Clone + PartialEq + Send + Sync + 'static,
+    C: Clone + PartialEq + Send + Sync + 'static,
+    D: Clone + PartialEq + Send + Sync + 'static,
+    T: Clone + PartialEq + Send + Sync + 'static,
+    F: FnMut(C, A, B, D) -> T + Send + Sync + 'static,
+{
+    let (a, b, d) = (signal_a.base.clone(), signal_b.base.clone(), signal_d.base.clone());
+    Continuous::new_snapshot(&mut clock.base, move |c| {
+        Some(closure(c, a.get()?, b.get()?, d.get()?))
+    })
+}
+
+/// Sample the given signals every time the clock signal changes, passing their values to a stateful
+/// closure and producing a new signal. See `snapshot1`.
+pub fn snapshot4<F, C, A, B, D, E, T>(clock: &mut Continuous<C>,
+                                      signal_a: &Continuous<A>,
+                                      signal_b: &Continuous<B>,
+                                      signal_d: &Continuous<D>,
+                                      signal_e: &Continuous<E>,
+                                      mut closure: F)
+    -> Continuous<T>
+where
+    A: Clone + PartialEq + Send + Sync + 'static,
+    B: Clone + PartialEq + Send + Sync + 'static,
+    C: Clone + PartialEq + Send + Sync + 'static,
+    D: Clone + PartialEq + Send + Sync + 'static,
+    E: Clone + PartialEq + Send + Sync + 'static,
+    T: Clone + PartialEq + Send + Sync + 'static,
+    F: FnMut(C, A, B, D, E) -> T + Send + Sync + 'static,
+{
+    let (a, b, d, e) = (signal_a.base.clone(), signal_b.base.clone(), signal_d.base.clone(), signal_e.base.clone());
+    Continuous::new_snapshot(&mut clock.base, move |c| {
+        Some(closure(c, a.get()?, b.get()?, d.get()?, e.get()?))
+    })
+}
+
+/// Sample the given signals every time the clock signal changes, passing their values to a stateful
+/// closure and producing a new signal. See `snapshot1`.
+pub fn snapshot5<F, C, A, B, D, E, G, T>(clock: &mut Continuous<C>,
+                                         signal_a: &Continuous<A>,
+                                         signal_b: &Continuous<B>,
+                                         signal_d: &Continuous<D>,
+                                         signal_e: &Continuous<E>,
+                                         signal_g: &Continuous<G>,
+                                         mut closure: F)
+    -> Continuous<T>
+where
+    A: Clone + PartialEq + Send + Sync + 'static,
+    B: Clone + PartialEq + Send + Sync + 'static,
+    C: Clone + PartialEq + Send + Sync + 'static,
+    D: Clone + PartialEq + Send + Sync + 'static,
+    E: Clone + PartialEq + Send + Sync + 'static,
+    G: Clone + PartialEq + Send + Sync + 'static,
+    T: Clone + PartialEq + Send + Sync + 'static,
+    F: FnMut(C, A, B, D, E, G) -> T + Send + Sync + 'static,
+{
+    let (a, b, d) = (signal_a.base.clone(), signal_b.base.clone(), signal_d.base.clone());
+    let (e, g) = (signal_e.base.clone(), signal_g.base.clone());
+    Continuous::new_snapshot(&mut clock.base, move |c| {
+        Some(closure(c, a.get()?, b.get()?, d.get()?, e.get()?, g.get()?))
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -237,4 +387,42 @@ mod test {
         //std::mem::drop(signal);
         //event.push(0.0);
     }
+
+    #[test]
+    fn test_snapshot() {
+        let mut clock = Discrete::<u32>::new();
+        let mut input = Discrete::<f64>::new();
+        let input_signal = input.hold();
+
+        // Count the number of clock ticks, summing the input at each one.
+        let mut total = 0.0;
+        let signal = snapshot1(clock.hold().as_mut(), &input_signal, move |_, value| {
+            total += value;
+            total
+        });
+
+        // Nothing should happen until both the clock and input have values.
+        clock.push(0);
+        assert_eq!(signal.sample(), None);
+
+        // The closure should be called once per clock tick, even if the input doesn't change.
+        input.push(1.0);
+        assert_eq!(signal.sample(), None);
+        clock.push(1);
+        assert_eq!(signal.sample(), Some(1.0));
+        clock.push(2);
+        assert_eq!(signal.sample(), Some(2.0));
+
+        // But changing the input alone shouldn't update it.
+        input.push(5.0);
+        assert_eq!(signal.sample(), Some(2.0));
+        clock.push(3);
+        assert_eq!(signal.sample(), Some(7.0));
+    }
+
+    #[test]
+    fn test_constant() {
+        let signal = Continuous::constant(5.0);
+        assert_eq!(signal.sample(), Some(5.0));
+    }
 }
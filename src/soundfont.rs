@@ -0,0 +1,678 @@
+//! Loading and playback of SoundFont 2 (.sf2) files.
+//!
+//! A SoundFont is a set of presets, chosen by bank and program number, which are made of zones
+//! that map instruments across the keyboard. Instruments are in turn made of zones that map
+//! samples. Each zone has generators, which set things like the key range, tuning and envelope,
+//! and modulators, which change generators as the note plays. Every preset is flattened into a
+//! sampler instrument when the file is loaded.
+//!
+//! Only modulators driven by the key or velocity of a note and changing the volume, pan or tuning
+//! are supported, which includes the standard velocity to volume modulator. The envelope's delay
+//! and hold stages are ignored. See the SoundFont 2.04 specification for the format.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::clock::Clock;
+use crate::envelope::Curve;
+use crate::sampler::{Instrument, LoopMode, ModulationCurve, ModulationTarget, NoteModulation, NoteSource, Region, SamplerVoice};
+use crate::signal::{Continuous, snapshot5};
+use crate::synth::Program;
+use crate::types::{Frame, Frequency, MidiNote, Sample, Time, Velocity};
+use crate::wav::Wav;
+
+/// The generators that are used.
+const START_OFFSET: u16 = 0;
+const END_OFFSET: u16 = 1;
+const LOOP_START_OFFSET: u16 = 2;
+const LOOP_END_OFFSET: u16 = 3;
+const START_COARSE_OFFSET: u16 = 4;
+const END_COARSE_OFFSET: u16 = 12;
+const PAN: u16 = 17;
+const ATTACK: u16 = 34;
+const DECAY: u16 = 36;
+const SUSTAIN: u16 = 37;
+const RELEASE: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VELOCITY_RANGE: u16 = 44;
+const LOOP_START_COARSE_OFFSET: u16 = 45;
+const ATTENUATION: u16 = 48;
+const LOOP_END_COARSE_OFFSET: u16 = 50;
+const COARSE_TUNE: u16 = 51;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const SCALE_TUNING: u16 = 56;
+const ROOT_KEY: u16 = 58;
+
+/// The size of the coarse sample offsets.
+const COARSE_OFFSET: i64 = 32768;
+
+/// The modulator sources that are supported.
+const SOURCE_VELOCITY: u16 = 2;
+const SOURCE_KEY: u16 = 3;
+
+/// The standard velocity to volume modulator, which every zone has unless it's overridden.
+const DEFAULT_MODULATORS: [Modulator; 1] = [
+    Modulator { source: 0x0502, destination: ATTENUATION, amount: 960, amount_source: 0, transform: 0 },
+];
+
+/// The sample type flag for samples in ROM, which aren't in the file.
+const ROM_SAMPLE: u16 = 0x8000;
+
+/// A preset from a SoundFont.
+#[derive(Debug)]
+pub struct Preset {
+    pub name: String,
+    pub program: Program,
+    pub instrument: Arc<Instrument>,
+}
+
+/// A SoundFont, with its presets converted to sampler instruments.
+#[derive(Debug, Default)]
+pub struct SoundFont {
+    pub name: String,
+    pub presets: Vec<Preset>,
+}
+
+impl SoundFont {
+    /// Parse a SoundFont from the contents of an .sf2 file.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"sfbk" {
+            return Err("Not a SoundFont file".into());
+        }
+
+        let mut name = String::new();
+        let mut samples: &[u8] = &[];
+        let mut samples_24: &[u8] = &[];
+        let mut hydra: HashMap<[u8; 4], &[u8]> = HashMap::new();
+        for (id, body) in read_chunks(&bytes[12..])? {
+            if &id != b"LIST" || body.len() < 4 {
+                continue;
+            }
+            for (id, chunk) in read_chunks(&body[4..])? {
+                match &id {
+                    b"INAM" => name = read_name(chunk),
+                    b"smpl" => samples = chunk,
+                    b"sm24" => samples_24 = chunk,
+                    _ => { hydra.insert(id, chunk); },
+                }
+            }
+        }
+
+        // Decode the 16-bit samples, with the extra 8 bits for 24-bit samples if they're there.
+        let samples: Vec<Sample> = if samples_24.len() == samples.len() / 2 {
+            samples.chunks_exact(2).zip(samples_24).map(|(bytes, &low)| {
+                ((i16::from_le_bytes([bytes[0], bytes[1]]) as i32) << 8 | low as i32) as Sample / 8388608.0
+            }).collect()
+        } else {
+            samples.chunks_exact(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as Sample / 32768.0).collect()
+        };
+
+        let hydra = Hydra::parse(&hydra)?;
+        let mut cache = HashMap::new();
+        let presets = (0..hydra.presets.len().saturating_sub(1))
+            .map(|index| hydra.preset(index, &samples, &mut cache))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { name, presets })
+    }
+
+    /// Load a SoundFont file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// Find the preset for a program. Missing programs fall back to the same program in the first
+    /// bank, or the first kit for percussion, as General MIDI sound modules do.
+    pub fn preset(&self, program: Program) -> Option<&Preset> {
+        let find = |program: Program| self.presets.iter().find(|preset| preset.program == program);
+        let fallback = if program.bank == Program::PERCUSSION_BANK {
+            Program { bank: program.bank, number: 0 }
+        } else {
+            Program { bank: 0, number: program.number }
+        };
+        find(program).or_else(|| find(fallback))
+    }
+}
+
+/// A RIFF chunk's id and body.
+type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// Split a list of RIFF chunks into their ids and bodies.
+fn read_chunks(mut bytes: &[u8]) -> Result<Vec<Chunk<'_>>, Box<dyn Error>> {
+    let mut chunks = Vec::new();
+    while bytes.len() >= 8 {
+        let id = bytes[0..4].try_into()?;
+        let size = u32::from_le_bytes(bytes[4..8].try_into()?) as usize;
+        let body = bytes.get(8..8 + size).ok_or("SoundFont chunk is truncated")?;
+        chunks.push((id, body));
+        // Chunks are padded to an even length.
+        bytes = bytes.get(8 + size + size % 2..).unwrap_or(&[]);
+    }
+    Ok(chunks)
+}
+
+/// Read a name, which is padded with zeros.
+fn read_name(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// A modulator, as stored in the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Modulator {
+    source: u16,
+    destination: u16,
+    amount: i16,
+    amount_source: u16,
+    transform: u16,
+}
+
+impl Modulator {
+    /// Whether two modulators are the same apart from their amount, in which case one overrides
+    /// the other.
+    fn is_identical(&self, other: &Modulator) -> bool {
+        (self.source, self.destination, self.amount_source, self.transform)
+            == (other.source, other.destination, other.amount_source, other.transform)
+    }
+
+    /// Convert to a note modulation, if it's one that's supported.
+    fn note_modulation(&self) -> Option<NoteModulation> {
+        // The source's index is in the bottom 7 bits, then a flag for midi controllers, the
+        // direction, the polarity and finally the curve.
+        let index = self.source & 0x7F;
+        let is_controller = self.source & 0x80 != 0;
+        let source = match (is_controller, index) {
+            (false, SOURCE_VELOCITY) => NoteSource::Velocity,
+            (false, SOURCE_KEY) => NoteSource::Key,
+            _ => return None,
+        };
+        let curve = match self.source >> 10 {
+            0 => ModulationCurve::Linear,
+            1 => ModulationCurve::Concave,
+            2 => ModulationCurve::Convex,
+            3 => ModulationCurve::Switch,
+            _ => return None,
+        };
+        // A source of 0 is a constant 1, and a linear transform leaves the value alone.
+        if self.amount_source != 0 || self.transform != 0 {
+            return None;
+        }
+
+        let amount = self.amount as f64;
+        let (target, amount) = match self.destination {
+            ATTENUATION => (ModulationTarget::Gain, -amount / 10.0),
+            PAN => (ModulationTarget::Pan, amount / 500.0),
+            COARSE_TUNE => (ModulationTarget::Tune, amount * 100.0),
+            FINE_TUNE => (ModulationTarget::Tune, amount),
+            _ => return None,
+        };
+
+        Some(NoteModulation {
+            source,
+            curve,
+            bipolar: self.source & 0x200 != 0,
+            inverted: self.source & 0x100 != 0,
+            target,
+            amount,
+        })
+    }
+}
+
+/// A sample header.
+#[derive(Clone, Debug)]
+struct SampleHeader {
+    start: usize,
+    end: usize,
+    loop_start: usize,
+    loop_end: usize,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+    sample_type: u16,
+}
+
+/// The generators and modulators of a zone.
+#[derive(Clone, Debug, Default)]
+struct Zone {
+    generators: BTreeMap<u16, [u8; 2]>,
+    modulators: Vec<Modulator>,
+}
+
+impl Zone {
+    /// A generator's amount as a signed number.
+    fn amount(&self, generator: u16) -> Option<i64> {
+        self.generators.get(&generator).map(|&bytes| i16::from_le_bytes(bytes) as i64)
+    }
+
+    /// A generator's amount as a range, such as a key range.
+    fn range(&self, generator: u16) -> Option<(u8, u8)> {
+        self.generators.get(&generator).map(|&[low, high]| (low, high))
+    }
+
+    /// Combine a global zone with a local one, with the local generators replacing the global ones
+    /// and the local modulators replacing identical global ones.
+    fn merge(global: &Zone, local: &Zone) -> Zone {
+        let mut generators = global.generators.clone();
+        generators.extend(local.generators.iter().map(|(&generator, &amount)| (generator, amount)));
+        Zone { generators, modulators: override_modulators(&global.modulators, &local.modulators) }
+    }
+}
+
+/// Add modulators to a list, replacing any identical ones.
+fn override_modulators(base: &[Modulator], overrides: &[Modulator]) -> Vec<Modulator> {
+    let mut modulators: Vec<Modulator> = base.iter()
+        .filter(|modulator| !overrides.iter().any(|other| other.is_identical(modulator)))
+        .copied()
+        .collect();
+    modulators.extend_from_slice(overrides);
+    modulators
+}
+
+/// The preset, instrument and sample data of a SoundFont (which the specification calls the
+/// "hydra"), with the zones read from the bags of generators and modulators.
+#[derive(Debug)]
+struct Hydra {
+    /// The name, program and zones of each preset, including the terminal record.
+    presets: Vec<(String, Program, Range<usize>)>,
+    preset_zones: Vec<Zone>,
+    /// The zones of each instrument, including the terminal record.
+    instruments: Vec<Range<usize>>,
+    instrument_zones: Vec<Zone>,
+    samples: Vec<SampleHeader>,
+}
+
+impl Hydra {
+    fn parse(chunks: &HashMap<[u8; 4], &[u8]>) -> Result<Self, Box<dyn Error>> {
+        let records = |id: &[u8; 4], size: usize| -> Result<Vec<&[u8]>, Box<dyn Error>> {
+            let chunk = chunks.get(id).ok_or_else(|| format!("SoundFont is missing its {} chunk", String::from_utf8_lossy(id)))?;
+            Ok(chunk.chunks_exact(size).collect())
+        };
+
+        let preset_headers = records(b"phdr", 38)?;
+        let instrument_headers = records(b"inst", 22)?;
+        let preset_zones = read_zones(&records(b"pbag", 4)?, &records(b"pgen", 4)?, &records(b"pmod", 10)?)?;
+        let instrument_zones = read_zones(&records(b"ibag", 4)?, &records(b"igen", 4)?, &records(b"imod", 10)?)?;
+
+        // Each header's zones run up to the next header's first zone.
+        let bag_ranges = |headers: &[&[u8]], offset: usize| -> Vec<Range<usize>> {
+            headers.windows(2)
+                .map(|pair| read_u16(pair[0], offset) as usize..read_u16(pair[1], offset) as usize)
+                .chain(std::iter::once(0..0))
+                .collect()
+        };
+        let presets = preset_headers.iter().zip(bag_ranges(&preset_headers, 24)).map(|(header, zones)| {
+            let program = Program { bank: read_u16(header, 22), number: read_u16(header, 20) as u8 };
+            (read_name(&header[..20]), program, zones)
+        }).collect();
+        let instruments = bag_ranges(&instrument_headers, 20);
+
+        let samples = records(b"shdr", 46)?.iter().map(|header| SampleHeader {
+            start: read_u32(header, 20) as usize,
+            end: read_u32(header, 24) as usize,
+            loop_start: read_u32(header, 28) as usize,
+            loop_end: read_u32(header, 32) as usize,
+            sample_rate: read_u32(header, 36),
+            original_pitch: header[40],
+            pitch_correction: header[41] as i8,
+            sample_type: read_u16(header, 44),
+        }).collect();
+
+        Ok(Self { presets, preset_zones, instruments, instrument_zones, samples })
+    }
+
+    /// Split a list of zones into the global zone, if there is one, and the local zones. The global
+    /// zone is a first zone without the generator that ends every local zone.
+    fn split_zones(zones: &[Zone], range: Range<usize>, terminal: u16) -> Result<(Zone, &[Zone]), Box<dyn Error>> {
+        let zones = zones.get(range).ok_or("SoundFont zone index is out of range")?;
+        match zones.first() {
+            Some(first) if !first.generators.contains_key(&terminal) => Ok((first.clone(), &zones[1..])),
+            _ => Ok((Zone::default(), zones)),
+        }
+    }
+
+    /// Flatten a preset into a sampler instrument.
+    fn preset(&self, index: usize, samples: &[Sample], cache: &mut HashMap<(usize, i64), Arc<Wav>>) -> Result<Preset, Box<dyn Error>> {
+        let (name, program, range) = self.presets[index].clone();
+        let (preset_global, preset_zones) = Self::split_zones(&self.preset_zones, range, INSTRUMENT)?;
+
+        let mut regions = Vec::new();
+        for preset_zone in preset_zones {
+            let preset_zone = Zone::merge(&preset_global, preset_zone);
+            let Some(instrument) = preset_zone.amount(INSTRUMENT) else { continue };
+            let range = self.instruments.get(instrument as usize).ok_or("SoundFont instrument index is out of range")?;
+            let (instrument_global, instrument_zones) = Self::split_zones(&self.instrument_zones, range.clone(), SAMPLE_ID)?;
+
+            for instrument_zone in instrument_zones {
+                let instrument_zone = Zone::merge(&instrument_global, instrument_zone);
+                if let Some(region) = self.region(&preset_zone, &instrument_zone, samples, cache)? {
+                    regions.push(region);
+                }
+            }
+        }
+
+        Ok(Preset { name, program, instrument: Arc::new(Instrument::new(regions)) })
+    }
+
+    /// Build a region from a preset zone and an instrument zone. Preset generators are added to the
+    /// instrument's, apart from the ranges, which are intersected. Returns None if the zones don't
+    /// overlap or the sample isn't available.
+    fn region(&self,
+              preset: &Zone,
+              instrument: &Zone,
+              samples: &[Sample],
+              cache: &mut HashMap<(usize, i64), Arc<Wav>>)
+        -> Result<Option<Region>, Box<dyn Error>>
+    {
+        let Some(sample_id) = instrument.amount(SAMPLE_ID) else { return Ok(None) };
+        let header = self.samples.get(sample_id as usize).ok_or("SoundFont sample index is out of range")?;
+        if header.sample_type & ROM_SAMPLE != 0 {
+            return Ok(None);
+        }
+
+        let intersect = |generator| {
+            let (low, high) = instrument.range(generator).unwrap_or((0, 127));
+            let (preset_low, preset_high) = preset.range(generator).unwrap_or((0, 127));
+            (low.max(preset_low), high.min(preset_high).min(127))
+        };
+        let (low_key, high_key) = intersect(KEY_RANGE);
+        let (low_velocity, high_velocity) = intersect(VELOCITY_RANGE);
+        if low_key > high_key || low_velocity > high_velocity {
+            return Ok(None);
+        }
+
+        let value = |generator, default| instrument.amount(generator).unwrap_or(default) + preset.amount(generator).unwrap_or(0);
+        let offset = |fine, coarse| instrument.amount(fine).unwrap_or(0) + instrument.amount(coarse).unwrap_or(0) * COARSE_OFFSET;
+        let seconds = |generator| f64::powf(2.0, value(generator, -12000) as f64 / 1200.0);
+
+        // The sample runs up to its end, which is the first sample after it.
+        let end_offset = offset(END_OFFSET, END_COARSE_OFFSET);
+        let sample = match cache.get(&(sample_id as usize, end_offset)) {
+            Some(sample) => sample.clone(),
+            None => {
+                if header.start > samples.len() {
+                    return Err("SoundFont sample is out of range".into());
+                }
+                let end = (header.end as i64 + end_offset).clamp(header.start as i64, samples.len() as i64) as usize;
+                let data = samples.get(header.start..end).ok_or("SoundFont sample is out of range")?.to_vec();
+                let loop_points = (header.loop_end > header.loop_start)
+                    .then(|| (header.loop_start.saturating_sub(header.start), header.loop_end.saturating_sub(header.start + 1)));
+                let sample = Arc::new(Wav { sample_rate: header.sample_rate, channels: vec![data], loop_points });
+                cache.insert((sample_id as usize, end_offset), sample.clone());
+                sample
+            },
+        };
+
+        let mut region = Region::new(sample);
+        region.keys = low_key..=high_key;
+        region.velocities = low_velocity..=high_velocity;
+        region.root_key = match instrument.amount(ROOT_KEY) {
+            Some(key @ 0..=127) => key as MidiNote,
+            _ if header.original_pitch <= 127 => header.original_pitch,
+            _ => 60,
+        };
+        region.tune = (value(COARSE_TUNE, 0) * 100 + value(FINE_TUNE, 0) + header.pitch_correction as i64) as f64;
+        region.pitch_keytrack = value(SCALE_TUNING, 100) as f64;
+        region.gain = f64::powf(10.0, -value(ATTENUATION, 0).max(0) as f64 / 200.0);
+        region.pan = (value(PAN, 0) as f64 / 500.0).clamp(-1.0, 1.0);
+
+        // Loop points are relative to the start of the sample, and the end is exclusive.
+        region.offset = offset(START_OFFSET, START_COARSE_OFFSET).max(0) as usize;
+        region.loop_points = region.sample.valid_loop(region.loop_points.map(|(start, end)| {
+            let start = start as i64 + offset(LOOP_START_OFFSET, LOOP_START_COARSE_OFFSET);
+            let end = end as i64 + offset(LOOP_END_OFFSET, LOOP_END_COARSE_OFFSET);
+            (start.max(0) as usize, end.max(start).max(0) as usize)
+        }));
+        region.loop_mode = match instrument.amount(SAMPLE_MODES).unwrap_or(0) & 3 {
+            1 => LoopMode::Continuous,
+            3 => LoopMode::Sustain,
+            _ => LoopMode::NoLoop,
+        };
+
+        // The sustain level is an attenuation in centibels, and the decay and release fall in
+        // decibels, so they're curved to fall quickly at first.
+        let envelope = &mut region.envelope;
+        envelope.attack = seconds(ATTACK);
+        envelope.decay = seconds(DECAY);
+        envelope.sustain = f64::powf(10.0, -value(SUSTAIN, 0).clamp(0, 1440) as f64 / 200.0);
+        envelope.release = seconds(RELEASE);
+        envelope.decay_curve = Curve::Exponential(5.0);
+        envelope.release_curve = Curve::Exponential(5.0);
+        // Velocity is handled by the modulators instead.
+        envelope.velocity_sensitivity = 0.0;
+
+        // Instrument modulators replace identical default modulators, and preset modulators are
+        // added on top.
+        let modulators = override_modulators(&DEFAULT_MODULATORS, &instrument.modulators);
+        region.modulations = modulators.iter().chain(&preset.modulators)
+            .filter_map(Modulator::note_modulation)
+            .collect();
+
+        Ok(Some(region))
+    }
+}
+
+/// Read the zones from the bags, generators and modulators, including the terminal bag.
+fn read_zones(bags: &[&[u8]], generators: &[&[u8]], modulators: &[&[u8]]) -> Result<Vec<Zone>, Box<dyn Error>> {
+    bags.windows(2).map(|pair| {
+        let generator_range = read_u16(pair[0], 0) as usize..read_u16(pair[1], 0) as usize;
+        let modulator_range = read_u16(pair[0], 2) as usize..read_u16(pair[1], 2) as usize;
+        let generators = generators.get(generator_range).ok_or("SoundFont generator index is out of range")?;
+        let modulators = modulators.get(modulator_range).ok_or("SoundFont modulator index is out of range")?;
+        Ok(Zone {
+            generators: generators.iter().map(|record| (read_u16(record, 0), [record[2], record[3]])).collect(),
+            modulators: modulators.iter().map(|record| Modulator {
+                source: read_u16(record, 0),
+                destination: read_u16(record, 2),
+                amount: read_u16(record, 4) as i16,
+                amount_source: read_u16(record, 6),
+                transform: read_u16(record, 8),
+            }).collect(),
+        })
+    }).collect()
+}
+
+/// Create a SoundFont node, which plays the preset for the program of each note.
+pub fn soundfont(time: &mut Continuous<Time>,
+                 note: &Continuous<MidiNote>,
+                 frequency: &Continuous<Frequency>,
+                 gate: &Continuous<bool>,
+                 velocity: &Continuous<Velocity>,
+                 program: &Continuous<Program>,
+                 soundfont: Arc<SoundFont>)
+    -> Continuous<Frame>
+{
+    let mut clock = Clock::new();
+    let silence = Arc::new(Instrument::default());
+    let mut voice = SamplerVoice::new(silence.clone());
+    let mut previous_gate = false;
+    snapshot5(time, note, frequency, gate, velocity, program, move |time, note, frequency, gate, velocity, program| {
+        let time_step = clock.tick(time);
+        if gate && !previous_gate {
+            let instrument = soundfont.preset(program).map_or_else(|| silence.clone(), |preset| preset.instrument.clone());
+            voice.set_instrument(instrument);
+        }
+        previous_gate = gate;
+        voice.step(note, frequency, gate, velocity, time_step)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = kind.to_vec();
+        chunks.iter().for_each(|chunk| body.extend_from_slice(chunk));
+        chunk(b"LIST", &body)
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(20, 0);
+        bytes
+    }
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    fn generator(operator: u16, amount: i16) -> Vec<u8> {
+        u16s(&[operator, amount as u16])
+    }
+
+    fn range(operator: u16, low: u8, high: u8) -> Vec<u8> {
+        [u16s(&[operator]), vec![low, high]].concat()
+    }
+
+    fn preset_header(preset_name: &str, number: u16, bank: u16, bag: u16) -> Vec<u8> {
+        [name(preset_name), u16s(&[number, bank, bag]), vec![0; 12]].concat()
+    }
+
+    fn sample_header(start: u32, end: u32, loop_start: u32, loop_end: u32, pitch: u8, correction: i8) -> Vec<u8> {
+        let mut bytes = name("sample");
+        [start, end, loop_start, loop_end, 22050].iter().for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+        bytes.extend_from_slice(&[pitch, correction as u8]);
+        bytes.extend_from_slice(&u16s(&[0, 1]));
+        bytes
+    }
+
+    /// A SoundFont with a piano preset, whose instrument has a global zone and two sample zones,
+    /// and a drum kit.
+    fn soundfont_bytes() -> Vec<u8> {
+        let samples = u16s(&(0..100).map(|i| (i * 100) as u16).collect::<Vec<_>>());
+
+        let phdr = [preset_header("Piano", 0, 0, 0), preset_header("Drums", 0, 128, 1), preset_header("EOP", 0, 0, 2)].concat();
+        let pbag = u16s(&[0, 0, 3, 0, 4, 1]);
+        // The piano is transposed up an octave with a velocity range, the drums have a modulator
+        // which pans by key.
+        let pgen = [range(VELOCITY_RANGE, 10, 127), generator(COARSE_TUNE, 12), generator(INSTRUMENT, 0), generator(INSTRUMENT, 1), vec![0; 4]].concat();
+        let pmod = [u16s(&[SOURCE_KEY, PAN, 100, 0, 0]), vec![0; 10]].concat();
+
+        let inst = [name("Piano"), u16s(&[0]), name("Drums"), u16s(&[3]), name("EOI"), u16s(&[4])].concat();
+        let ibag = u16s(&[0, 0, 2, 0, 6, 0, 8, 0, 9, 0]);
+        let igen = [
+            // Global zone.
+            generator(ATTENUATION, 60), generator(RELEASE, 0),
+            // Low zone.
+            range(KEY_RANGE, 0, 59), generator(FINE_TUNE, -10), generator(ATTENUATION, 120), generator(SAMPLE_ID, 0),
+            // High zone, looping.
+            generator(SAMPLE_MODES, 1), generator(SAMPLE_ID, 1),
+            // Drum zone.
+            generator(SAMPLE_ID, 0),
+            vec![0; 4],
+        ].concat();
+        let imod = vec![0; 10];
+        let shdr = [sample_header(0, 50, 0, 0, 60, 0), sample_header(50, 100, 60, 90, 72, 5), vec![0; 46]].concat();
+
+        let body = [
+            b"sfbk".to_vec(),
+            list(b"INFO", &[chunk(b"INAM", b"Test\0")]),
+            list(b"sdta", &[chunk(b"smpl", &samples)]),
+            list(b"pdta", &[
+                chunk(b"phdr", &phdr), chunk(b"pbag", &pbag), chunk(b"pmod", &pmod), chunk(b"pgen", &pgen),
+                chunk(b"inst", &inst), chunk(b"ibag", &ibag), chunk(b"imod", &imod), chunk(b"igen", &igen),
+                chunk(b"shdr", &shdr),
+            ]),
+        ].concat();
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn test_parse() {
+        let soundfont = SoundFont::parse(&soundfont_bytes()).unwrap();
+        assert_eq!(soundfont.name, "Test");
+        assert_eq!(soundfont.presets.len(), 2);
+
+        let piano = soundfont.preset(Program { bank: 0, number: 0 }).unwrap();
+        assert_eq!(piano.name, "Piano");
+        let [low, high] = &piano.instrument.regions[..] else { panic!("expected two regions") };
+
+        // The global zone's attenuation is replaced by the low zone's, and the preset's tuning is
+        // added to the instrument's.
+        assert_eq!(low.keys, 0..=59);
+        assert_eq!(low.velocities, 10..=127);
+        assert_eq!(low.root_key, 60);
+        assert_relative_eq!(low.tune, 1190.0);
+        assert_relative_eq!(low.gain, f64::powf(10.0, -120.0 / 200.0));
+        assert_relative_eq!(low.envelope.release, 1.0);
+        assert_eq!(low.loop_mode, LoopMode::NoLoop);
+        assert_eq!(low.sample.frame_count(), 50);
+        assert_eq!(low.sample.sample_rate, 22050);
+        assert_relative_eq!(low.sample.channels[0][1], 100.0 / 32768.0);
+
+        assert_eq!(high.keys, 0..=127);
+        assert_eq!(high.root_key, 72);
+        assert_relative_eq!(high.tune, 1205.0);
+        assert_relative_eq!(high.gain, f64::powf(10.0, -60.0 / 200.0));
+        assert_eq!(high.loop_mode, LoopMode::Continuous);
+        assert_eq!(high.loop_points, Some((10, 39)));
+
+        // Every region should have the standard velocity modulator.
+        let velocity = NoteModulation {
+            source: NoteSource::Velocity,
+            curve: ModulationCurve::Concave,
+            bipolar: false,
+            inverted: true,
+            target: ModulationTarget::Gain,
+            amount: -96.0,
+        };
+        assert_eq!(low.modulations, vec![velocity]);
+    }
+
+    #[test]
+    fn test_preset_fallback() {
+        let soundfont = SoundFont::parse(&soundfont_bytes()).unwrap();
+
+        // Unknown banks fall back to the first bank, and unknown kits to the first kit.
+        assert_eq!(soundfont.preset(Program { bank: 5, number: 0 }).unwrap().name, "Piano");
+        let drums = soundfont.preset(Program { bank: Program::PERCUSSION_BANK, number: 16 }).unwrap();
+        assert_eq!(drums.name, "Drums");
+        assert!(soundfont.preset(Program { bank: 0, number: 1 }).is_none());
+
+        // The drum kit's preset modulator pans by key.
+        let region = &drums.instrument.regions[0];
+        assert_eq!(region.modulations.len(), 2);
+        assert_eq!(region.modulations[1].target, ModulationTarget::Pan);
+        assert_relative_eq!(region.modulations[1].amount, 0.2);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(SoundFont::parse(b"not a soundfont").is_err());
+        // A file without its preset data.
+        let bytes = chunk(b"RIFF", &[b"sfbk".to_vec(), list(b"sdta", &[chunk(b"smpl", &[0; 4])])].concat());
+        assert!(SoundFont::parse(&bytes).is_err());
+
+        // A sample which starts beyond the end of the sample data, as in a truncated file.
+        let mut bytes = soundfont_bytes();
+        let shdr = bytes.windows(4).position(|id| id == b"shdr").unwrap();
+        let start = shdr + 8 + 20;
+        bytes[start..start + 4].copy_from_slice(&200u32.to_le_bytes());
+        assert!(SoundFont::parse(&bytes).is_err());
+    }
+}
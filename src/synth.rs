@@ -1,19 +1,226 @@
 //! Simple synth host that samples a network and outputs samples to a ring buffer at a given sample
 //! rate.
 
-use std::collections::HashSet;
-use std::{thread::JoinHandle, mem::MaybeUninit, time::Duration};
+use std::{thread::JoinHandle, time::Duration};
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}, mpsc::Receiver};
 
 use midi_control::MidiMessage;
-use ringbuf::{Producer, SharedRb};
+use ringbuf::HeapProducer;
 
+use crate::effects::{Effect, EffectsRack};
+use crate::mts::TuningMessage;
 use crate::signal::{Continuous, Discrete};
+use crate::tuning::Tuning;
+use crate::types::{Frame, MidiNote, Time, Velocity};
 
 /// The amount of time for the thread to sleep between processing new midi inputs and re-filling
 /// the output ringbuffer.
 const THREAD_SLEEP: Duration = Duration::from_millis(1);
 
+/// The number of midi channels.
+const CHANNEL_COUNT: usize = 16;
+
+/// The channel that General MIDI reserves for percussion (channel 10, counting from 1).
+const PERCUSSION_CHANNEL: u8 = 9;
+
+/// The bank select controllers.
+const BANK_SELECT: u8 = 0;
+
+/// The mod wheel controller.
+const MOD_WHEEL: u8 = 1;
+
+/// A bank and program number, chosen on each channel with bank select and program change messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    pub bank: u16,
+    pub number: u8,
+}
+
+impl Program {
+    /// The bank used for every program on the percussion channel, following the SoundFont
+    /// convention.
+    pub const PERCUSSION_BANK: u16 = 128;
+}
+
+/// The input signals for a single voice of a synth network, which are driven by the midi synth.
+#[derive(Clone, Default)]
+pub struct VoiceInput {
+    /// The note the voice is playing. This is held after the note is released so that the voice
+    /// keeps its pitch while its envelopes release.
+    pub note: Discrete<MidiNote>,
+    /// Whether the note is currently held down.
+    pub gate: Discrete<bool>,
+    /// The velocity of the most recent note on.
+    pub velocity: Discrete<Velocity>,
+    /// The program selected on the channel of the most recent note on.
+    pub program: Discrete<Program>,
+    /// The position of the mod wheel on the voice's channel, from 0 to 1.
+    pub mod_wheel: Discrete<f64>,
+}
+
+impl VoiceInput {
+    /// Create a new set of voice inputs with no values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// The input signals of a synth network, which are driven by the midi synth.
+#[derive(Clone, Default)]
+pub struct SynthInputs {
+    /// The current time in seconds, which is pushed once per sample.
+    pub time: Discrete<Time>,
+    /// The inputs for each voice.
+    pub voices: Vec<VoiceInput>,
+    /// The tuning used to map notes to frequencies.
+    pub tuning: Discrete<Tuning>,
+}
+
+impl SynthInputs {
+    /// Create a new set of synth inputs with the given number of voices.
+    pub fn new(voice_count: usize) -> Self {
+        Self {
+            time: Discrete::new(),
+            voices: std::iter::repeat_with(VoiceInput::new).take(voice_count).collect(),
+            tuning: Discrete::new(),
+        }
+    }
+}
+
+/// A synth network: the input signals driven by the midi synth, and the stereo output signal that
+/// is sampled to get the synth's audio.
+pub struct SynthNetwork {
+    pub inputs: SynthInputs,
+    pub output: Continuous<Frame>,
+}
+
+/// The state of a single voice, as tracked by the voice allocator.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VoiceState {
+    pub channel: u8,
+    pub note: MidiNote,
+    pub gate: bool,
+    pub velocity: Velocity,
+    pub program: Program,
+    /// A note that's waiting to be played on this voice once its gate has been closed for a
+    /// sample, so that envelopes see a new note on when a held voice is stolen.
+    pending: Option<PendingNote>,
+    /// When the voice was last triggered or released, used to find the oldest voice.
+    age: u64,
+}
+
+/// A note on that's waiting for its voice.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PendingNote {
+    channel: u8,
+    note: MidiNote,
+    velocity: Velocity,
+    program: Program,
+}
+
+impl VoiceState {
+    /// Start playing a note.
+    fn play(&mut self, pending: PendingNote) {
+        self.channel = pending.channel;
+        self.note = pending.note;
+        self.velocity = pending.velocity;
+        self.program = pending.program;
+        self.gate = true;
+    }
+}
+
+/// Assigns midi notes to a fixed number of voices, preferring voices that have been released for
+/// the longest, and stealing the oldest held voice when they're all in use.
+#[derive(Clone, Debug)]
+pub struct VoiceAllocator {
+    voices: Vec<VoiceState>,
+    counter: u64,
+}
+
+impl VoiceAllocator {
+    /// Create a new allocator with the given number of voices, which all start released.
+    pub fn new(voice_count: usize) -> Self {
+        Self {
+            voices: vec![VoiceState::default(); voice_count],
+            counter: 0,
+        }
+    }
+
+    /// The current state of each voice.
+    pub fn voices(&self) -> &[VoiceState] {
+        &self.voices
+    }
+
+    /// Assign a note on a channel to a voice. Returns the index of the voice the note was assigned
+    /// to, or None if there are no voices or the note isn't mapped in the tuning, which is silent.
+    pub fn note_on(&mut self,
+                   channel: u8,
+                   note: MidiNote,
+                   velocity: Velocity,
+                   program: Program,
+                   tuning: &Tuning)
+        -> Option<usize>
+    {
+        tuning.frequency(note)?;
+        self.counter += 1;
+        let pending = PendingNote { channel, note, velocity, program };
+
+        // Prefer a voice already playing this note, then the longest released voice, then the
+        // oldest held voice.
+        let index = self.voices.iter()
+            .position(|voice| voice.gate && voice.channel == channel && voice.note == note)
+            .or_else(|| self.oldest(|voice| !voice.gate && voice.pending.is_none()))
+            .or_else(|| self.oldest(|_| true))?;
+
+        let voice = &mut self.voices[index];
+        voice.age = self.counter;
+        if voice.gate {
+            // Close the gate for a sample before playing the new note.
+            voice.gate = false;
+            voice.pending = Some(pending);
+        }
+        else {
+            voice.play(pending);
+            voice.pending = None;
+        }
+
+        Some(index)
+    }
+
+    /// Release the voice playing the given note on a channel, if there is one.
+    pub fn note_off(&mut self, channel: u8, note: MidiNote) {
+        self.counter += 1;
+        for voice in self.voices.iter_mut() {
+            if voice.pending.is_some_and(|pending| pending.channel == channel && pending.note == note) {
+                voice.pending = None;
+                voice.age = self.counter;
+            }
+            else if voice.gate && voice.channel == channel && voice.note == note {
+                voice.gate = false;
+                voice.age = self.counter;
+            }
+        }
+    }
+
+    /// Advance by one sample, playing any notes that were waiting for their voice's gate to close.
+    pub fn advance(&mut self) {
+        for voice in self.voices.iter_mut() {
+            if let Some(pending) = voice.pending.take() {
+                voice.play(pending);
+            }
+        }
+    }
+
+    /// Find the index of the oldest voice that matches the given predicate.
+    fn oldest<P: Fn(&VoiceState) -> bool>(&self, predicate: P) -> Option<usize> {
+        self.voices.iter()
+            .enumerate()
+            .filter(|(_, voice)| predicate(voice))
+            .min_by_key(|(_, voice)| voice.age)
+            .map(|(index, _)| index)
+    }
+}
+
 /// A midi synth that accepts midi input and samples one or more oscillators to produce audio samples.
 pub struct MidiSynth {
     thread_run: Arc<AtomicBool>,
@@ -23,16 +230,30 @@ pub struct MidiSynth {
 impl MidiSynth {
     /// Create a new midi synth controlled by midi messages, producing samples to the
     /// given ring buffer, at the given sample rate and number of channels.
+    ///
+    /// The synth starts with the given tuning, which it pushes to the tuning input, and updates it
+    /// when it receives MIDI tuning standard messages.
+    ///
+    /// Each channel's program is set by bank select (the most significant byte only) and program
+    /// change messages, and given to the voices that play its notes. The percussion channel always
+    /// uses the percussion bank, as General MIDI expects. Each channel's mod wheel is given to the
+    /// voices playing its notes in the same way.
+    ///
+    /// The output of the network is passed through the effects rack, which is prepared for the
+    /// sample rate before the thread starts, before being written to the ring buffer. Mono devices
+    /// get the average of both channels, and devices with more than two channels get the stereo
+    /// pair repeated.
     pub fn new(receiver: Receiver<MidiMessage>,
-               mut prod: Producer<f32, Arc<SharedRb<f32, Vec<MaybeUninit<f32>>>>>,
+               mut prod: HeapProducer<f32>,
                sample_rate: usize,
                channel_count: usize,
-               mut input_time: Discrete<f64>,
-               mut input_notes: Vec<Discrete<u8>>,
-               network: Continuous<f64>)
+               network: SynthNetwork,
+               mut tuning: Tuning,
+               mut effects: EffectsRack)
         -> Self
     {
         log::info!("Starting midi synth thread");
+        effects.prepare(sample_rate as f64);
 
         // Create atomic bool for controlling thread exit.
         let thread_run = Arc::new(AtomicBool::new(true));
@@ -42,21 +263,58 @@ impl MidiSynth {
         let time_step = 1.0 / sample_rate as f64;
 
         let mut time = 0.0;
-        let mut voices: HashSet<u8> = HashSet::new();
+        let SynthNetwork { mut inputs, output } = network;
+        let mut voices = VoiceAllocator::new(inputs.voices.len());
+        let mut programs = [Program::default(); CHANNEL_COUNT];
+        programs[PERCUSSION_CHANNEL as usize].bank = Program::PERCUSSION_BANK;
+        let mut mod_wheels = [0.0; CHANNEL_COUNT];
 
         let thread_handle = std::thread::spawn(move || {
+            inputs.tuning.push(tuning.clone());
+
             // Run until cancellation requested.
             while thread_run_clone.load(Ordering::Relaxed) {
                 // Receive new midi notes.
                 while let Ok(msg) = receiver.try_recv() {
                     match msg {
-                        MidiMessage::NoteOn(_, e) => {
+                        // A note on with a velocity of 0 is a note off.
+                        MidiMessage::NoteOn(channel, e) if e.value > 0 => {
                             log::debug!("Got note down: {}", e.key);
-                            voices.insert(e.key);
+                            let channel = channel as u8;
+                            let program = programs.get(channel as usize).copied().unwrap_or_default();
+                            voices.note_on(channel, e.key, e.value as Velocity / 127.0, program, &tuning);
                         },
-                        MidiMessage::NoteOff(_, e) => {
+                        MidiMessage::NoteOn(channel, e) | MidiMessage::NoteOff(channel, e) => {
                             log::debug!("Got note up: {}", e.key);
-                            voices.remove(&e.key);
+                            voices.note_off(channel as u8, e.key);
+                        },
+                        MidiMessage::ProgramChange(channel, number) => {
+                            log::debug!("Got program change: {number}");
+                            if let Some(program) = programs.get_mut(channel as usize) {
+                                program.number = number;
+                            }
+                        },
+                        MidiMessage::ControlChange(channel, e) if e.control == BANK_SELECT => {
+                            log::debug!("Got bank select: {}", e.value);
+                            if let Some(program) = programs.get_mut(channel as usize) {
+                                if channel as u8 != PERCUSSION_CHANNEL {
+                                    program.bank = e.value as u16;
+                                }
+                            }
+                        },
+                        MidiMessage::ControlChange(channel, e) if e.control == MOD_WHEEL => {
+                            log::debug!("Got mod wheel: {}", e.value);
+                            if let Some(mod_wheel) = mod_wheels.get_mut(channel as usize) {
+                                *mod_wheel = e.value as f64 / 127.0;
+                            }
+                        },
+                        // Retune notes, including those that are currently playing.
+                        MidiMessage::SysEx(e) => {
+                            if let Some(message) = TuningMessage::parse(&e) {
+                                log::debug!("Got tuning message: {:?}", message);
+                                message.apply(&mut tuning);
+                                inputs.tuning.push(tuning.clone());
+                            }
                         },
                         _ => {}
                     }
@@ -64,37 +322,35 @@ impl MidiSynth {
 
                 // Fill audio buffer.
                 while prod.free_len() > channel_count {
-                    // A simple averaging coefficient so that the audio doesn't clip
-                    // TODO: figure out the 'proper' way to mix multiple voices.
-                    //let sample_coeff = if voices.is_empty() { 0.0 } else { 1.0 / voices.len() as f64 };
+                    // Update input for each voice before the time, so that nodes stepped by the
+                    // time see the new values this sample.
+                    for (input_voice, voice) in inputs.voices.iter_mut().zip(voices.voices()) {
+                        input_voice.note.push(voice.note);
+                        input_voice.velocity.push(voice.velocity);
+                        input_voice.program.push(voice.program);
+                        input_voice.mod_wheel.push(mod_wheels.get(voice.channel as usize).copied().unwrap_or_default());
+                        input_voice.gate.push(voice.gate);
+                    }
+                    voices.advance();
 
                     // Update time
                     time += time_step;
-                    input_time.push(time);
-
-                    // Update input for each voice
-                    let voices: Vec<u8> = voices.iter().map(|x| *x).collect();
-                    for (i, input_note) in input_notes.iter_mut().enumerate() {
-                        if i < voices.len() {
-                            input_note.push(voices[i]);
-                        }
-                        else {
-                            input_note.push(0);
-                        }
-                    }
+                    inputs.time.push(time);
+
+                    // Sample network and apply effects.
+                    let frame = output.sample().unwrap_or([0.0; 2]);
+                    // Clamp to full scale, as the effects after the limiter can still overshoot
+                    // a little.
+                    let frame = effects.process(frame, sample_rate as f64).map(|sample| sample.clamp(-1.0, 1.0));
 
-                    // Sample network
-                    let sample;
-                    if let Some(new_sample) = network.sample() {
-                        sample = new_sample;
+                    // Push one sample for each channel.
+                    if channel_count == 1 {
+                        prod.push(((frame[0] + frame[1]) * 0.5) as f32).ok();
                     }
                     else {
-                        sample = 0.0;
+                        let mut samples = (0..channel_count).map(|channel| frame[channel % 2] as f32);
+                        prod.push_iter(&mut samples);
                     }
-
-                    // Push one sample for each channel.
-                    let mut samples = std::iter::repeat(sample as f32).take(channel_count);
-                    prod.push_iter(&mut samples);
                 }
 
                 // Sleep for a few ms so we aren't just spinning.
@@ -119,3 +375,79 @@ impl Drop for MidiSynth {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tuning::{KeyboardMapping, Scale};
+
+    #[test]
+    fn test_voice_allocation() {
+        let tuning = Tuning::default();
+        let mut voices = VoiceAllocator::new(2);
+
+        // Notes should go to free voices, and the same note should go back to its voice.
+        assert_eq!(voices.note_on(0, 60, 1.0, Program::default(), &tuning), Some(0));
+        assert_eq!(voices.note_on(0, 64, 1.0, Program::default(), &tuning), Some(1));
+        voices.note_off(0, 60);
+        assert_eq!(voices.voices()[0].note, 60);
+        assert!(!voices.voices()[0].gate);
+        assert_eq!(voices.note_on(0, 67, 1.0, Program::default(), &tuning), Some(0));
+        assert!(voices.voices()[0].gate);
+
+        // When all voices are held, the oldest should be stolen, and its gate should close for a
+        // sample before the new note plays.
+        assert_eq!(voices.note_on(0, 72, 0.5, Program::default(), &tuning), Some(1));
+        assert_eq!(voices.voices()[1].note, 64);
+        assert!(!voices.voices()[1].gate);
+        voices.advance();
+        assert_eq!(voices.voices()[1].note, 72);
+        assert_eq!(voices.voices()[1].velocity, 0.5);
+        assert!(voices.voices()[1].gate);
+    }
+
+    #[test]
+    fn test_voice_allocation_pending_note_off() {
+        let tuning = Tuning::default();
+        let mut voices = VoiceAllocator::new(1);
+        voices.note_on(0, 60, 1.0, Program::default(), &tuning);
+        voices.note_on(0, 62, 1.0, Program::default(), &tuning);
+
+        // Releasing a note before it's played should cancel it.
+        voices.note_off(0, 62);
+        voices.advance();
+        assert!(!voices.voices()[0].gate);
+    }
+
+    #[test]
+    fn test_voice_allocation_channels() {
+        let tuning = Tuning::default();
+        let mut voices = VoiceAllocator::new(2);
+        let drums = Program { bank: Program::PERCUSSION_BANK, number: 0 };
+
+        // The same note on different channels should play on separate voices, with their own
+        // programs, and only be released by a note off on the same channel.
+        assert_eq!(voices.note_on(0, 60, 1.0, Program::default(), &tuning), Some(0));
+        assert_eq!(voices.note_on(PERCUSSION_CHANNEL, 60, 1.0, drums, &tuning), Some(1));
+        assert_eq!(voices.voices()[1].program, drums);
+        voices.note_off(PERCUSSION_CHANNEL, 60);
+        assert!(voices.voices()[0].gate);
+        assert!(!voices.voices()[1].gate);
+    }
+
+    #[test]
+    fn test_voice_allocation_unmapped() {
+        // A note that isn't mapped in the tuning shouldn't open any voice's gate, so it's silent.
+        let mapping = KeyboardMapping { first_note: 48, last_note: 72, ..KeyboardMapping::default() };
+        let mut tuning = Tuning::from_scale(&Scale::equal_temperament(12), &mapping).unwrap();
+        assert_eq!(tuning.frequency(40), None);
+        let mut voices = VoiceAllocator::new(2);
+        assert_eq!(voices.note_on(0, 40, 1.0, Program::default(), &tuning), None);
+        voices.advance();
+        assert!(voices.voices().iter().all(|voice| !voice.gate));
+
+        // Mapped notes still play, and retuning a note maps it.
+        assert_eq!(voices.note_on(0, 60, 1.0, Program::default(), &tuning), Some(0));
+        tuning.set_frequency(40, 82.0);
+        assert_eq!(voices.note_on(0, 40, 1.0, Program::default(), &tuning), Some(1));
+    }
+}
@@ -0,0 +1,387 @@
+//! Tuning tables which map midi notes to frequencies, with support for loading Scala scale (.scl)
+//! and keyboard mapping (.kbm) files.
+//!
+//! See https://www.huygens-fokker.org/scala/scl_format.html and
+//! https://www.huygens-fokker.org/scala/help.htm#mappings for the file formats.
+
+use std::{error::Error, path::Path, sync::Arc};
+
+use crate::types::{Frequency, MidiNote};
+
+/// The number of midi notes.
+const NOTE_COUNT: usize = 128;
+
+/// A scale, as a list of pitches in cents above the root note. The last pitch is the period of the
+/// scale (usually an octave), which the scale repeats at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    pub pitches: Vec<f64>,
+}
+
+impl Scale {
+    /// Create a scale of `steps` equal divisions of the octave.
+    pub fn equal_temperament(steps: usize) -> Self {
+        Self {
+            description: format!("{steps} tone equal temperament"),
+            pitches: (1..=steps).map(|step| 1200.0 * step as f64 / steps as f64).collect(),
+        }
+    }
+
+    /// Parse a scale from the contents of a Scala .scl file.
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut lines = scala_lines(text);
+
+        let description = lines.next().ok_or("Scale file is missing a description")?.to_string();
+        let count: usize = lines.next()
+            .ok_or("Scale file is missing a note count")?
+            .split_whitespace()
+            .next()
+            .ok_or("Scale file is missing a note count")?
+            .parse()?;
+
+        let pitches = lines.take(count)
+            .map(parse_pitch)
+            .collect::<Result<Vec<f64>, Box<dyn Error>>>()?;
+        if pitches.len() != count {
+            return Err(format!("Scale file has {} pitches but should have {count}", pitches.len()).into());
+        }
+        if count == 0 {
+            return Err("Scale file must have at least one pitch".into());
+        }
+
+        Ok(Self { description, pitches })
+    }
+
+    /// Load a scale from a Scala .scl file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// The number of notes in each period of the scale.
+    pub fn len(&self) -> usize {
+        self.pitches.len()
+    }
+
+    /// Whether the scale has no pitches (which isn't valid for tuning).
+    pub fn is_empty(&self) -> bool {
+        self.pitches.is_empty()
+    }
+
+    /// The pitch of the given scale degree in cents above the root, where degree 0 is the root and
+    /// degrees beyond the length of the scale continue into the next periods.
+    pub fn cents(&self, degree: i64) -> f64 {
+        let len = self.pitches.len() as i64;
+        let (period, step) = (degree.div_euclid(len), degree.rem_euclid(len));
+        let period_cents = self.pitches[self.pitches.len() - 1];
+        let step_cents = if step == 0 { 0.0 } else { self.pitches[step as usize - 1] };
+        period as f64 * period_cents + step_cents
+    }
+}
+
+/// Parse a single pitch line from a scale file, which is either a value in cents (if it contains a
+/// period) or a ratio. Anything after the value is ignored.
+fn parse_pitch(line: &str) -> Result<f64, Box<dyn Error>> {
+    let value = line.split_whitespace().next().ok_or("Empty pitch in scale file")?;
+    if value.contains('.') {
+        return Ok(value.parse()?);
+    }
+
+    let ratio = match value.split_once('/') {
+        Some((numerator, denominator)) => numerator.parse::<f64>()? / denominator.parse::<f64>()?,
+        None => value.parse::<f64>()?,
+    };
+    if !(ratio > 0.0 && ratio.is_finite()) {
+        return Err(format!("Invalid ratio {value} in scale file").into());
+    }
+    Ok(1200.0 * ratio.log2())
+}
+
+/// Iterate over the lines of a Scala file, skipping comments.
+fn scala_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('!'))
+}
+
+/// A keyboard mapping, which determines which scale degree each midi note plays and which note is
+/// tuned to the reference frequency.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyboardMapping {
+    /// The first and last notes to retune. Notes outside this range are unmapped.
+    pub first_note: MidiNote,
+    pub last_note: MidiNote,
+    /// The note which plays the first entry of the mapping (usually the root of the scale).
+    pub middle_note: MidiNote,
+    /// The note which is tuned to `reference_frequency`.
+    pub reference_note: MidiNote,
+    pub reference_frequency: Frequency,
+    /// The scale degree which the mapping repeats at, or 0 to use the scale's period.
+    pub octave_degree: usize,
+    /// The scale degree played by each key in a repeat of the mapping, or None if the key is
+    /// unmapped. An empty mapping maps every key to consecutive scale degrees.
+    pub mapping: Vec<Option<usize>>,
+}
+
+impl KeyboardMapping {
+    /// A mapping which plays consecutive scale degrees on consecutive keys, with the root of the
+    /// scale on `middle_note` and `reference_note` tuned to `reference_frequency`.
+    pub fn linear(middle_note: MidiNote, reference_note: MidiNote, reference_frequency: Frequency) -> Self {
+        Self {
+            first_note: 0,
+            last_note: (NOTE_COUNT - 1) as MidiNote,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+
+    /// Parse a keyboard mapping from the contents of a Scala .kbm file.
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut lines = scala_lines(text).filter(|line| !line.is_empty());
+        let mut next_value = |name: &str| -> Result<&str, Box<dyn Error>> {
+            lines.next()
+                .and_then(|line| line.split_whitespace().next())
+                .ok_or_else(|| format!("Keyboard mapping file is missing the {name}").into())
+        };
+
+        let size: usize = next_value("map size")?.parse()?;
+        let first_note = next_value("first note")?.parse()?;
+        let last_note = next_value("last note")?.parse()?;
+        let middle_note = next_value("middle note")?.parse()?;
+        let reference_note = next_value("reference note")?.parse()?;
+        let reference_frequency = next_value("reference frequency")?.parse()?;
+        let octave_degree = next_value("octave degree")?.parse()?;
+
+        let mapping = (0..size).map(|_| {
+            match next_value("mapping")? {
+                "x" | "X" => Ok(None),
+                degree => Ok(Some(degree.parse()?)),
+            }
+        }).collect::<Result<Vec<Option<usize>>, Box<dyn Error>>>()?;
+
+        Ok(Self {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree,
+            mapping,
+        })
+    }
+
+    /// Load a keyboard mapping from a Scala .kbm file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// The pitch of the given note in cents above the root of the scale, or None if it's unmapped.
+    fn cents(&self, scale: &Scale, note: MidiNote) -> Option<f64> {
+        if note < self.first_note || note > self.last_note {
+            return None;
+        }
+
+        let offset = note as i64 - self.middle_note as i64;
+        if self.mapping.is_empty() {
+            return Some(scale.cents(offset));
+        }
+
+        let size = self.mapping.len() as i64;
+        let (repeat, key) = (offset.div_euclid(size), offset.rem_euclid(size));
+        let degree = self.mapping[key as usize]?;
+        let octave_degree = if self.octave_degree == 0 { scale.len() } else { self.octave_degree };
+        Some(repeat as f64 * scale.cents(octave_degree as i64) + scale.cents(degree as i64))
+    }
+}
+
+impl Default for KeyboardMapping {
+    /// The standard mapping, with A4 tuned to 440Hz and the scale starting on C4.
+    fn default() -> Self {
+        Self::linear(60, 69, 440.0)
+    }
+}
+
+/// A table of the frequency of every midi note, or None for notes which aren't mapped and so
+/// shouldn't play.
+///
+/// The table is shared when cloned, so tunings can cheaply be passed around as signal values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    frequencies: Arc<[Option<Frequency>; NOTE_COUNT]>,
+}
+
+impl Tuning {
+    /// Create a tuning where every note has the given frequency.
+    pub fn from_frequencies(frequencies: [Frequency; NOTE_COUNT]) -> Self {
+        Self {
+            frequencies: Arc::new(frequencies.map(Some)),
+        }
+    }
+
+    /// Create a 12 tone equal tempered tuning with the given note tuned to the given frequency.
+    pub fn equal_temperament(reference_note: MidiNote, reference_frequency: Frequency) -> Self {
+        Self::from_frequencies(std::array::from_fn(|note| {
+            reference_frequency * f64::powf(2.0, (note as f64 - reference_note as f64) / 12.0)
+        }))
+    }
+
+    /// Create a tuning from a scale and a keyboard mapping. Unmapped notes have no frequency.
+    pub fn from_scale(scale: &Scale, mapping: &KeyboardMapping) -> Result<Self, Box<dyn Error>> {
+        if scale.is_empty() {
+            return Err("Scale must have at least one pitch".into());
+        }
+        let reference_cents = mapping.cents(scale, mapping.reference_note)
+            .ok_or("The reference note must be mapped to a scale degree")?;
+
+        let frequencies = std::array::from_fn(|note| {
+            mapping.cents(scale, note as MidiNote)
+                .map(|cents| mapping.reference_frequency * f64::powf(2.0, (cents - reference_cents) / 1200.0))
+        });
+        Ok(Self { frequencies: Arc::new(frequencies) })
+    }
+
+    /// Load a tuning from a Scala scale file and an optional keyboard mapping file. Without a
+    /// mapping file, the default mapping is used.
+    pub fn load<P: AsRef<Path>>(scale_path: P, mapping_path: Option<P>) -> Result<Self, Box<dyn Error>> {
+        let scale = Scale::load(scale_path)?;
+        let mapping = match mapping_path {
+            Some(path) => KeyboardMapping::load(path)?,
+            None => KeyboardMapping::default(),
+        };
+        Self::from_scale(&scale, &mapping)
+    }
+
+    /// Get the frequency of a midi note, or None if the note isn't mapped.
+    pub fn frequency(&self, note: MidiNote) -> Option<Frequency> {
+        self.frequencies[note as usize % NOTE_COUNT]
+    }
+
+    /// Retune a single midi note, which maps it if it wasn't already.
+    pub fn set_frequency(&mut self, note: MidiNote, frequency: Frequency) {
+        Arc::make_mut(&mut self.frequencies)[note as usize % NOTE_COUNT] = Some(frequency);
+    }
+}
+
+impl Default for Tuning {
+    /// Standard 12 tone equal temperament, with A4 tuned to 440Hz.
+    fn default() -> Self {
+        Self::equal_temperament(69, 440.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::functions::midi_note_to_frequency;
+
+    const PENTATONIC: &str = "! pentatonic.scl
+!
+Just pentatonic
+ 5
+!
+ 9/8
+ 5/4
+ 3/2
+ 5/3
+ 2/1
+";
+
+    #[test]
+    fn test_default_tuning() {
+        // The default tuning should match the standard function.
+        let tuning = Tuning::default();
+        for note in 0..=127 {
+            assert_relative_eq!(tuning.frequency(note).unwrap(), midi_note_to_frequency(note), epsilon = 1e-9);
+        }
+
+        // As should a 12 tone scale with the default mapping.
+        let tuning = Tuning::from_scale(&Scale::equal_temperament(12), &KeyboardMapping::default()).unwrap();
+        for note in 0..=127 {
+            assert_relative_eq!(tuning.frequency(note).unwrap(), midi_note_to_frequency(note), epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_parse_scale() {
+        let scale = Scale::parse(PENTATONIC).unwrap();
+        assert_eq!(scale.description, "Just pentatonic");
+        assert_eq!(scale.len(), 5);
+        assert_relative_eq!(scale.pitches[2], 701.955, epsilon = 0.001);
+        assert_relative_eq!(scale.pitches[4], 1200.0);
+
+        // Pitches can also be given in cents, or as whole numbers, with comments after them.
+        let scale = Scale::parse("Test\n3\n100.0 cents\n3/2 fifth\n2\n").unwrap();
+        assert_relative_eq!(scale.pitches[0], 100.0);
+        assert_relative_eq!(scale.pitches[2], 1200.0);
+
+        assert!(Scale::parse("Too short\n3\n100.0\n").is_err());
+        assert!(Scale::parse("Bad ratio\n1\n0/1\n").is_err());
+    }
+
+    #[test]
+    fn test_scale_tuning() {
+        // With a linear mapping and C4 as the root at 261.6Hz, the notes should step through the
+        // scale and then repeat an octave higher.
+        let scale = Scale::parse(PENTATONIC).unwrap();
+        let tuning = Tuning::from_scale(&scale, &KeyboardMapping::linear(60, 60, 261.6)).unwrap();
+        assert_relative_eq!(tuning.frequency(60).unwrap(), 261.6);
+        assert_relative_eq!(tuning.frequency(61).unwrap(), 261.6 * 9.0 / 8.0);
+        assert_relative_eq!(tuning.frequency(63).unwrap(), 261.6 * 3.0 / 2.0);
+        assert_relative_eq!(tuning.frequency(65).unwrap(), 261.6 * 2.0);
+        assert_relative_eq!(tuning.frequency(59).unwrap(), 261.6 * 5.0 / 6.0);
+    }
+
+    #[test]
+    fn test_keyboard_mapping() {
+        // Map the pentatonic scale to the black keys only, starting from C#4 at 432Hz.
+        let mapping = KeyboardMapping::parse("! black keys
+12
+0
+127
+60
+61
+432.0
+5
+! C  C# D  D# E  F  F# G  G# A  A# B
+x
+0
+x
+1
+x
+x
+2
+x
+3
+x
+4
+x
+").unwrap();
+        let scale = Scale::parse(PENTATONIC).unwrap();
+        let tuning = Tuning::from_scale(&scale, &mapping).unwrap();
+
+        assert_relative_eq!(tuning.frequency(61).unwrap(), 432.0);
+        assert_relative_eq!(tuning.frequency(63).unwrap(), 432.0 * 9.0 / 8.0);
+        assert_relative_eq!(tuning.frequency(70).unwrap(), 432.0 * 5.0 / 3.0);
+        assert_relative_eq!(tuning.frequency(73).unwrap(), 864.0);
+        assert_relative_eq!(tuning.frequency(49).unwrap(), 216.0);
+        assert_eq!(tuning.frequency(62), None);
+
+        // The reference note has to be mapped.
+        let unmapped = KeyboardMapping { reference_note: 62, ..mapping };
+        assert!(Tuning::from_scale(&scale, &unmapped).is_err());
+    }
+
+    #[test]
+    fn test_set_frequency() {
+        let tuning = Tuning::default();
+        let mut retuned = tuning.clone();
+        retuned.set_frequency(69, 432.0);
+        assert_eq!(retuned.frequency(69).unwrap(), 432.0);
+        assert_eq!(tuning.frequency(69).unwrap(), 440.0);
+        assert_ne!(tuning, retuned);
+    }
+}
@@ -11,3 +11,9 @@ pub type Sample = f64;
 
 /// A type representing a midi note.
 pub type MidiNote = u8;
+
+/// A type representing the velocity of a note, normalised to the range 0-1.
+pub type Velocity = f64;
+
+/// A type representing a stereo sample, with the left channel first.
+pub type Frame = [Sample; 2];
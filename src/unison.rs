@@ -0,0 +1,143 @@
+//! Unison oscillators, which play several detuned copies of an oscillator spread across the stereo
+//! field for thick leads and pads, such as the classic supersaw.
+
+use std::f64::consts::{FRAC_PI_4, SQRT_2};
+use std::sync::Arc;
+
+use crate::random::Rng;
+use crate::signal::{Continuous, lift2};
+use crate::types::{Frame, Frequency, Sample, Time};
+
+/// The settings of a unison oscillator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UnisonParams {
+    /// The number of copies of the oscillator.
+    pub count: usize,
+    /// The distance between the lowest and highest copies, in cents.
+    pub detune: f64,
+    /// How far the copies are spread across the stereo field, from 0 (all in the centre) to 1 (the
+    /// lowest fully left and the highest fully right).
+    pub stereo_spread: f64,
+    /// How much the starting phases of the copies are randomised, from 0 (all in phase) to 1 (any
+    /// phase).
+    pub phase_randomisation: f64,
+    /// The seed for the random phases.
+    pub seed: u64,
+}
+
+impl Default for UnisonParams {
+    fn default() -> Self {
+        Self {
+            count: 7,
+            detune: 30.0,
+            stereo_spread: 0.8,
+            phase_randomisation: 1.0,
+            seed: 1,
+        }
+    }
+}
+
+/// A single copy of the oscillator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct UnisonCopy {
+    /// The frequency of the copy as a multiple of the played frequency.
+    ratio: f64,
+    /// The phase offset of the copy, in cycles.
+    phase: f64,
+    /// The gain of the copy in the left and right channels.
+    gains: [f64; 2],
+}
+
+/// Work out the detune, phase and pan of each copy. The copies are normalised so that their total
+/// power is about the same as a single oscillator.
+fn copies(params: &UnisonParams) -> Vec<UnisonCopy> {
+    let count = params.count.max(1);
+    let normalisation = 1.0 / (count as f64).sqrt();
+    let mut rng = Rng::new(params.seed);
+
+    (0..count).map(|i| {
+        // Spread the copies evenly from -1 to 1, lowest first.
+        let position = if count > 1 { i as f64 / (count - 1) as f64 * 2.0 - 1.0 } else { 0.0 };
+
+        // Equal power panning, scaled so that the centre has unity gain in both channels.
+        let pan = position * params.stereo_spread.clamp(0.0, 1.0);
+        let angle = (pan + 1.0) * FRAC_PI_4;
+        let gain = normalisation * SQRT_2;
+
+        UnisonCopy {
+            ratio: f64::powf(2.0, position * params.detune / 2.0 / 1200.0),
+            phase: rng.next_f64() * params.phase_randomisation.clamp(0.0, 1.0),
+            gains: [angle.cos() * gain, angle.sin() * gain],
+        }
+    }).collect()
+}
+
+/// Create a unison oscillator from an oscillator function, such as `functions::saw_wave`.
+pub fn unison(time: &mut Continuous<Time>,
+              frequency: &mut Continuous<Frequency>,
+              oscillator: fn(Time, Frequency) -> Sample,
+              params: UnisonParams)
+    -> Continuous<Frame>
+{
+    let copies: Arc<[UnisonCopy]> = copies(&params).into();
+    lift2(time, frequency, move |time, frequency| {
+        copies.iter().fold([0.0; 2], |frame, copy| {
+            let frequency = frequency * copy.ratio;
+            // Offset the phase by shifting the time by a fraction of the copy's period.
+            let offset = if frequency > 0.0 { copy.phase / frequency } else { 0.0 };
+            let sample = oscillator(time + offset, frequency);
+            [frame[0] + sample * copy.gains[0], frame[1] + sample * copy.gains[1]]
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::functions::sine_wave;
+    use crate::signal::Discrete;
+
+    #[test]
+    fn test_copies() {
+        let params = UnisonParams { count: 3, detune: 20.0, stereo_spread: 1.0, phase_randomisation: 0.0, seed: 1 };
+        let copies = copies(&params);
+
+        // The outer copies should be 10 cents either side, and panned hard left and right.
+        assert_relative_eq!(copies[0].ratio, f64::powf(2.0, -10.0 / 1200.0));
+        assert_relative_eq!(copies[1].ratio, 1.0);
+        assert_relative_eq!(copies[2].ratio, f64::powf(2.0, 10.0 / 1200.0));
+        assert_relative_eq!(copies[0].gains[1], 0.0, epsilon = 1e-12);
+        assert_relative_eq!(copies[2].gains[0], 0.0, epsilon = 1e-12);
+        assert_relative_eq!(copies[1].gains[0], copies[1].gains[1]);
+        assert!(copies.iter().all(|copy| copy.phase == 0.0));
+
+        // Randomised phases should be the same for the same seed.
+        let params = UnisonParams { phase_randomisation: 1.0, ..params };
+        assert_eq!(super::copies(&params), super::copies(&params));
+        assert_ne!(super::copies(&params)[0].phase, super::copies(&params)[1].phase);
+    }
+
+    #[test]
+    fn test_unison() {
+        // A single copy should just be the oscillator in both channels.
+        let mut time = Discrete::new();
+        let mut frequency = Discrete::new();
+        let params = UnisonParams { count: 1, phase_randomisation: 0.0, ..UnisonParams::default() };
+        let output = unison(&mut time.hold(), &mut frequency.hold(), sine_wave, params);
+
+        frequency.push(100.0);
+        time.push(0.0025);
+        let frame = output.sample().unwrap();
+        assert_relative_eq!(frame[0], 1.0);
+        assert_relative_eq!(frame[1], 1.0);
+
+        // With no stereo spread, both channels should be the same.
+        let params = UnisonParams { stereo_spread: 0.0, ..UnisonParams::default() };
+        let output = unison(&mut time.hold(), &mut frequency.hold(), sine_wave, params);
+        frequency.push(200.0);
+        time.push(0.01);
+        let frame = output.sample().unwrap();
+        assert_relative_eq!(frame[0], frame[1]);
+    }
+}
@@ -0,0 +1,265 @@
+//! A channel vocoder, which imposes the spectral envelope of a modulator (usually a voice) onto the
+//! synth's output.
+//!
+//! Both signals are split into the same set of band-pass filters. The level of each modulator band
+//! is followed and used to set the level of the matching carrier band, so the synth takes on the
+//! shape of the modulator's vowels and consonants while keeping its own pitch.
+
+use std::sync::Arc;
+
+use ringbuf::HeapConsumer;
+
+use crate::dynamics::time_coefficient;
+use crate::effects::{mix_frames, Effect, Param};
+use crate::filter::{Biquad, Filter, FilterMode};
+use crate::sampler::interpolate;
+use crate::types::{Frame, Frequency, Sample};
+use crate::wav::Wav;
+
+/// The centre frequencies of the lowest and highest bands, which cover the range that matters for
+/// speech.
+const MIN_BAND_FREQUENCY: Frequency = 100.0;
+const MAX_BAND_FREQUENCY: Frequency = 8000.0;
+
+/// The level a full scale sine wave in a band is followed at, before correction. The band levels
+/// are scaled up by its inverse.
+const FOLLOWER_SINE_LEVEL: f64 = 2.0 / std::f64::consts::PI;
+
+/// Where the modulator comes from.
+pub enum Modulator {
+    /// A sample, which loops and is mixed down to mono.
+    Sample {
+        wav: Arc<Wav>,
+        /// The position in the sample, in frames.
+        position: f64,
+    },
+    /// A live input stream, such as from an audio input device. Silence is used if the stream
+    /// falls behind.
+    Live(HeapConsumer<f32>),
+}
+
+impl Modulator {
+    /// Create a modulator which loops a sample from its start.
+    pub fn sample(wav: Arc<Wav>) -> Self {
+        Modulator::Sample { wav, position: 0.0 }
+    }
+
+    /// Get the next modulator sample.
+    fn next(&mut self, sample_rate: f64) -> Sample {
+        match self {
+            Modulator::Sample { wav, position } => {
+                let frame_count = wav.frame_count();
+                if frame_count == 0 {
+                    return 0.0;
+                }
+                let sample = wav.channels.iter()
+                    .map(|channel| interpolate(channel, *position, None))
+                    .sum::<Sample>() / wav.channel_count() as f64;
+                *position = (*position + wav.sample_rate as f64 / sample_rate) % frame_count as f64;
+                sample
+            },
+            Modulator::Live(consumer) => consumer.pop().map_or(0.0, |sample| sample as Sample),
+        }
+    }
+}
+
+/// A fourth order band-pass filter made from two biquads, which separates the bands more cleanly
+/// than a single biquad.
+type BandPass = [Biquad; 2];
+
+/// Create a band-pass filter for a band.
+fn band_pass() -> BandPass {
+    [Biquad::new(FilterMode::BandPass), Biquad::new(FilterMode::BandPass)]
+}
+
+/// Filter a sample through a band-pass filter.
+fn filter_band(filters: &mut BandPass, frequency: Frequency, resonance: f64, sample_rate: f64, input: Sample) -> Sample {
+    filters.iter_mut().fold(input, |sample, filter| {
+        filter.set_params(frequency, resonance, sample_rate);
+        filter.process(sample)
+    })
+}
+
+/// A single band of the vocoder.
+#[derive(Clone, Debug)]
+struct Band {
+    frequency: Frequency,
+    modulator_filter: BandPass,
+    carrier_filters: [BandPass; 2],
+    level: f64,
+}
+
+/// A channel vocoder with a configurable number of bands, spaced evenly in pitch.
+pub struct Vocoder {
+    /// How quickly the band levels follow rises and falls in the modulator, in seconds.
+    pub attack: Param,
+    pub release: Param,
+    /// The balance between the carrier and the vocoded output.
+    pub mix: Param,
+    modulator: Modulator,
+    bands: Vec<Band>,
+    /// The resonance of the band filters, which are wide enough to just overlap their neighbours.
+    resonance: f64,
+}
+
+impl Vocoder {
+    /// Create a vocoder with `band_count` bands, which shapes its input with the given modulator.
+    /// It starts with a 5ms attack, 50ms release and a fully wet mix.
+    pub fn new(band_count: usize, modulator: Modulator) -> Self {
+        let band_count = band_count.max(1);
+        let octaves = (MAX_BAND_FREQUENCY / MIN_BAND_FREQUENCY).log2();
+        let bands = (0..band_count)
+            .map(|band| {
+                let position = if band_count > 1 { band as f64 / (band_count - 1) as f64 } else { 0.5 };
+                Band {
+                    frequency: MIN_BAND_FREQUENCY * f64::powf(2.0, octaves * position),
+                    modulator_filter: band_pass(),
+                    carrier_filters: [band_pass(), band_pass()],
+                    level: 0.0,
+                }
+            })
+            .collect();
+
+        // Each band is as wide as the spacing between bands, which gives a Q of sqrt(r) / (r - 1)
+        // for a band spanning a frequency ratio of r. The resonance is the inverse of
+        // `resonance_to_q`, and never goes below 0 so that one or two bands are just very wide.
+        let ratio = f64::powf(2.0, octaves / band_count as f64);
+        let q = ratio.sqrt() / (ratio - 1.0);
+        let resonance = (1.0 - 0.5 / q).max(0.0);
+
+        Self {
+            attack: 0.005.into(),
+            release: 0.05.into(),
+            mix: 1.0.into(),
+            modulator,
+            bands,
+            resonance,
+        }
+    }
+
+    /// The centre frequencies of the bands.
+    pub fn band_frequencies(&self) -> impl Iterator<Item = Frequency> + '_ {
+        self.bands.iter().map(|band| band.frequency)
+    }
+
+    /// The current levels of the bands, as followed from the modulator.
+    pub fn band_levels(&self) -> impl Iterator<Item = f64> + '_ {
+        self.bands.iter().map(|band| band.level)
+    }
+}
+
+impl Effect for Vocoder {
+    fn process(&mut self, input: Frame, sample_rate: f64) -> Frame {
+        let modulator = self.modulator.next(sample_rate);
+        let attack = time_coefficient(self.attack.value().max(0.0), sample_rate);
+        let release = time_coefficient(self.release.value().max(0.0), sample_rate);
+
+        let mut wet = [0.0; 2];
+        for band in &mut self.bands {
+            let target = filter_band(&mut band.modulator_filter, band.frequency, self.resonance, sample_rate, modulator).abs()
+                / FOLLOWER_SINE_LEVEL;
+            let coefficient = if target > band.level { attack } else { release };
+            band.level = target + (band.level - target) * coefficient;
+
+            for (channel, filters) in band.carrier_filters.iter_mut().enumerate() {
+                wet[channel] += filter_band(filters, band.frequency, self.resonance, sample_rate, input[channel]) * band.level;
+            }
+        }
+
+        mix_frames(input, wet, self.mix.value())
+    }
+
+    fn reset(&mut self) {
+        for band in &mut self.bands {
+            band.modulator_filter.iter_mut()
+                .chain(band.carrier_filters.iter_mut().flatten())
+                .for_each(Filter::reset);
+            band.level = 0.0;
+        }
+        if let Modulator::Sample { position, .. } = &mut self.modulator {
+            *position = 0.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use crate::functions::sine_wave;
+    use ringbuf::HeapRb;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    /// A one second mono sample of a sine wave.
+    fn sine(frequency: Frequency) -> Arc<Wav> {
+        Arc::new(Wav {
+            sample_rate: SAMPLE_RATE as u32,
+            channels: vec![(0..SAMPLE_RATE as usize).map(|n| sine_wave(n as f64 / SAMPLE_RATE, frequency)).collect()],
+            loop_points: None,
+        })
+    }
+
+    /// Vocode a carrier, and return the RMS level of the second half of the output.
+    fn vocode(vocoder: &mut Vocoder, carrier: Frequency, length: usize) -> f64 {
+        let output: Vec<Frame> = (0..length)
+            .map(|n| {
+                let sample = sine_wave(n as f64 / SAMPLE_RATE, carrier);
+                vocoder.process([sample; 2], SAMPLE_RATE)
+            })
+            .collect();
+        let second_half = &output[length / 2..];
+        (second_half.iter().map(|frame| frame[0] * frame[0]).sum::<f64>() / second_half.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn test_bands() {
+        let vocoder = Vocoder::new(16, Modulator::sample(sine(1000.0)));
+        let frequencies: Vec<Frequency> = vocoder.band_frequencies().collect();
+        assert_eq!(frequencies.len(), 16);
+        assert_relative_eq!(frequencies[0], MIN_BAND_FREQUENCY);
+        assert_relative_eq!(frequencies[15], MAX_BAND_FREQUENCY, max_relative = 1e-9);
+        // The bands are evenly spaced in pitch.
+        let ratio = frequencies[1] / frequencies[0];
+        assert!(frequencies.windows(2).all(|pair| (pair[1] / pair[0] - ratio).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_spectral_envelope() {
+        // A carrier only comes through where the modulator has energy.
+        let mut vocoder = Vocoder::new(16, Modulator::sample(sine(1000.0)));
+        let matching = vocode(&mut vocoder, 1000.0, 9600);
+        let mut vocoder = Vocoder::new(16, Modulator::sample(sine(1000.0)));
+        let distant = vocode(&mut vocoder, 150.0, 9600);
+        assert!(matching > 0.3, "{matching}");
+        assert!(distant < matching * 0.05, "{distant} vs {matching}");
+
+        // The band nearest the modulator should be the loudest.
+        let levels: Vec<f64> = vocoder.band_levels().collect();
+        let frequencies: Vec<Frequency> = vocoder.band_frequencies().collect();
+        let loudest = (0..levels.len()).max_by(|&a, &b| levels[a].total_cmp(&levels[b])).unwrap();
+        let nearest = (0..frequencies.len()).min_by(|&a, &b| {
+            (frequencies[a] / 1000.0).ln().abs().total_cmp(&(frequencies[b] / 1000.0).ln().abs())
+        }).unwrap();
+        assert_eq!(loudest, nearest);
+    }
+
+    #[test]
+    fn test_silent_modulator() {
+        // With nothing coming in from a live input, the output should be silent.
+        let (_producer, consumer) = HeapRb::<f32>::new(16).split();
+        let mut vocoder = Vocoder::new(8, Modulator::Live(consumer));
+        assert_eq!(vocode(&mut vocoder, 1000.0, 4800), 0.0);
+    }
+
+    #[test]
+    fn test_live() {
+        // A live modulator should behave like the same signal from a sample.
+        let (mut producer, consumer) = HeapRb::<f32>::new(9600).split();
+        let wav = sine(500.0);
+        producer.push_iter(&mut wav.channels[0].iter().take(9600).map(|&sample| sample as f32));
+        let live = vocode(&mut Vocoder::new(8, Modulator::Live(consumer)), 500.0, 9600);
+        let sampled = vocode(&mut Vocoder::new(8, Modulator::sample(wav)), 500.0, 9600);
+        assert_relative_eq!(live, sampled, max_relative = 1e-4);
+    }
+}
@@ -0,0 +1,238 @@
+//! Loading of WAV files, for impulse responses and samples.
+
+use std::error::Error;
+use std::path::Path;
+
+use crate::types::Sample;
+
+/// The WAV format tags for integer PCM, floating point and extensible formats.
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Decoded audio from a WAV file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Wav {
+    pub sample_rate: u32,
+    /// The samples of each channel, from -1 to 1.
+    pub channels: Vec<Vec<Sample>>,
+    /// The first and last sample of the first loop in the sampler chunk, if there is one.
+    pub loop_points: Option<(usize, usize)>,
+}
+
+impl Wav {
+    /// Parse a WAV file from its bytes. Supports 8, 16, 24 and 32-bit integer PCM, and 32 and
+    /// 64-bit floating point.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err("Not a WAV file".into());
+        }
+
+        let mut format = None;
+        let mut data = None;
+        let mut loop_points = None;
+        let mut chunks = &bytes[12..];
+        while chunks.len() >= 8 {
+            let id = &chunks[0..4];
+            let size = u32::from_le_bytes(chunks[4..8].try_into()?) as usize;
+            let body = chunks.get(8..8 + size).ok_or("WAV file chunk is truncated")?;
+            match id {
+                b"fmt " => format = Some(Format::parse(body)?),
+                b"data" => data = Some(body),
+                b"smpl" => loop_points = parse_loop(body),
+                _ => {},
+            }
+            // Chunks are padded to an even length.
+            chunks = chunks.get(8 + size + size % 2..).unwrap_or(&[]);
+        }
+
+        let format = format.ok_or("WAV file has no format chunk")?;
+        let data = data.ok_or("WAV file has no data chunk")?;
+        let bytes_per_sample = format.bits_per_sample as usize / 8;
+        let channel_count = format.channel_count as usize;
+
+        let mut channels = vec![Vec::with_capacity(data.len() / bytes_per_sample / channel_count); channel_count];
+        for frame in data.chunks_exact(bytes_per_sample * channel_count) {
+            for (channel, bytes) in channels.iter_mut().zip(frame.chunks_exact(bytes_per_sample)) {
+                channel.push(format.decode(bytes));
+            }
+        }
+
+        let mut wav = Self {
+            sample_rate: format.sample_rate,
+            channels,
+            loop_points: None,
+        };
+        wav.loop_points = wav.valid_loop(loop_points);
+        Ok(wav)
+    }
+
+    /// Load a WAV file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// The number of channels.
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// The number of samples in each channel.
+    pub fn frame_count(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    /// The given loop points if they can be played in this sample, or None if the loop runs
+    /// backwards or past the end of the sample.
+    pub fn valid_loop(&self, loop_points: Option<(usize, usize)>) -> Option<(usize, usize)> {
+        loop_points.filter(|&(start, end)| start <= end && end < self.frame_count())
+    }
+}
+
+/// Read the start and end of the first loop from a sampler chunk, which has a 36 byte header
+/// followed by 24 bytes for each loop.
+fn parse_loop(body: &[u8]) -> Option<(usize, usize)> {
+    let read_u32 = |offset: usize| Some(u32::from_le_bytes(body.get(offset..offset + 4)?.try_into().ok()?) as usize);
+    if read_u32(28)? == 0 {
+        return None;
+    }
+    Some((read_u32(36 + 8)?, read_u32(36 + 12)?))
+}
+
+/// The sample format from a WAV file's format chunk.
+#[derive(Clone, Copy, Debug)]
+struct Format {
+    float: bool,
+    channel_count: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+impl Format {
+    fn parse(body: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if body.len() < 16 {
+            return Err("WAV format chunk is too short".into());
+        }
+        let read_u16 = |offset: usize| u16::from_le_bytes([body[offset], body[offset + 1]]);
+
+        // The extensible format stores the real format tag at the start of its sub-format GUID.
+        let mut tag = read_u16(0);
+        if tag == FORMAT_EXTENSIBLE && body.len() >= 26 {
+            tag = read_u16(24);
+        }
+
+        let format = Self {
+            float: tag == FORMAT_FLOAT,
+            channel_count: read_u16(2),
+            sample_rate: u32::from_le_bytes(body[4..8].try_into()?),
+            bits_per_sample: read_u16(14),
+        };
+
+        match (tag, format.bits_per_sample) {
+            (FORMAT_PCM, 8 | 16 | 24 | 32) | (FORMAT_FLOAT, 32 | 64) => {},
+            _ => return Err(format!("Unsupported WAV format {tag} with {} bits per sample", format.bits_per_sample).into()),
+        }
+        if format.channel_count == 0 {
+            return Err("WAV file has no channels".into());
+        }
+
+        Ok(format)
+    }
+
+    /// Decode a single sample to the range -1 to 1.
+    fn decode(&self, bytes: &[u8]) -> Sample {
+        match (self.float, bytes) {
+            (true, &[a, b, c, d]) => f32::from_le_bytes([a, b, c, d]) as Sample,
+            (true, bytes) => f64::from_le_bytes(bytes.try_into().unwrap_or_default()),
+            // 8-bit samples are unsigned.
+            (false, &[a]) => (a as f64 - 128.0) / 128.0,
+            (false, &[a, b]) => i16::from_le_bytes([a, b]) as f64 / 32768.0,
+            (false, &[a, b, c]) => (i32::from_le_bytes([0, a, b, c]) >> 8) as f64 / 8388608.0,
+            (false, bytes) => i32::from_le_bytes(bytes.try_into().unwrap_or_default()) as f64 / 2147483648.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a WAV file with the given format tag and bit depth from the raw sample data.
+    fn wav_bytes(tag: u16, channel_count: u16, bits_per_sample: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(4 + 24 + 8 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(b"fmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        bytes.extend_from_slice(&channel_count.to_le_bytes());
+        bytes.extend_from_slice(&48000u32.to_le_bytes());
+        let block_align = channel_count * bits_per_sample / 8;
+        bytes.extend_from_slice(&(48000 * block_align as u32).to_le_bytes());
+        bytes.extend_from_slice(&block_align.to_le_bytes());
+        bytes.extend_from_slice(&bits_per_sample.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn test_pcm() {
+        // Stereo 16-bit with the channels interleaved.
+        let data: Vec<u8> = [0i16, 16384, -32768, 32767].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let wav = Wav::parse(&wav_bytes(FORMAT_PCM, 2, 16, &data)).unwrap();
+        assert_eq!(wav.sample_rate, 48000);
+        assert_eq!(wav.channel_count(), 2);
+        assert_eq!(wav.frame_count(), 2);
+        assert_eq!(wav.channels[0], vec![0.0, -1.0]);
+        assert_eq!(wav.channels[1], vec![0.5, 32767.0 / 32768.0]);
+
+        // 24-bit mono.
+        let data = [0x00, 0x00, 0x40, 0x00, 0x00, 0xC0];
+        let wav = Wav::parse(&wav_bytes(FORMAT_PCM, 1, 24, &data)).unwrap();
+        assert_eq!(wav.channels[0], vec![0.5, -0.5]);
+    }
+
+    #[test]
+    fn test_float() {
+        let data: Vec<u8> = [0.25f32, -0.75].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let wav = Wav::parse(&wav_bytes(FORMAT_FLOAT, 1, 32, &data)).unwrap();
+        assert_eq!(wav.channels[0], vec![0.25, -0.75]);
+    }
+
+    #[test]
+    fn test_loop_points() {
+        let mut bytes = wav_bytes(FORMAT_PCM, 1, 16, &[0; 20]);
+        let mut sampler = vec![0u8; 36 + 24];
+        sampler[28] = 1;
+        sampler[36 + 8] = 2;
+        sampler[36 + 12] = 7;
+        bytes.extend_from_slice(b"smpl");
+        bytes.extend_from_slice(&(sampler.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&sampler);
+
+        let wav = Wav::parse(&bytes).unwrap();
+        assert_eq!(wav.loop_points, Some((2, 7)));
+        assert_eq!(Wav::parse(&wav_bytes(FORMAT_PCM, 1, 16, &[0; 20])).unwrap().loop_points, None);
+
+        // Loops which run backwards or past the end of the data are dropped.
+        for (start, end) in [(7, 2), (2, 10)] {
+            let length = bytes.len();
+            bytes[length - 16] = start;
+            bytes[length - 12] = end;
+            assert_eq!(Wav::parse(&bytes).unwrap().loop_points, None);
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Wav::parse(b"not a wav file").is_err());
+        assert!(Wav::parse(&wav_bytes(FORMAT_PCM, 1, 12, &[0, 0])).is_err());
+        // A data chunk that claims to be longer than the file.
+        let mut bytes = wav_bytes(FORMAT_PCM, 1, 16, &[0, 0]);
+        bytes.truncate(bytes.len() - 1);
+        assert!(Wav::parse(&bytes).is_err());
+    }
+}